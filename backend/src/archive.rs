@@ -0,0 +1,692 @@
+use actix_web::http::header::ContentDisposition;
+use actix_web::{web, HttpResponse, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures_util::{stream, Stream, StreamExt as _, TryStreamExt as _};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::auth::Claims;
+use crate::storage::{compression, FileMetadata, ObjectStorage, ObjectStream, Permission, StorageError};
+use crate::{check_file_access, error_response, folders, AppState};
+
+/// Most files that can be picked for one archive by id
+pub const MAX_ARCHIVE_FILES: usize = 1000;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+
+/// General purpose flags: the CRC follows the data in a descriptor (bit 3), names are UTF-8 (bit 11)
+const ENTRY_FLAGS: u16 = 0x0808;
+/// Entries are stored as they are; the content is not compressed again
+const METHOD_STORED: u16 = 0;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+/// Sizes and offsets from this value on only fit into ZIP64 records
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+/// Entry counts from this value on only fit into ZIP64 records
+const ZIP64_ENTRY_LIMIT: usize = u16::MAX as usize;
+/// Longest entry name in bytes; headers store the length in 16 bits
+const MAX_ENTRY_NAME_LENGTH: usize = u16::MAX as usize;
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveRequest {
+    /// Files to put into the archive, in this order
+    #[serde(default)]
+    pub file_ids: Vec<String>,
+    /// Folder whose files, including those in subfolders, go into the archive instead
+    pub folder_id: Option<String>,
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// `value` for a 32-bit field, or the marker telling readers to look in the ZIP64 record
+fn u32_or_marker(value: u64) -> u32 {
+    value.min(ZIP64_LIMIT) as u32
+}
+
+/// MS-DOS `(time, date)` of a timestamp, clamped to the range the format can represent
+fn dos_date_time(time: DateTime<Utc>) -> (u16, u16) {
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = time.year().min(2107) as u32;
+    let date = ((year - 1980) << 9) | (time.month() << 5) | time.day();
+    let time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    (time as u16, date as u16)
+}
+
+/// A file placed into an archive
+struct ZipEntry {
+    /// At most `MAX_ENTRY_NAME_LENGTH` bytes, as handed out by `EntryNames`
+    name: String,
+    file: FileMetadata,
+    /// Size of the file as uploaded, which is what the entry holds
+    size: u64,
+    time: u16,
+    date: u16,
+    /// Position of the local header within the archive
+    offset: u64,
+}
+
+impl ZipEntry {
+    fn zip64_size(&self) -> bool {
+        self.size >= ZIP64_LIMIT
+    }
+
+    fn zip64_offset(&self) -> bool {
+        self.offset >= ZIP64_LIMIT
+    }
+
+    fn version_needed(&self) -> u16 {
+        if self.zip64_size() || self.zip64_offset() {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        }
+    }
+
+    /// Header written in front of the content. The size is known up front, the CRC is not.
+    fn local_header(&self) -> Vec<u8> {
+        let zip64 = self.zip64_size();
+        let mut buf = Vec::with_capacity(50 + self.name.len());
+        put_u32(&mut buf, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut buf, self.version_needed());
+        put_u16(&mut buf, ENTRY_FLAGS);
+        put_u16(&mut buf, METHOD_STORED);
+        put_u16(&mut buf, self.time);
+        put_u16(&mut buf, self.date);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, u32_or_marker(self.size));
+        put_u32(&mut buf, u32_or_marker(self.size));
+        put_u16(&mut buf, self.name.len() as u16);
+        put_u16(&mut buf, if zip64 { 20 } else { 0 });
+        buf.extend_from_slice(self.name.as_bytes());
+        if zip64 {
+            put_u16(&mut buf, ZIP64_EXTRA_ID);
+            put_u16(&mut buf, 16);
+            put_u64(&mut buf, self.size);
+            put_u64(&mut buf, self.size);
+        }
+        buf
+    }
+
+    /// Descriptor written after the content, once its CRC is known
+    fn data_descriptor(&self, crc: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(24);
+        put_u32(&mut buf, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut buf, crc);
+        if self.zip64_size() {
+            put_u64(&mut buf, self.size);
+            put_u64(&mut buf, self.size);
+        } else {
+            put_u32(&mut buf, self.size as u32);
+            put_u32(&mut buf, self.size as u32);
+        }
+        buf
+    }
+
+    fn central_header(&self, crc: u32) -> Vec<u8> {
+        let mut extra = Vec::new();
+        if self.zip64_size() {
+            put_u64(&mut extra, self.size);
+            put_u64(&mut extra, self.size);
+        }
+        if self.zip64_offset() {
+            put_u64(&mut extra, self.offset);
+        }
+        let extra_len = if extra.is_empty() { 0 } else { extra.len() + 4 };
+
+        let mut buf = Vec::with_capacity(46 + self.name.len() + extra_len);
+        put_u32(&mut buf, CENTRAL_HEADER_SIGNATURE);
+        put_u16(&mut buf, VERSION_ZIP64);
+        put_u16(&mut buf, self.version_needed());
+        put_u16(&mut buf, ENTRY_FLAGS);
+        put_u16(&mut buf, METHOD_STORED);
+        put_u16(&mut buf, self.time);
+        put_u16(&mut buf, self.date);
+        put_u32(&mut buf, crc);
+        put_u32(&mut buf, u32_or_marker(self.size));
+        put_u32(&mut buf, u32_or_marker(self.size));
+        put_u16(&mut buf, self.name.len() as u16);
+        put_u16(&mut buf, extra_len as u16);
+        put_u16(&mut buf, 0); // comment length
+        put_u16(&mut buf, 0); // disk number
+        put_u16(&mut buf, 0); // internal attributes
+        put_u32(&mut buf, 0); // external attributes
+        put_u32(&mut buf, u32_or_marker(self.offset));
+        buf.extend_from_slice(self.name.as_bytes());
+        if !extra.is_empty() {
+            put_u16(&mut buf, ZIP64_EXTRA_ID);
+            put_u16(&mut buf, extra.len() as u16);
+            buf.extend_from_slice(&extra);
+        }
+        buf
+    }
+}
+
+/// End of central directory record, preceded by its ZIP64 counterpart when the archive needs one
+fn end_of_central_directory(entries: usize, offset: u64, size: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(98);
+    if entries >= ZIP64_ENTRY_LIMIT || offset >= ZIP64_LIMIT || size >= ZIP64_LIMIT {
+        put_u32(&mut buf, ZIP64_END_SIGNATURE);
+        put_u64(&mut buf, 44);
+        put_u16(&mut buf, VERSION_ZIP64);
+        put_u16(&mut buf, VERSION_ZIP64);
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, 0);
+        put_u64(&mut buf, entries as u64);
+        put_u64(&mut buf, entries as u64);
+        put_u64(&mut buf, size);
+        put_u64(&mut buf, offset);
+
+        put_u32(&mut buf, ZIP64_LOCATOR_SIGNATURE);
+        put_u32(&mut buf, 0);
+        put_u64(&mut buf, offset + size);
+        put_u32(&mut buf, 1);
+    }
+    let entries = entries.min(ZIP64_ENTRY_LIMIT) as u16;
+    put_u32(&mut buf, END_SIGNATURE);
+    put_u16(&mut buf, 0);
+    put_u16(&mut buf, 0);
+    put_u16(&mut buf, entries);
+    put_u16(&mut buf, entries);
+    put_u32(&mut buf, u32_or_marker(size));
+    put_u32(&mut buf, u32_or_marker(offset));
+    put_u16(&mut buf, 0);
+    buf
+}
+
+/// Layout of a ZIP archive of stored files.
+///
+/// Entries are not compressed, so every header, and with them the size of the whole archive,
+/// is known before any content is read; only the CRCs are filled in while streaming.
+pub struct ZipArchive {
+    entries: Vec<ZipEntry>,
+    central_directory_offset: u64,
+    size: u64,
+}
+
+impl ZipArchive {
+    /// Lay out an archive of `files`, each with the name of its entry
+    pub fn new(files: Vec<(String, FileMetadata)>) -> Self {
+        let mut offset = 0;
+        let entries: Vec<ZipEntry> = files
+            .into_iter()
+            .map(|(name, file)| {
+                let (time, date) = dos_date_time(file.upload_time);
+                let entry = ZipEntry { name, size: file.size, file, time, date, offset };
+                offset += entry.local_header().len() as u64 + entry.size + entry.data_descriptor(0).len() as u64;
+                entry
+            })
+            .collect();
+
+        let central_directory_size: u64 = entries.iter().map(|entry| entry.central_header(0).len() as u64).sum();
+        let end_size = end_of_central_directory(entries.len(), offset, central_directory_size).len() as u64;
+
+        Self {
+            entries,
+            central_directory_offset: offset,
+            size: offset + central_directory_size + end_size,
+        }
+    }
+
+    /// Number of bytes the archive streams
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn central_directory(&self, crcs: &[u32]) -> Vec<u8> {
+        let mut buf: Vec<u8> = self.entries
+            .iter()
+            .zip(crcs)
+            .flat_map(|(entry, crc)| entry.central_header(*crc))
+            .collect();
+        let size = buf.len() as u64;
+        buf.extend(end_of_central_directory(self.entries.len(), self.central_directory_offset, size));
+        buf
+    }
+
+    /// Stream the archive, reading one object from storage at a time.
+    ///
+    /// Storage errors, or an object that does not have the size of its file, end the stream
+    /// with an error; the client is left with a truncated archive.
+    pub fn stream(self, storage: Arc<dyn ObjectStorage>) -> impl Stream<Item = Result<Bytes, StorageError>> {
+        let writer = ZipWriter { storage, archive: self, crcs: Vec::new(), current: None, done: false };
+        stream::unfold(writer, |mut writer| async move {
+            writer.next_chunk().await.map(|chunk| (chunk, writer))
+        })
+    }
+}
+
+/// Content of the entry being streamed
+struct EntryBody {
+    body: ObjectStream,
+    hasher: crc32fast::Hasher,
+    written: u64,
+}
+
+struct ZipWriter {
+    storage: Arc<dyn ObjectStorage>,
+    archive: ZipArchive,
+    /// CRCs of the entries streamed so far
+    crcs: Vec<u32>,
+    current: Option<EntryBody>,
+    done: bool,
+}
+
+impl ZipWriter {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, StorageError>> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.advance().await;
+        if !matches!(chunk, Some(Ok(_))) {
+            self.done = true;
+        }
+        chunk
+    }
+
+    async fn advance(&mut self) -> Option<Result<Bytes, StorageError>> {
+        let index = self.crcs.len();
+
+        if let Some(current) = self.current.as_mut() {
+            let entry = &self.archive.entries[index];
+            return match current.body.next().await {
+                Some(Ok(chunk)) => {
+                    current.written += chunk.len() as u64;
+                    if current.written > entry.size {
+                        return Some(Err(size_mismatch(entry)));
+                    }
+                    current.hasher.update(&chunk);
+                    Some(Ok(chunk))
+                }
+                Some(Err(e)) => Some(Err(e)),
+                None if current.written != entry.size => Some(Err(size_mismatch(entry))),
+                None => {
+                    let crc = current.hasher.clone().finalize();
+                    self.current = None;
+                    self.crcs.push(crc);
+                    Some(Ok(Bytes::from(entry.data_descriptor(crc))))
+                }
+            };
+        }
+
+        match self.archive.entries.get(index) {
+            Some(entry) => {
+                // Compressed files are decompressed, so every entry holds the content as uploaded
+                let object = match compression::get_content(self.storage.as_ref(), &entry.file).await {
+                    Ok(object) => object,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.current = Some(EntryBody { body: object.body, hasher: crc32fast::Hasher::new(), written: 0 });
+                Some(Ok(Bytes::from(entry.local_header())))
+            }
+            None => {
+                self.done = true;
+                Some(Ok(Bytes::from(self.archive.central_directory(&self.crcs))))
+            }
+        }
+    }
+}
+
+fn size_mismatch(entry: &ZipEntry) -> StorageError {
+    StorageError::Io(format!("Object {} does not have the expected size of {} bytes", entry.file.s3_key, entry.size))
+}
+
+/// Entry names already handed out, to keep the names within an archive unique
+#[derive(Default)]
+struct EntryNames {
+    /// Lowercased, as many systems extract names differing only in case to the same file
+    used: HashSet<String>,
+}
+
+impl EntryNames {
+    /// Name for `filename` in the directory `dir` (empty or ending in `/`), numbered like
+    /// `report (1).pdf` if the name is taken. `None` if the name is too long for an entry.
+    fn unique(&mut self, dir: &str, filename: &str) -> Option<String> {
+        let filename = entry_component(filename);
+        let (stem, extension) = match filename.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+            _ => (filename.as_str(), String::new()),
+        };
+
+        let mut name = format!("{}{}", dir, filename);
+        let mut counter = 1;
+        while !self.used.insert(name.to_lowercase()) {
+            name = format!("{}{} ({}){}", dir, stem, counter, extension);
+            counter += 1;
+        }
+        (name.len() <= MAX_ENTRY_NAME_LENGTH).then_some(name)
+    }
+}
+
+/// A file name usable as one component of an entry path, so entries cannot escape the archive
+fn entry_component(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\') || c.is_control() { '_' } else { c })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => name,
+    }
+}
+
+// Stream a ZIP archive of several files, or of a folder with its subfolders
+pub async fn download_archive(
+    claims: Claims,
+    archive_req: web::Json<ArchiveRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+    let archive_req = archive_req.into_inner();
+
+    let (archive_name, files) = match (archive_req.folder_id, archive_req.file_ids.is_empty()) {
+        (Some(_), false) => {
+            return Ok(error_response(HttpResponse::BadRequest(), "Give either file_ids or folder_id, not both"));
+        }
+        (None, true) => return Ok(error_response(HttpResponse::BadRequest(), "No files given")),
+        (Some(folder_id), true) => {
+            let folder = match folders::check_folder_ownership(&data, &folder_id, user_id).await {
+                Ok(folder) => folder,
+                Err(response) => return Ok(response),
+            };
+            match data.file_metadata.list_folder_tree_files(&folder.id).await {
+                Ok(files) => (format!("{}.zip", folder.name), files),
+                Err(e) => {
+                    eprintln!("Database error: {}", e);
+                    return Ok(error_response(HttpResponse::InternalServerError(), "Failed to list folder contents"));
+                }
+            }
+        }
+        (None, false) => {
+            if archive_req.file_ids.len() > MAX_ARCHIVE_FILES {
+                return Ok(error_response(HttpResponse::BadRequest(), "Too many files for one archive"));
+            }
+
+            // Every file is checked before anything is streamed, so a refusal is still a proper response
+            let mut seen = HashSet::new();
+            let mut files = Vec::new();
+            for file_id in &archive_req.file_ids {
+                if !seen.insert(file_id.as_str()) {
+                    continue;
+                }
+                match check_file_access(&data, file_id, user_id, Permission::Read).await {
+                    Ok(file) => files.push((String::new(), file)),
+                    Err(response) => return Ok(response),
+                }
+            }
+            ("files.zip".to_string(), files)
+        }
+    };
+
+    let mut names = EntryNames::default();
+    let entries = files
+        .into_iter()
+        .map(|(dir, file)| Some((names.unique(&dir, &file.filename)?, file)))
+        .collect::<Option<Vec<_>>>();
+    let Some(entries) = entries else {
+        return Ok(error_response(HttpResponse::BadRequest(), "A file path is too long for a ZIP archive"));
+    };
+    let archive = ZipArchive::new(entries);
+    let size = archive.size();
+
+    let body = archive.stream(data.storage.clone()).inspect_err(|e| eprintln!("Archive error: {}", e));
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition::attachment(archive_name))
+        .content_type("application/zip")
+        .no_chunking(size)
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use chrono::TimeZone;
+
+    const GIB: u64 = 1 << 30;
+
+    fn file(name: &str, size: u64) -> FileMetadata {
+        FileMetadata {
+            id: name.to_string(),
+            filename: name.to_string(),
+            size,
+            content_type: None,
+            upload_time: Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 10).unwrap(),
+            s3_key: name.to_string(),
+            content_hash: None,
+            version: 1,
+            folder_id: None,
+            workspace_id: None,
+            description: None,
+            tags: Vec::new(),
+            attributes: Default::default(),
+            content_encoding: None,
+            expires_at: None,
+        }
+    }
+
+    fn entry(size: u64, offset: u64) -> ZipEntry {
+        ZipEntry { name: "a.bin".to_string(), file: file("a.bin", size), size, time: 0, date: 0, offset }
+    }
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn entry_components() {
+        assert_eq!(entry_component("report.pdf"), "report.pdf");
+        assert_eq!(entry_component("../etc/passwd"), ".._etc_passwd");
+        assert_eq!(entry_component("a\\b"), "a_b");
+        assert_eq!(entry_component("line\nbreak"), "line_break");
+        assert_eq!(entry_component(""), "_");
+        assert_eq!(entry_component("."), "_");
+        assert_eq!(entry_component(".."), "_");
+        assert_eq!(entry_component("..."), "...");
+    }
+
+    #[test]
+    fn unique_names() {
+        let mut names = EntryNames::default();
+        assert_eq!(names.unique("", "report.pdf").unwrap(), "report.pdf");
+        assert_eq!(names.unique("", "report.pdf").unwrap(), "report (1).pdf");
+        // Names differing only in case collide
+        assert_eq!(names.unique("", "Report.PDF").unwrap(), "Report (2).PDF");
+        assert_eq!(names.unique("docs/", "report.pdf").unwrap(), "docs/report.pdf");
+        assert_eq!(names.unique("", "archive.tar.gz").unwrap(), "archive.tar.gz");
+        assert_eq!(names.unique("", "archive.tar.gz").unwrap(), "archive.tar (1).gz");
+        assert_eq!(names.unique("", ".bashrc").unwrap(), ".bashrc");
+        assert_eq!(names.unique("", ".bashrc").unwrap(), ".bashrc (1)");
+        assert_eq!(names.unique("", "README").unwrap(), "README");
+        assert_eq!(names.unique("", "README").unwrap(), "README (1)");
+        // A numbered name can itself be taken already
+        assert_eq!(names.unique("", "notes (1).txt").unwrap(), "notes (1).txt");
+        assert_eq!(names.unique("", "notes.txt").unwrap(), "notes.txt");
+        assert_eq!(names.unique("", "notes.txt").unwrap(), "notes (2).txt");
+        assert_eq!(names.unique("", "a/b").unwrap(), "a_b");
+    }
+
+    #[test]
+    fn overlong_names() {
+        let mut names = EntryNames::default();
+        let dir = format!("{}/", "d".repeat(MAX_ENTRY_NAME_LENGTH - 10));
+        assert_eq!(names.unique(&dir, "file.txt").unwrap().len(), MAX_ENTRY_NAME_LENGTH - 1);
+        // The numbered name no longer fits
+        assert_eq!(names.unique(&dir, "file.txt"), None);
+        assert_eq!(names.unique(&dir, "longer-file.txt"), None);
+    }
+
+    #[test]
+    fn zip64_sizes() {
+        let small = entry(ZIP64_LIMIT - 1, 0);
+        let header = small.local_header();
+        assert_eq!(u16_at(&header, 4), VERSION_DEFAULT);
+        assert_eq!(u32_at(&header, 22), (ZIP64_LIMIT - 1) as u32);
+        assert_eq!(u16_at(&header, 28), 0);
+        assert_eq!(small.data_descriptor(0).len(), 16);
+
+        let large = entry(4 * GIB, 0);
+        let header = large.local_header();
+        assert_eq!(u16_at(&header, 4), VERSION_ZIP64);
+        assert_eq!(u32_at(&header, 18), u32::MAX);
+        assert_eq!(u32_at(&header, 22), u32::MAX);
+        assert_eq!(u16_at(&header, 28), 20);
+        let extra = 30 + large.name.len();
+        assert_eq!(u16_at(&header, extra), ZIP64_EXTRA_ID);
+        assert_eq!(u64_at(&header, extra + 4), 4 * GIB);
+        assert_eq!(u64_at(&header, extra + 12), 4 * GIB);
+        assert_eq!(header.len(), extra + 20);
+
+        let descriptor = large.data_descriptor(7);
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u64_at(&descriptor, 8), 4 * GIB);
+
+        let central = large.central_header(7);
+        assert_eq!(u32_at(&central, 16), 7);
+        assert_eq!(u32_at(&central, 20), u32::MAX);
+        assert_eq!(u16_at(&central, 30), 20);
+        assert_eq!(u32_at(&central, 42), 0);
+    }
+
+    #[test]
+    fn zip64_offsets() {
+        let central = entry(10, ZIP64_LIMIT - 1).central_header(0);
+        assert_eq!(u16_at(&central, 6), VERSION_DEFAULT);
+        assert_eq!(u16_at(&central, 30), 0);
+        assert_eq!(u32_at(&central, 42), (ZIP64_LIMIT - 1) as u32);
+
+        let entry = entry(10, 5 * GIB);
+        let central = entry.central_header(0);
+        assert_eq!(u16_at(&central, 6), VERSION_ZIP64);
+        assert_eq!(u32_at(&central, 20), 10);
+        assert_eq!(u16_at(&central, 30), 12);
+        assert_eq!(u32_at(&central, 42), u32::MAX);
+        let extra = 46 + entry.name.len();
+        assert_eq!(u16_at(&central, extra), ZIP64_EXTRA_ID);
+        assert_eq!(u16_at(&central, extra + 2), 8);
+        assert_eq!(u64_at(&central, extra + 4), 5 * GIB);
+        // The local header only carries sizes, which still fit
+        assert_eq!(u16_at(&entry.local_header(), 28), 0);
+    }
+
+    #[test]
+    fn zip64_end_records() {
+        let end = end_of_central_directory(ZIP64_ENTRY_LIMIT - 1, ZIP64_LIMIT - 1, 100);
+        assert_eq!(end.len(), 22);
+        assert_eq!(u32_at(&end, 0), END_SIGNATURE);
+        assert_eq!(u16_at(&end, 10), (ZIP64_ENTRY_LIMIT - 1) as u16);
+
+        let end = end_of_central_directory(ZIP64_ENTRY_LIMIT, 1000, 100);
+        assert_eq!(end.len(), 98);
+        assert_eq!(u32_at(&end, 0), ZIP64_END_SIGNATURE);
+        assert_eq!(u64_at(&end, 32), ZIP64_ENTRY_LIMIT as u64);
+        assert_eq!(u64_at(&end, 40), 100);
+        assert_eq!(u64_at(&end, 48), 1000);
+        assert_eq!(u32_at(&end, 56), ZIP64_LOCATOR_SIGNATURE);
+        assert_eq!(u64_at(&end, 64), 1100);
+        assert_eq!(u32_at(&end, 76), END_SIGNATURE);
+        assert_eq!(u16_at(&end, 86), u16::MAX);
+        assert_eq!(u32_at(&end, 92), 1000);
+
+        let end = end_of_central_directory(2, 5 * GIB, 100);
+        assert_eq!(u32_at(&end, 0), ZIP64_END_SIGNATURE);
+        assert_eq!(u16_at(&end, 86), 2);
+        assert_eq!(u32_at(&end, 92), u32::MAX);
+    }
+
+    #[test]
+    fn zip64_archive_layout() {
+        let archive = ZipArchive::new(vec![
+            ("big.bin".to_string(), file("big.bin", 4 * GIB)),
+            ("small.txt".to_string(), file("small.txt", 10)),
+        ]);
+        let second = &archive.entries[1];
+        assert_eq!(second.offset, 30 + 7 + 20 + 4 * GIB + 24);
+        assert!(second.zip64_offset());
+        assert_eq!(archive.central_directory_offset, second.offset + 30 + 9 + 10 + 16);
+
+        let central_directory = archive.central_directory(&[0, 0]);
+        assert_eq!(archive.size(), archive.central_directory_offset + central_directory.len() as u64);
+        assert_eq!(u32_at(&central_directory, central_directory.len() - 98), ZIP64_END_SIGNATURE);
+    }
+
+    #[tokio::test]
+    async fn streams_archive() {
+        let storage = MemoryStorage::new();
+        let contents: [&[u8]; 3] = [b"hello, world", b"", &[7u8; 100_000]];
+        let mut files = Vec::new();
+        for (i, content) in contents.iter().enumerate() {
+            let file = file(&format!("{}.bin", i), content.len() as u64);
+            storage.put_object(&file.s3_key, content.to_vec(), None).await.unwrap();
+            files.push((format!("dir/{}", file.filename), file));
+        }
+
+        let archive = ZipArchive::new(files);
+        let size = archive.size();
+        let chunks: Vec<Bytes> = archive.stream(Arc::new(storage)).try_collect().await.unwrap();
+        let zip = chunks.concat();
+        assert_eq!(zip.len() as u64, size);
+
+        // Walk the central directory and check every entry against its local header and content
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(&zip, end), END_SIGNATURE);
+        assert_eq!(u16_at(&zip, end + 10), 3);
+        let mut at = u32_at(&zip, end + 16) as usize;
+        for (i, content) in contents.iter().enumerate() {
+            assert_eq!(u32_at(&zip, at), CENTRAL_HEADER_SIGNATURE);
+            assert_eq!(u32_at(&zip, at + 16), crc32fast::hash(content));
+            let name_len = u16_at(&zip, at + 28) as usize;
+            assert_eq!(&zip[at + 46..at + 46 + name_len], format!("dir/{}.bin", i).as_bytes());
+
+            let local = u32_at(&zip, at + 42) as usize;
+            assert_eq!(u32_at(&zip, local), LOCAL_HEADER_SIGNATURE);
+            let data = local + 30 + name_len;
+            assert_eq!(&zip[data..data + content.len()], *content);
+            let descriptor = data + content.len();
+            assert_eq!(u32_at(&zip, descriptor), DATA_DESCRIPTOR_SIGNATURE);
+            assert_eq!(u32_at(&zip, descriptor + 4), crc32fast::hash(content));
+
+            at += 46 + name_len;
+        }
+        assert_eq!(at, end);
+    }
+
+    #[tokio::test]
+    async fn stops_on_size_mismatch() {
+        let storage = MemoryStorage::new();
+        storage.put_object("short.bin", vec![1; 10], None).await.unwrap();
+        let archive = ZipArchive::new(vec![("short.bin".to_string(), file("short.bin", 20))]);
+        let result: Result<Vec<Bytes>, _> = archive.stream(Arc::new(storage)).try_collect().await;
+        assert!(result.is_err());
+    }
+}
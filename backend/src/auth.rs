@@ -3,7 +3,7 @@ use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tokio_postgres::{NoTls, Row};
+use tokio_postgres::NoTls;
 use crate::storage::redis_token_store::{RedisTokenStore, TokenInfo};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +18,7 @@ pub struct Claims {
 pub struct GoogleTokenInfo {
     pub email: String,
     pub name: String,
+    #[allow(dead_code)]
     pub picture: Option<String>,
     pub sub: String, // Google user ID
 }
@@ -45,7 +46,6 @@ pub struct LoginResponse {
 
 pub struct AuthService {
     db_client: tokio_postgres::Client,
-    database_url: String,
     encoding_key: EncodingKey,
     decoding_key: DecodingKey,
     token_store: RedisTokenStore,
@@ -68,23 +68,12 @@ impl AuthService {
         
         Ok(Self {
             db_client: client,
-            database_url: database_url.to_string(),
             encoding_key,
             decoding_key,
             token_store,
         })
     }
     
-    // Public method to execute database queries
-    pub async fn execute_query(&self, query: &str, params: &[&(dyn tokio_postgres::types::ToSql + Sync)]) -> Result<u64, tokio_postgres::Error> {
-        self.db_client.execute(query, params).await
-    }
-    
-    // Public method to query database
-    pub async fn query_database(&self, query: &str, params: &[&(dyn tokio_postgres::types::ToSql + Sync)]) -> Result<Vec<tokio_postgres::Row>, tokio_postgres::Error> {
-        self.db_client.query(query, params).await
-    }
-
     pub async fn verify_google_token(&self, token: &str) -> Result<GoogleTokenInfo, Box<dyn std::error::Error>> {
         let client = Client::new();
         let url = format!("https://oauth2.googleapis.com/tokeninfo?id_token={}", token);
@@ -208,6 +197,23 @@ impl AuthService {
             Ok(None)
         }
     }
+
+    /// Look up a registered user by email address, ignoring case
+    pub async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        let row = self.db_client
+            .query_opt(
+                "SELECT id, name, email, third_party_id FROM users WHERE LOWER(email) = LOWER($1) ORDER BY id LIMIT 1",
+                &[&email.trim()],
+            )
+            .await?;
+
+        Ok(row.map(|row| User {
+            id: row.get(0),
+            name: row.get(1),
+            email: row.get(2),
+            third_party_id: row.get(3),
+        }))
+    }
 }
 
 // Custom extractor for authentication - updated to use async verification
@@ -225,8 +231,8 @@ impl FromRequest for Claims {
                 Some(header_value) => {
                     match header_value.to_str() {
                         Ok(header_str) => {
-                            if header_str.starts_with("Bearer ") {
-                                &header_str[7..]
+                            if let Some(token) = header_str.strip_prefix("Bearer ") {
+                                token
                             } else {
                                 return Err(actix_web::error::ErrorUnauthorized("Invalid authorization header format"));
                             }
@@ -0,0 +1,101 @@
+use actix_web::web;
+use chrono::Utc;
+use std::time::Duration;
+
+use crate::storage::{MetadataStore, ObjectStorage};
+use crate::{tus, AppState};
+
+/// How often the background cleanup task runs
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Maximum number of queued objects removed per run
+const CLEANUP_BATCH_SIZE: i64 = 100;
+
+/// Remove a queued object from storage and take it off the deletion queue
+pub async fn remove_object(
+    store: &MetadataStore,
+    storage: &dyn ObjectStorage,
+    file_key: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    storage.delete_object(file_key).await?;
+    store.object_deleted(file_key).await?;
+    Ok(())
+}
+
+/// Remove the queued objects of files deleted for good.
+///
+/// Returns false if some could not be removed; they stay queued and are retried by the cleanup task.
+pub async fn remove_objects(store: &MetadataStore, storage: &dyn ObjectStorage, file_keys: &[String]) -> bool {
+    let mut removed = true;
+    for file_key in file_keys {
+        if let Err(e) = remove_object(store, storage, file_key).await {
+            eprintln!("Storage cleanup error for {}: {}", file_key, e);
+            removed = false;
+        }
+    }
+    removed
+}
+
+/// Remove an object no file refers to, queueing it for a later retry if that fails
+pub async fn discard_object(store: &MetadataStore, storage: &dyn ObjectStorage, file_key: &str) {
+    if let Err(e) = storage.delete_object(file_key).await {
+        eprintln!("Failed to remove object {}, queueing it for cleanup: {}", file_key, e);
+        if let Err(e) = store.queue_object_deletion(file_key).await {
+            eprintln!("Failed to queue object {} for cleanup: {}", file_key, e);
+        }
+    }
+}
+
+/// Retry removing objects whose deletion failed earlier
+async fn purge_pending_deletions(data: &AppState) {
+    // Expired files, trashed files past their retention, abandoned presigned and resumable uploads
+    // and unreferenced shared objects join the queue first
+    if let Err(e) = data.file_metadata.purge_expired_files(Utc::now()).await {
+        eprintln!("Failed to delete expired files: {}", e);
+    }
+    let retention = chrono::Duration::from_std(data.trash_retention).unwrap_or(chrono::Duration::MAX);
+    if let Some(deleted_before) = Utc::now().checked_sub_signed(retention) {
+        if let Err(e) = data.file_metadata.purge_trash(deleted_before).await {
+            eprintln!("Failed to purge trash: {}", e);
+        }
+    }
+    if let Err(e) = data.file_metadata.expire_pending_uploads().await {
+        eprintln!("Failed to expire pending uploads: {}", e);
+    }
+    match data.file_metadata.expire_tus_uploads().await {
+        Ok(uploads) => {
+            for upload in &uploads {
+                tus::discard_upload(data, upload).await;
+            }
+        }
+        Err(e) => eprintln!("Failed to expire resumable uploads: {}", e),
+    }
+    if let Err(e) = data.file_metadata.drop_unreferenced_objects().await {
+        eprintln!("Failed to drop unreferenced objects: {}", e);
+    }
+
+    let file_keys = match data.file_metadata.pending_object_deletions(CLEANUP_BATCH_SIZE).await {
+        Ok(file_keys) => file_keys,
+        Err(e) => {
+            eprintln!("Failed to load pending object deletions: {}", e);
+            return;
+        }
+    };
+
+    for file_key in file_keys {
+        if let Err(e) = remove_object(&data.file_metadata, data.storage.as_ref(), &file_key).await {
+            eprintln!("Failed to remove object {}: {}", file_key, e);
+        }
+    }
+}
+
+/// Periodically clean up storage in the background
+pub fn spawn_cleanup_task(data: web::Data<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            purge_pending_deletions(&data).await;
+        }
+    });
+}
@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
 
 #[derive(Deserialize)]
@@ -12,6 +13,13 @@ pub struct CloudflareConfig {
 #[derive(Deserialize)]
 pub struct PostgresConfig {
     pub postgres_url: String,
+    /// Apply pending schema migrations when the server starts
+    #[serde(default = "default_auto_migrate")]
+    pub auto_migrate: bool,
+}
+
+fn default_auto_migrate() -> bool {
+    true
 }
 
 #[derive(Deserialize)]
@@ -25,9 +33,177 @@ pub struct RedisConfig {
     pub token_ttl_seconds: u64,
 }
 
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Cloudflare R2, configured by the `[cloudflare]` section
+    #[default]
+    R2,
+    /// Files on the local disk below `local_path`
+    Local,
+    /// Process memory, lost on restart
+    Memory,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub local_path: String,
+    /// Lifetime of presigned upload and download URLs
+    pub presign_expiry_seconds: u64,
+    /// Number of most recent versions kept per file; older ones are pruned
+    pub version_retention: u32,
+    /// Time a resumable upload may stay idle before it is discarded
+    pub resumable_upload_expiry_seconds: u64,
+    /// Time a deleted file stays in the trash before it is removed for good
+    pub trash_retention_seconds: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::default(),
+            local_path: "uploads".to_string(),
+            presign_expiry_seconds: 900,
+            version_retention: 10,
+            resumable_upload_expiry_seconds: 86400,
+            trash_retention_seconds: 2_592_000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Storage each user may use unless overridden in `user_quotas`; 0 means unlimited
+    pub default_bytes: u64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            default_bytes: 10 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// What to do with an upload whose content does not match its declared type
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TypeMismatchAction {
+    /// Store the file with the type detected from its content
+    #[default]
+    Override,
+    /// Refuse the upload with `415 Unsupported Media Type`
+    Reject,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct UploadPolicyConfig {
+    /// Largest accepted file in bytes; 0 means unlimited
+    pub max_file_size: u64,
+    /// MIME types accepted, e.g. `image/*` or `application/pdf`; empty accepts every type
+    pub allowed_types: Vec<String>,
+    /// MIME types refused, taking precedence over `allowed_types`
+    pub denied_types: Vec<String>,
+    /// Filename extensions accepted, without the dot; empty accepts every extension
+    pub allowed_extensions: Vec<String>,
+    /// Filename extensions refused, taking precedence over `allowed_extensions`
+    pub denied_extensions: Vec<String>,
+    /// Detect the type of uploads from their first bytes instead of trusting the client
+    pub sniff_content: bool,
+    pub on_type_mismatch: TypeMismatchAction,
+}
+
+impl Default for UploadPolicyConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 5 * 1024 * 1024 * 1024,
+            allowed_types: Vec::new(),
+            denied_types: Vec::new(),
+            allowed_extensions: Vec::new(),
+            denied_extensions: Vec::new(),
+            sniff_content: true,
+            on_type_mismatch: TypeMismatchAction::default(),
+        }
+    }
+}
+
+/// Algorithm content is compressed with before it is stored
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[default]
+    Zstd,
+    Gzip,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Compress uploads of the listed content types before storing them
+    pub enabled: bool,
+    pub algorithm: CompressionAlgorithm,
+    /// Compression level; the algorithm's default when unset
+    pub level: Option<i32>,
+    /// MIME types compressed, e.g. `text/*` or `application/json`. A range request on a
+    /// compressed file decompresses it from the first byte, so audio, video and PDF files are
+    /// never compressed, even when listed here.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithm: CompressionAlgorithm::default(),
+            level: None,
+            content_types: [
+                "text/*",
+                "application/json",
+                "application/x-ndjson",
+                "application/xml",
+                "application/javascript",
+                "application/x-yaml",
+                "application/yaml",
+                "application/sql",
+                "application/x-sh",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|content_type| content_type.to_string())
+            .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// Encrypt new objects at rest; objects stored in plaintext before stay readable
+    pub enabled: bool,
+    /// Id of the master key that wraps new data keys
+    pub active_key: String,
+    /// Master keys by id, each 32 bytes encoded in base64. Retired keys are kept until no data
+    /// key is wrapped by them any more.
+    pub master_keys: BTreeMap<String, String>,
+}
+
 #[derive(Deserialize)]
 pub struct Config {
-    pub cloudflare: CloudflareConfig,
+    pub cloudflare: Option<CloudflareConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub upload_policy: UploadPolicyConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
     pub postgres: PostgresConfig,
     pub backend: BackendConfig,
     pub redis: RedisConfig,
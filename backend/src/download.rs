@@ -0,0 +1,337 @@
+use actix_web::http::header::{
+    self, ByteRangeSpec, ContentDisposition, ContentRange, ContentRangeSpec, EntityTag, Header,
+    HttpDate, IfRange, Range,
+};
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::{future, StreamExt, TryStreamExt};
+use std::time::SystemTime;
+
+use crate::storage::compression::{self, ContentEncoding};
+use crate::storage::{ByteRange, FileMetadata, ObjectBody, ObjectStorage, ObjectStream, StorageError};
+
+/// Strong entity tag identifying the stored content of a file; versions never change once uploaded
+fn file_etag(file: &FileMetadata) -> EntityTag {
+    EntityTag::new_strong(format!("{}-{}", file.id, file.version))
+}
+
+fn file_last_modified(file: &FileMetadata) -> HttpDate {
+    HttpDate::from(SystemTime::from(file.upload_time))
+}
+
+/// Whether an `If-Range` precondition still matches the current file, so the range can be honored
+fn if_range_matches(req: &HttpRequest, etag: &EntityTag, last_modified: &HttpDate) -> bool {
+    if !req.headers().contains_key(header::IF_RANGE) {
+        return true;
+    }
+
+    match IfRange::parse(req) {
+        Ok(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Ok(IfRange::Date(date)) => date == *last_modified,
+        Err(_) => false,
+    }
+}
+
+/// Single byte range requested by the client, if it should be honored.
+///
+/// Multiple ranges are not supported; such requests are answered with the whole file.
+fn requested_range(req: &HttpRequest, etag: &EntityTag, last_modified: &HttpDate) -> Option<ByteRangeSpec> {
+    if !req.headers().contains_key(header::RANGE) {
+        return None;
+    }
+
+    match Range::parse(req) {
+        Ok(Range::Bytes(mut specs)) if specs.len() == 1 && if_range_matches(req, etag, last_modified) => specs.pop(),
+        _ => None,
+    }
+}
+
+/// Whether `serve_file` would answer with a range starting after the first byte, as when a
+/// client resumes or seeks within a download it already started
+pub fn resumes_download(req: &HttpRequest, file: &FileMetadata) -> bool {
+    requested_range(req, &file_etag(file), &file_last_modified(file))
+        .and_then(|spec| spec.to_satisfiable_range(file.size))
+        .is_some_and(|(start, _)| start > 0)
+}
+
+/// Whether the request's `Accept-Encoding` lists `encoding` with a non-zero weight
+fn accepts_encoding(req: &HttpRequest, encoding: ContentEncoding) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let weight = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|weight| weight.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case(encoding.as_str()) && weight > 0.0
+        })
+}
+
+/// The bytes of `range` out of `body`, which starts at the beginning of the content
+fn slice_range(body: ObjectStream, range: ByteRange) -> ObjectStream {
+    let sliced = body
+        .scan(0u64, move |offset, chunk| {
+            let item = match chunk {
+                Ok(_) if *offset > range.end => return future::ready(None),
+                Ok(chunk) => {
+                    let start = *offset;
+                    *offset += chunk.len() as u64;
+                    let from = range.start.saturating_sub(start).min(chunk.len() as u64) as usize;
+                    let to = (range.end + 1).saturating_sub(start).min(chunk.len() as u64) as usize;
+                    Ok(chunk.slice(from..to))
+                }
+                Err(e) => Err(e),
+            };
+            future::ready(Some(item))
+        })
+        .try_filter(|chunk| future::ready(!chunk.is_empty()));
+    Box::pin(sliced)
+}
+
+/// Stream a file from storage, honoring `Range` and `If-Range` request headers.
+///
+/// Compressed files are sent as stored, with a `Content-Encoding`, to clients accepting the
+/// encoding. Otherwise, and for range requests, which address the content as uploaded, they
+/// are decompressed on the way.
+pub async fn serve_file(
+    req: &HttpRequest,
+    storage: &dyn ObjectStorage,
+    file: &FileMetadata,
+) -> Result<HttpResponse, StorageError> {
+    let etag = file_etag(file);
+    let last_modified = file_last_modified(file);
+
+    let range = match requested_range(req, &etag, &last_modified) {
+        Some(spec) => match spec.to_satisfiable_range(file.size) {
+            Some((start, end)) => Some(ByteRange { start, end }),
+            None => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                        range: None,
+                        instance_length: Some(file.size),
+                    }))
+                    .finish());
+            }
+        },
+        None => None,
+    };
+
+    let encoding = compression::stored_encoding(file)?;
+    let passthrough = encoding.filter(|&encoding| range.is_none() && accepts_encoding(req, encoding));
+
+    let object = match (encoding, passthrough) {
+        (None, _) => storage.get_object(&file.s3_key, range).await?,
+        (Some(_), Some(_)) => storage.get_object(&file.s3_key, None).await?,
+        (Some(_), None) => {
+            let content = compression::get_content(storage, file).await?;
+            match range {
+                Some(range) => ObjectBody {
+                    body: slice_range(content.body, range),
+                    content_length: range.end - range.start + 1,
+                },
+                None => content,
+            }
+        }
+    };
+
+    let mut response = match range {
+        Some(range) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((range.start, range.end)),
+                instance_length: Some(file.size),
+            }));
+            response
+        }
+        None => HttpResponse::Ok(),
+    };
+
+    if encoding.is_some() {
+        response.insert_header((header::VARY, "Accept-Encoding"));
+    }
+    let etag = match passthrough {
+        // The compressed bytes are a different representation than the content as uploaded
+        Some(encoding) => {
+            response.insert_header((header::CONTENT_ENCODING, encoding.as_str()));
+            EntityTag::new_strong(format!("{}-{}", etag.tag(), encoding.as_str()))
+        }
+        None => etag,
+    };
+
+    Ok(response
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(header::ETag(etag))
+        .insert_header(header::LastModified(last_modified))
+        .insert_header(ContentDisposition::attachment(file.filename.clone()))
+        .content_type(
+            file.content_type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+        )
+        .no_chunking(object.content_length)
+        .streaming(object.body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use bytes::Bytes;
+    use chrono::{TimeZone, Utc};
+
+    fn file(size: u64) -> FileMetadata {
+        FileMetadata {
+            id: "file".to_string(),
+            filename: "file.bin".to_string(),
+            size,
+            content_type: None,
+            upload_time: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+            s3_key: "file.bin".to_string(),
+            content_hash: None,
+            version: 2,
+            folder_id: None,
+            workspace_id: None,
+            description: None,
+            tags: Vec::new(),
+            attributes: Default::default(),
+            content_encoding: None,
+            expires_at: None,
+        }
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// The range `serve_file` would send for `headers`, as inclusive offsets into a file of `size` bytes
+    fn range(headers: &[(&str, &str)], size: u64) -> Option<Option<(u64, u64)>> {
+        let mut req = TestRequest::default();
+        for &(name, value) in headers {
+            req = req.insert_header((name, value));
+        }
+        let file = file(size);
+        requested_range(&req.to_http_request(), &file_etag(&file), &file_last_modified(&file))
+            .map(|spec| spec.to_satisfiable_range(size))
+    }
+
+    async fn serve(headers: &[(&str, &str)], stored: &[u8]) -> (StatusCode, Option<String>, Bytes) {
+        let storage = MemoryStorage::new();
+        let file = file(stored.len() as u64);
+        storage.put_object(&file.s3_key, stored.to_vec(), None).await.unwrap();
+        let mut req = TestRequest::default();
+        for &(name, value) in headers {
+            req = req.insert_header((name, value));
+        }
+        let response = serve_file(&req.to_http_request(), &storage, &file).await.unwrap();
+        let content_range = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.status(), content_range, to_bytes(response.into_body()).await.unwrap())
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range(&[], 100), None);
+        assert_eq!(range(&[("Range", "bytes=10-19")], 100), Some(Some((10, 19))));
+        assert_eq!(range(&[("Range", "bytes=90-200")], 100), Some(Some((90, 99))));
+        assert_eq!(range(&[("Range", "bytes=50-")], 100), Some(Some((50, 99))));
+        // Multiple ranges are answered with the whole file
+        assert_eq!(range(&[("Range", "bytes=0-9,20-29")], 100), None);
+        assert_eq!(range(&[("Range", "lines=1-2")], 100), None);
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(range(&[("Range", "bytes=-10")], 100), Some(Some((90, 99))));
+        assert_eq!(range(&[("Range", "bytes=-500")], 100), Some(Some((0, 99))));
+        assert_eq!(range(&[("Range", "bytes=-0")], 100), Some(None));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(range(&[("Range", "bytes=100-")], 100), Some(None));
+        assert_eq!(range(&[("Range", "bytes=150-160")], 100), Some(None));
+        assert_eq!(range(&[("Range", "bytes=0-")], 0), Some(None));
+    }
+
+    #[test]
+    fn if_range() {
+        let file = file(100);
+        let etag = file_etag(&file).to_string();
+        let last_modified = file_last_modified(&file).to_string();
+        let stale_date = HttpDate::from(SystemTime::from(Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()));
+        let stale_date = stale_date.to_string();
+
+        assert_eq!(range(&[("Range", "bytes=10-"), ("If-Range", &etag)], 100), Some(Some((10, 99))));
+        assert_eq!(range(&[("Range", "bytes=10-"), ("If-Range", &last_modified)], 100), Some(Some((10, 99))));
+        // A changed file is sent whole instead of the range
+        assert_eq!(range(&[("Range", "bytes=10-"), ("If-Range", "\"file-1\"")], 100), None);
+        assert_eq!(range(&[("Range", "bytes=10-"), ("If-Range", &stale_date)], 100), None);
+        // Weak tags never match
+        assert_eq!(range(&[("Range", "bytes=10-"), ("If-Range", &format!("W/{}", etag))], 100), None);
+        assert_eq!(range(&[("Range", "bytes=10-"), ("If-Range", "garbage")], 100), None);
+    }
+
+    #[test]
+    fn resumed_downloads() {
+        let file = file(100);
+        let resumes = |range: &str| {
+            resumes_download(&TestRequest::default().insert_header(("Range", range)).to_http_request(), &file)
+        };
+        assert!(resumes("bytes=10-"));
+        assert!(resumes("bytes=-10"));
+        assert!(!resumes("bytes=0-9"));
+        assert!(!resumes("bytes=-100"));
+        assert!(!resumes("bytes=200-"));
+    }
+
+    #[tokio::test]
+    async fn slices() {
+        let stored = content(1000);
+        for (start, end) in [(0, 999), (0, 0), (999, 999), (100, 299), (250, 250), (199, 200)] {
+            // Chunks of 100 bytes, so ranges start and end inside, at and across chunk boundaries
+            let chunks: Vec<Result<Bytes, StorageError>> =
+                stored.chunks(100).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+            let body: ObjectStream = Box::pin(futures_util::stream::iter(chunks));
+            let sliced: Vec<Bytes> = slice_range(body, ByteRange { start, end }).try_collect().await.unwrap();
+            assert_eq!(sliced.concat(), stored[start as usize..=end as usize], "{}-{}", start, end);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let stored = content(1000);
+
+        let (status, content_range, body) = serve(&[], &stored).await;
+        assert_eq!((status, content_range), (StatusCode::OK, None));
+        assert_eq!(body, stored);
+
+        let (status, content_range, body) = serve(&[("Range", "bytes=-100")], &stored).await;
+        assert_eq!((status, content_range.as_deref()), (StatusCode::PARTIAL_CONTENT, Some("bytes 900-999/1000")));
+        assert_eq!(body, stored[900..]);
+
+        let (status, content_range, _) = serve(&[("Range", "bytes=1000-")], &stored).await;
+        assert_eq!((status, content_range.as_deref()), (StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */1000")));
+
+        let (status, _, body) = serve(&[("Range", "bytes=10-"), ("If-Range", "\"file-1\"")], &stored).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, stored);
+    }
+
+    #[tokio::test]
+    async fn serves_empty_files() {
+        let (status, _, body) = serve(&[], &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_empty());
+
+        let (status, content_range, _) = serve(&[("Range", "bytes=0-")], &[]).await;
+        assert_eq!((status, content_range.as_deref()), (StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */0")));
+    }
+}
@@ -0,0 +1,341 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::error::SqlState;
+
+use crate::auth::Claims;
+use crate::storage::Folder;
+use crate::{check_file_ownership, error_response, AppState, FileInfo, UploadResponse};
+
+/// Longest accepted folder or file name, in characters
+pub const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateFolderRequest {
+    pub name: String,
+    /// Folder to create the new folder in, the top level if absent
+    pub parent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameFolderRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveFolderRequest {
+    /// New parent folder, the top level if absent
+    pub parent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveFileRequest {
+    /// Folder to move the file to, the top level if absent
+    pub folder_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FolderResponse {
+    pub success: bool,
+    pub message: String,
+    pub folder: Option<Folder>,
+}
+
+#[derive(Serialize)]
+pub struct FolderContentsResponse {
+    pub success: bool,
+    pub message: String,
+    /// The listed folder, `None` for the top level
+    pub folder: Option<Folder>,
+    pub folders: Vec<Folder>,
+    pub files: Vec<FileInfo>,
+}
+
+/// Trim a folder or file name and check that it can be used, returning why not otherwise
+pub fn validate_name(name: &str) -> std::result::Result<String, &'static str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Name must not be empty");
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err("Name is too long");
+    }
+    if name == "." || name == ".." || name.contains(['/', '\\']) || name.chars().any(char::is_control) {
+        return Err("Name contains invalid characters");
+    }
+    Ok(name.to_string())
+}
+
+fn is_unique_violation(e: &tokio_postgres::Error) -> bool {
+    e.code() == Some(&SqlState::UNIQUE_VIOLATION)
+}
+
+/// Check that `user_id` owns the folder `folder_id`, answering with an error response if not
+pub async fn check_folder_ownership(data: &AppState, folder_id: &str, user_id: i64) -> Result<Folder, HttpResponse> {
+    match data.file_metadata.get_folder(folder_id).await {
+        Ok(Some(record)) if record.user_id == user_id => Ok(record.folder),
+        Ok(Some(_)) => Err(error_response(HttpResponse::Forbidden(), "Access denied: You don't own this folder")),
+        Ok(None) => Err(error_response(HttpResponse::NotFound(), "Folder not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(
+                HttpResponse::InternalServerError(),
+                "Database error while checking folder ownership",
+            ))
+        }
+    }
+}
+
+async fn contents_response(data: &AppState, user_id: i64, folder: Option<Folder>) -> HttpResponse {
+    let folder_id = folder.as_ref().map(|folder| folder.id.as_str());
+
+    let folders = match data.file_metadata.list_folders(user_id, folder_id).await {
+        Ok(folders) => folders,
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return error_response(HttpResponse::InternalServerError(), "Failed to retrieve folders");
+        }
+    };
+
+    match data.file_metadata.list_folder_files(user_id, folder_id).await {
+        Ok(files) => HttpResponse::Ok().json(FolderContentsResponse {
+            success: true,
+            message: "Folder contents retrieved successfully".to_string(),
+            folder,
+            folders,
+            files,
+        }),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            error_response(HttpResponse::InternalServerError(), "Failed to retrieve files")
+        }
+    }
+}
+
+// List the folders and files at the top level
+pub async fn list_root(
+    claims: Claims,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    Ok(contents_response(&data, user_id, None).await)
+}
+
+// List the folders and files directly inside a folder
+pub async fn list_folder(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let folder_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let folder = match check_folder_ownership(&data, &folder_id, user_id).await {
+        Ok(folder) => folder,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(contents_response(&data, user_id, Some(folder)).await)
+}
+
+pub async fn create_folder(
+    claims: Claims,
+    folder_req: web::Json<CreateFolderRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let folder_req = folder_req.into_inner();
+    let name = match validate_name(&folder_req.name) {
+        Ok(name) => name,
+        Err(message) => return Ok(error_response(HttpResponse::BadRequest(), message)),
+    };
+
+    if let Some(parent_id) = &folder_req.parent_id {
+        if let Err(response) = check_folder_ownership(&data, parent_id, user_id).await {
+            return Ok(response);
+        }
+    }
+
+    match data.file_metadata.create_folder(user_id, &name, folder_req.parent_id.as_deref()).await {
+        Ok(folder) => Ok(HttpResponse::Created().json(FolderResponse {
+            success: true,
+            message: "Folder created successfully".to_string(),
+            folder: Some(folder),
+        })),
+        Err(e) if is_unique_violation(&e) => Ok(error_response(
+            HttpResponse::Conflict(),
+            "A folder with this name already exists here",
+        )),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to create folder"))
+        }
+    }
+}
+
+pub async fn rename_folder(
+    claims: Claims,
+    path: web::Path<String>,
+    rename_req: web::Json<RenameFolderRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let folder_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let name = match validate_name(&rename_req.name) {
+        Ok(name) => name,
+        Err(message) => return Ok(error_response(HttpResponse::BadRequest(), message)),
+    };
+
+    if let Err(response) = check_folder_ownership(&data, &folder_id, user_id).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.rename_folder(&folder_id, &name).await {
+        Ok(Some(folder)) => Ok(HttpResponse::Ok().json(FolderResponse {
+            success: true,
+            message: "Folder renamed successfully".to_string(),
+            folder: Some(folder),
+        })),
+        Ok(None) => Ok(error_response(HttpResponse::NotFound(), "Folder not found")),
+        Err(e) if is_unique_violation(&e) => Ok(error_response(
+            HttpResponse::Conflict(),
+            "A folder with this name already exists here",
+        )),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to rename folder"))
+        }
+    }
+}
+
+pub async fn move_folder(
+    claims: Claims,
+    path: web::Path<String>,
+    move_req: web::Json<MoveFolderRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let folder_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_folder_ownership(&data, &folder_id, user_id).await {
+        return Ok(response);
+    }
+    if let Some(parent_id) = &move_req.parent_id {
+        if let Err(response) = check_folder_ownership(&data, parent_id, user_id).await {
+            return Ok(response);
+        }
+    }
+
+    // The folder exists, so no update means the target is the folder itself or one of its subfolders
+    match data.file_metadata.move_folder(&folder_id, move_req.parent_id.as_deref()).await {
+        Ok(Some(folder)) => Ok(HttpResponse::Ok().json(FolderResponse {
+            success: true,
+            message: "Folder moved successfully".to_string(),
+            folder: Some(folder),
+        })),
+        Ok(None) => Ok(error_response(
+            HttpResponse::BadRequest(),
+            "A folder cannot be moved into itself or one of its subfolders",
+        )),
+        Err(e) if is_unique_violation(&e) => Ok(error_response(
+            HttpResponse::Conflict(),
+            "A folder with this name already exists in the target folder",
+        )),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to move folder"))
+        }
+    }
+}
+
+// Delete a folder with all its subfolders, moving the files inside them to the trash
+pub async fn delete_folder(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let folder_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let folder = match check_folder_ownership(&data, &folder_id, user_id).await {
+        Ok(folder) => folder,
+        Err(response) => return Ok(response),
+    };
+
+    match data.file_metadata.delete_folder(&folder_id, user_id).await {
+        Ok(0) => Ok(HttpResponse::Ok().json(FolderResponse {
+            success: true,
+            message: "Folder deleted successfully".to_string(),
+            folder: Some(folder),
+        })),
+        Ok(_) => Ok(HttpResponse::Ok().json(FolderResponse {
+            success: true,
+            message: "Folder deleted; its files were moved to the trash".to_string(),
+            folder: Some(folder),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(
+                HttpResponse::InternalServerError(),
+                "Failed to delete folder; nothing was deleted",
+            ))
+        }
+    }
+}
+
+// Move a file into another folder or to the top level
+pub async fn move_file(
+    claims: Claims,
+    path: web::Path<String>,
+    move_req: web::Json<MoveFileRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let mut file_metadata = match check_file_ownership(&data, &file_id, user_id).await {
+        Ok(file_metadata) => file_metadata,
+        Err(response) => return Ok(response),
+    };
+    if let Some(folder_id) = &move_req.folder_id {
+        if let Err(response) = check_folder_ownership(&data, folder_id, user_id).await {
+            return Ok(response);
+        }
+    }
+
+    // The file exists, so no update means the name is already taken in the target folder
+    match data.file_metadata.move_file(&file_id, move_req.folder_id.as_deref()).await {
+        Ok(true) => {
+            file_metadata.folder_id = move_req.into_inner().folder_id;
+            Ok(HttpResponse::Ok().json(UploadResponse {
+                success: true,
+                message: "File moved successfully".to_string(),
+                file: Some(file_metadata),
+            }))
+        }
+        Ok(false) => Ok(error_response(
+            HttpResponse::Conflict(),
+            "A file with this name already exists in the target folder",
+        )),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to move file"))
+        }
+    }
+}
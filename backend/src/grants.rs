@@ -0,0 +1,168 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{AuthService, Claims};
+use crate::storage::{FileGrant, Permission, SharedFile};
+use crate::{check_file_access, error_response, AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct GrantRequest {
+    /// Email address the other user signed in with
+    pub email: String,
+    pub permission: Permission,
+}
+
+#[derive(Serialize)]
+pub struct GrantResponse {
+    pub success: bool,
+    pub message: String,
+    pub grant: Option<FileGrant>,
+}
+
+#[derive(Serialize)]
+pub struct GrantsResponse {
+    pub success: bool,
+    pub message: String,
+    pub grants: Vec<FileGrant>,
+}
+
+#[derive(Serialize)]
+pub struct SharedFilesResponse {
+    pub files: Vec<SharedFile>,
+}
+
+fn grant_error(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(GrantResponse {
+        success: false,
+        message: message.to_string(),
+        grant: None,
+    })
+}
+
+// Grant another user access to a file, or change the access granted before
+pub async fn grant_access(
+    claims: Claims,
+    path: web::Path<String>,
+    grant_req: web::Json<GrantRequest>,
+    data: web::Data<AppState>,
+    auth_service: web::Data<AuthService>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        return Ok(response);
+    }
+
+    let grantee = match auth_service.get_user_by_email(&grant_req.email).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(grant_error(HttpResponse::NotFound(), "No user is registered with this email")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(grant_error(HttpResponse::InternalServerError(), "Failed to look up user"));
+        }
+    };
+
+    match data.file_metadata.get_file(&file_id).await {
+        Ok(Some(record)) if record.metadata.workspace_id.is_none() && record.user_id == grantee.id => {
+            return Ok(grant_error(HttpResponse::BadRequest(), "The owner of a file always has full access"));
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(grant_error(HttpResponse::NotFound(), "File not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(grant_error(HttpResponse::InternalServerError(), "Database error while checking file ownership"));
+        }
+    }
+
+    match data.file_metadata.grant_access(&file_id, grantee.id, grant_req.permission, user_id).await {
+        Ok(grant) => Ok(HttpResponse::Ok().json(GrantResponse {
+            success: true,
+            message: format!("Granted {} access to {}", grant.permission.as_str(), grant.email),
+            grant: Some(grant),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(grant_error(HttpResponse::InternalServerError(), "Failed to grant access"))
+        }
+    }
+}
+
+// List the users granted access to a file
+pub async fn list_grants(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.list_file_grants(&file_id).await {
+        Ok(grants) => Ok(HttpResponse::Ok().json(GrantsResponse {
+            success: true,
+            message: "Access grants retrieved successfully".to_string(),
+            grants,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve access grants"))
+        }
+    }
+}
+
+// Withdraw the access granted to a user; grantees may also give up their own access
+pub async fn revoke_access(
+    claims: Claims,
+    path: web::Path<(String, i64)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (file_id, grantee_id) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if grantee_id != user_id {
+        if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+            return Ok(response);
+        }
+    }
+
+    match data.file_metadata.revoke_access(&file_id, grantee_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(GrantResponse {
+            success: true,
+            message: "Access revoked successfully".to_string(),
+            grant: None,
+        })),
+        Ok(false) => Ok(grant_error(HttpResponse::NotFound(), "This user has no access grant on the file")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(grant_error(HttpResponse::InternalServerError(), "Failed to revoke access"))
+        }
+    }
+}
+
+// List the files other users granted the current user access to
+pub async fn list_shared_with_me(
+    claims: Claims,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    match data.file_metadata.list_shared_files(user_id).await {
+        Ok(files) => Ok(HttpResponse::Ok().json(SharedFilesResponse { files })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve shared files"))
+        }
+    }
+}
@@ -1,31 +1,54 @@
 use actix_cors::Cors;
 use actix_multipart::Multipart;
-use actix_web::{middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer, Result};
-use futures_util::TryStreamExt as _;
-use serde_json;
-use std::collections::HashMap;
+use actix_web::{http::StatusCode, middleware::Logger, mime, web, App, HttpRequest, HttpResponse, HttpServer, Result};
+use futures_util::{StreamExt as _, TryStreamExt as _};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use actix_web::http::header::ContentType;
+use std::time::Duration;
 
 mod storage;
+mod archive;
 mod auth;
 mod conf;
-use storage::{CloudflareStorage, FileMetadata};
+mod cleanup;
+mod download;
+mod folders;
+mod grants;
+mod migrations;
+mod policy;
+mod presign;
+mod quota;
+mod shares;
+mod tags;
+mod trash;
+mod tus;
+mod versions;
+mod workspaces;
+use storage::{
+    create_storage, FileCursor, FileFilter, FileMetadata, FileOwner, FilePage, FileRecord, FileSort, MetadataStore,
+    ObjectStorage, Permission, UploadStreamError, WorkspaceRole,
+};
 use auth::{AuthService, Claims, login, me, logout, logout_all};
 use conf::load_config;
 
-fn get_current_time() -> String {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    format!("{}", duration.as_secs())
-}
-
 // Application state to hold storage and metadata
 struct AppState {
-    storage: CloudflareStorage,
-    // In-memory storage for file metadata (in production, use a database)
-    file_metadata: Arc<Mutex<HashMap<String, FileMetadata>>>,
+    storage: Arc<dyn ObjectStorage>,
+    // File metadata persisted in Postgres
+    file_metadata: MetadataStore,
+    // Lifetime of presigned upload and download URLs
+    presign_expiry: Duration,
+    // Number of most recent versions kept per file
+    version_retention: i32,
+    // Time an unfinished resumable upload may stay idle
+    resumable_upload_expiry: Duration,
+    // Time a deleted file stays in the trash before it is purged
+    trash_retention: Duration,
+    // Storage quota of users without an override, `None` if unlimited
+    default_quota: Option<u64>,
+    // Limits on the size, name and type of uploads
+    upload_policy: policy::UploadPolicy,
+    // Content types compressed before they are stored
+    compression: policy::CompressionPolicy,
 }
 
 // Alias for FileInfo to maintain API compatibility
@@ -41,112 +64,404 @@ struct UploadResponse {
 #[derive(serde::Serialize)]
 struct FilesListResponse {
     files: Vec<FileInfo>,
+    // Pass as `cursor` to fetch the next page; absent on the last page
+    next_cursor: Option<String>,
+}
+
+// Number of files listed per page unless `limit` says otherwise
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+fn error_response(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(UploadResponse {
+        success: false,
+        message: message.to_string(),
+        file: None,
+    })
+}
+
+/// Response for a file past its expiry time that the cleanup task has not deleted yet
+fn file_expired_response() -> HttpResponse {
+    error_response(HttpResponse::Gone(), "This file has expired")
+}
+
+/// Check that `user_id` owns the file `file_id`, answering with an error response if not.
+///
+/// Files in a workspace belong to the workspace, not to the member who uploaded them.
+async fn check_file_ownership(data: &AppState, file_id: &str, user_id: i64) -> Result<FileInfo, HttpResponse> {
+    match data.file_metadata.get_file(file_id).await {
+        Ok(Some(record)) if record.metadata.workspace_id.is_some() => {
+            Err(error_response(HttpResponse::Forbidden(), "Access denied: This file belongs to a workspace"))
+        }
+        Ok(Some(record)) if record.user_id == user_id && record.metadata.is_expired() => Err(file_expired_response()),
+        Ok(Some(record)) if record.user_id == user_id => Ok(record.metadata),
+        Ok(Some(_)) => Err(error_response(HttpResponse::Forbidden(), "Access denied: You don't own this file")),
+        Ok(None) => Err(error_response(HttpResponse::NotFound(), "File not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(
+                HttpResponse::InternalServerError(),
+                "Database error while checking file ownership",
+            ))
+        }
+    }
+}
+
+/// Check that `user_id` owns the file `file_id`, has a role in its workspace allowing `permission`,
+/// or was granted at least `permission` on it, answering with an error response if not
+async fn check_file_access(
+    data: &AppState,
+    file_id: &str,
+    user_id: i64,
+    permission: Permission,
+) -> Result<FileInfo, HttpResponse> {
+    match data.file_metadata.get_file(file_id).await {
+        Ok(Some(record)) => {
+            let file = authorize_file_access(data, record, user_id, permission).await?;
+            if file.is_expired() {
+                return Err(file_expired_response());
+            }
+            Ok(file)
+        }
+        Ok(None) => Err(error_response(HttpResponse::NotFound(), "File not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(
+                HttpResponse::InternalServerError(),
+                "Database error while checking file ownership",
+            ))
+        }
+    }
+}
+
+/// Check that `user_id` may access the file of `record` with `permission`, like `check_file_access`
+async fn authorize_file_access(
+    data: &AppState,
+    record: FileRecord,
+    user_id: i64,
+    permission: Permission,
+) -> Result<FileInfo, HttpResponse> {
+    let file_id = record.metadata.id.as_str();
+
+    // Workspace files are reached through the member's role; whoever uploaded them has no say of their own
+    let role_permission = match &record.metadata.workspace_id {
+        None if record.user_id == user_id => return Ok(record.metadata),
+        None => None,
+        Some(workspace_id) => match data.file_metadata.workspace_role(workspace_id, user_id).await {
+            Ok(role) => role.map(WorkspaceRole::permission),
+            Err(e) => {
+                eprintln!("Database error: {}", e);
+                return Err(error_response(
+                    HttpResponse::InternalServerError(),
+                    "Database error while checking workspace membership",
+                ));
+            }
+        },
+    };
+    if role_permission.is_some_and(|granted| granted >= permission) {
+        return Ok(record.metadata);
+    }
+
+    match data.file_metadata.file_permission(file_id, user_id).await {
+        Ok(grant) => match role_permission.max(grant) {
+            Some(granted) if granted >= permission => Ok(record.metadata),
+            Some(_) => Err(error_response(
+                HttpResponse::Forbidden(),
+                &format!("Access denied: You need {} access to this file", permission.as_str()),
+            )),
+            None => Err(error_response(HttpResponse::Forbidden(), "Access denied: You don't own this file")),
+        },
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(
+                HttpResponse::InternalServerError(),
+                "Database error while checking file access",
+            ))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UploadQuery {
+    // Folder to upload into, the top level if absent
+    folder_id: Option<String>,
+    // Workspace to upload into instead of the user's own files
+    workspace_id: Option<String>,
+    // Time after which the uploaded files are gone and deleted
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Seconds from now after which the uploaded files are gone, instead of `expires_at`
+    ttl: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+struct DownloadQuery {
+    // Workspace the file is expected in; files elsewhere are reported as not found
+    workspace_id: Option<String>,
+}
+
+/// Outcome of one file of an upload request
+#[derive(serde::Serialize)]
+struct FileUploadResult {
+    filename: String,
+    success: bool,
+    message: String,
+    file: Option<FileInfo>,
+}
+
+#[derive(serde::Serialize)]
+struct BatchUploadResponse {
+    success: bool,
+    message: String,
+    // First file stored, for clients sending one file per request
+    file: Option<FileInfo>,
+    // One entry per file part, in request order
+    files: Vec<FileUploadResult>,
+}
+
+/// Why a file of an upload request was not stored
+enum FileUploadError {
+    /// The file was refused or could not be stored; the other files of the request are unaffected
+    Failed(StatusCode, String),
+    /// Reading the request failed, so neither this file nor any after it can be read
+    Aborted(actix_web::Error),
+}
+
+impl From<policy::PolicyViolation> for FileUploadError {
+    fn from(violation: policy::PolicyViolation) -> Self {
+        FileUploadError::Failed(violation.status(), violation.message())
+    }
+}
+
+/// Where the files of an upload request are stored, and until when
+struct UploadTarget {
+    folder_id: Option<String>,
+    workspace_id: Option<String>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Check and store one file part of an upload request, stopping it once it grows beyond `max_size`
+async fn store_upload(
+    data: &AppState,
+    user_id: i64,
+    target: &UploadTarget,
+    filename: &str,
+    field: actix_multipart::Field,
+    max_size: Option<u64>,
+) -> std::result::Result<FileInfo, FileUploadError> {
+    let filename = &folders::validate_name(filename)
+        .map_err(|message| FileUploadError::Failed(StatusCode::BAD_REQUEST, message.to_string()))?;
+    data.upload_policy.check_filename(filename)?;
+
+    // The first bytes decide the stored type; they are put back in front of the rest of the field
+    let declared_type = field.content_type().map(|ct| ct.to_string());
+    let mut field = field.fuse();
+    let prefix = policy::read_prefix(&mut field).await.map_err(|e| FileUploadError::Aborted(e.into()))?;
+    let content_type = data.upload_policy.resolve_content_type(declared_type.as_deref(), &prefix)?;
+    let body = futures_util::stream::iter([Ok(prefix)]).chain(field);
+    let compression = data.compression.compression_for(content_type.as_deref());
+
+    // Stream the file to the configured object storage, one part at a time
+    let upload = storage::upload_file(data.storage.as_ref(), filename, content_type, body, max_size, compression);
+    let mut file_metadata = match upload.await {
+        Ok(file_metadata) => file_metadata,
+        Err(UploadStreamError::Source(e)) => {
+            eprintln!("Upload aborted by client: {}", e);
+            return Err(FileUploadError::Aborted(e.into()));
+        }
+        Err(UploadStreamError::TooLarge(limit)) => {
+            if data.upload_policy.max_file_size() == Some(limit) {
+                return Err(policy::PolicyViolation::TooLarge(limit).into());
+            }
+            return Err(FileUploadError::Failed(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                quota::QUOTA_EXCEEDED_MESSAGE.to_string(),
+            ));
+        }
+        Err(UploadStreamError::Storage(e)) => {
+            eprintln!("Upload error: {}", e);
+            return Err(FileUploadError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to upload file to storage".to_string(),
+            ));
+        }
+    };
+    file_metadata.folder_id = target.folder_id.clone();
+    file_metadata.workspace_id = target.workspace_id.clone();
+    file_metadata.expires_at = target.expires_at;
+
+    // Store metadata in database; an existing file with this name gets a new version
+    match data.file_metadata.insert_file(user_id, &file_metadata).await {
+        Ok(stored) => {
+            if stored.s3_key != file_metadata.s3_key {
+                // Identical content is already stored; drop the copy just uploaded
+                cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &file_metadata.s3_key).await;
+            }
+            if stored.version > 1 {
+                if let Err(e) = versions::prune(data, &stored.id).await {
+                    eprintln!("Failed to prune versions of {}: {}", stored.id, e);
+                }
+            }
+            Ok(stored)
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            // Try to delete the uploaded file since database insert failed
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &file_metadata.s3_key).await;
+            Err(FileUploadError::Failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to save file metadata".to_string(),
+            ))
+        }
+    }
 }
 
 async fn upload_file(
     claims: Claims,
+    req: HttpRequest,
+    query: web::Query<UploadQuery>,
     mut payload: Multipart,
     data: web::Data<AppState>,
-    auth_service: web::Data<AuthService>,
 ) -> Result<HttpResponse> {
     let user_id: i64 = claims.sub.parse().map_err(|_| {
         actix_web::error::ErrorBadRequest("Invalid user ID")
     })?;
 
-    while let Some(mut field) = payload.try_next().await? {
-        let content_disposition = field.content_disposition();
+    let UploadQuery { folder_id, workspace_id, expires_at, ttl } = query.into_inner();
+    // Uploads may expire at a given time or after a number of seconds, but not both
+    let expires_at = match (expires_at, ttl) {
+        (Some(_), Some(_)) => {
+            return Ok(error_response(HttpResponse::BadRequest(), "Give either expires_at or ttl, not both"));
+        }
+        (None, Some(ttl)) => {
+            let expires_at = i64::try_from(ttl)
+                .ok()
+                .and_then(chrono::Duration::try_seconds)
+                .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl));
+            match expires_at {
+                Some(expires_at) => Some(expires_at),
+                None => return Ok(error_response(HttpResponse::BadRequest(), "Invalid ttl: too far in the future")),
+            }
+        }
+        (expires_at, None) => expires_at,
+    };
+    if expires_at.is_some_and(|expires_at| expires_at <= chrono::Utc::now()) {
+        return Ok(error_response(HttpResponse::BadRequest(), "Expiry time must be in the future"));
+    }
 
-        if let Some(filename) = content_disposition.get_filename() {
-            let filename = filename.to_string();
-            let content_type = field.content_type().map(|ct| ct.to_string());
+    if let Some(workspace_id) = &workspace_id {
+        // Folders belong to a single user, so workspace files are kept at the workspace's top level
+        if folder_id.is_some() {
+            return Ok(error_response(HttpResponse::BadRequest(), "Files in a workspace cannot be filed in folders"));
+        }
+        if let Err(response) = workspaces::check_workspace_role(&data, workspace_id, user_id, WorkspaceRole::Member).await {
+            return Ok(response);
+        }
+    }
+    if let Some(folder_id) = &folder_id {
+        if let Err(response) = folders::check_folder_ownership(&data, folder_id, user_id).await {
+            return Ok(response);
+        }
+    }
+    let target = UploadTarget { folder_id, workspace_id, expires_at };
 
-            let mut file_content = Vec::new();
-            // Collect all chunks into a single buffer
-            while let Some(chunk) = field.try_next().await? {
-                file_content.extend_from_slice(&chunk);
-            }
+    // Refuse uploads that cannot fit the quota before reading the body, and stop any file that grows too large
+    // while streaming. The Content-Length covers the whole request, so it is only held against the quota;
+    // the size limit applies to each file on its own. Files uploaded to a workspace count towards the quota
+    // of the member uploading them.
+    let mut remaining = match quota::remaining_quota(&data, user_id).await {
+        Ok(remaining) => remaining,
+        Err(response) => return Ok(response),
+    };
+    if let Some(content_length) = policy::content_length(&req) {
+        if remaining.is_some_and(|remaining| content_length > remaining) {
+            return Ok(quota::quota_exceeded_response());
+        }
+    }
 
-            // Upload to Cloudflare R2
-            match data.storage.upload_file(&filename, file_content, content_type).await {
-                Ok(file_metadata) => {
-                    // Store metadata in database
-                    match auth_service.execute_query(
-                        "INSERT INTO user_files (user_id, file_key) VALUES ($1, $2)",
-                        &[&user_id, &file_metadata.s3_key.as_str()]
-                    ).await {
-                        Ok(_) => {
-                            // Also store in memory for backward compatibility
-                            let mut metadata_store = data.file_metadata.lock().await;
-                            metadata_store.insert(file_metadata.id.clone(), file_metadata.clone());
-
-                            return Ok(HttpResponse::Ok().json(UploadResponse {
-                                success: true,
-                                message: "File uploaded successfully".to_string(),
-                                file: Some(file_metadata),
-                            }));
-                        }
-                        Err(e) => {
-                            eprintln!("Database error: {}", e);
-                            // Try to delete the uploaded file since database insert failed
-                            let _ = data.storage.delete_file(&file_metadata.s3_key).await;
-                            return Ok(HttpResponse::InternalServerError().json(UploadResponse {
-                                success: false,
-                                message: "Failed to save file metadata".to_string(),
-                                file: None,
-                            }));
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Upload error: {}", e);
-                    return Ok(HttpResponse::InternalServerError().json(UploadResponse {
-                        success: false,
-                        message: "Failed to upload file to storage".to_string(),
-                        file: None,
-                    }));
-                }
+    let mut results = Vec::new();
+    let mut first_failure = None;
+    while let Some(field) = payload.try_next().await? {
+        let Some(filename) = field.content_disposition().get_filename().map(str::to_string) else {
+            continue;
+        };
+
+        // Files stored earlier in the request use up the quota of the ones after them
+        let max_size = [remaining, data.upload_policy.max_file_size()].into_iter().flatten().min();
+        match store_upload(&data, user_id, &target, &filename, field, max_size).await {
+            Ok(stored) => {
+                remaining = remaining.map(|remaining| remaining.saturating_sub(stored.size));
+                results.push(FileUploadResult {
+                    filename,
+                    success: true,
+                    message: "File uploaded successfully".to_string(),
+                    file: Some(stored),
+                });
+            }
+            Err(FileUploadError::Failed(status, message)) => {
+                first_failure.get_or_insert(status);
+                results.push(FileUploadResult { filename, success: false, message, file: None });
             }
+            Err(FileUploadError::Aborted(e)) => return Err(e),
         }
     }
 
-    Ok(HttpResponse::BadRequest().json(UploadResponse {
-        success: false,
-        message: "No file found in request".to_string(),
-        file: None,
+    if results.is_empty() {
+        return Ok(error_response(HttpResponse::BadRequest(), "No file found in request"));
+    }
+
+    let stored = results.iter().filter(|result| result.success).count();
+    let (status, message) = match first_failure {
+        None if stored == 1 => (StatusCode::OK, "File uploaded successfully".to_string()),
+        None => (StatusCode::OK, format!("{} files uploaded successfully", stored)),
+        Some(status) if stored == 0 && results.len() == 1 => (status, results[0].message.clone()),
+        Some(status) if stored == 0 => (status, "No files were uploaded".to_string()),
+        Some(_) => (
+            StatusCode::MULTI_STATUS,
+            format!("{} of {} files uploaded successfully", stored, results.len()),
+        ),
+    };
+
+    Ok(HttpResponse::build(status).json(BatchUploadResponse {
+        success: first_failure.is_none(),
+        message,
+        file: results.iter().find_map(|result| result.file.clone()),
+        files: results,
     }))
 }
 
 async fn list_files(
     claims: Claims,
+    query: web::Query<Vec<(String, String)>>,
     data: web::Data<AppState>,
-    auth_service: web::Data<AuthService>,
 ) -> Result<HttpResponse> {
     let user_id: i64 = claims.sub.parse().map_err(|_| {
         actix_web::error::ErrorBadRequest("Invalid user ID")
     })?;
 
-    // Get user's files from database
-    match auth_service.query_database(
-        "SELECT file_key FROM user_files WHERE user_id = $1",
-        &[&user_id]
-    ).await {
-        Ok(rows) => {
-            let metadata_store = data.file_metadata.lock().await;
-            let mut user_files = Vec::new();
-
-            for row in rows {
-                let file_key: String = row.get(0);
-                // Find matching file in memory store by s3_key
-                for file_metadata in metadata_store.values() {
-                    if file_metadata.s3_key == file_key {
-                        user_files.push(file_metadata.clone());
-                        break;
-                    }
-                }
+    // `workspace_id` lists the files of a workspace the user is a member of instead of their own
+    let mut query = query.into_inner();
+    let owner = match query.iter().position(|(name, _)| name == "workspace_id") {
+        Some(index) => {
+            let (_, workspace_id) = query.remove(index);
+            if let Err(response) = workspaces::check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Viewer).await {
+                return Ok(response);
             }
-
-            Ok(HttpResponse::Ok().json(FilesListResponse { files: user_files }))
+            FileOwner::Workspace(workspace_id)
         }
+        None => FileOwner::User(user_id),
+    };
+
+    let (filter, page) = match parse_listing_query(query) {
+        Ok(listing) => listing,
+        Err(message) => return Ok(error_response(HttpResponse::BadRequest(), &message)),
+    };
+
+    // Get user's files from database
+    match data.file_metadata.list_user_files(&owner, &filter, &page).await {
+        Ok((user_files, next)) => Ok(HttpResponse::Ok().json(FilesListResponse {
+            files: user_files,
+            next_cursor: next.map(|cursor| cursor.encode()),
+        })),
         Err(e) => {
             eprintln!("Database error: {}", e);
             Ok(HttpResponse::InternalServerError().json(UploadResponse {
@@ -158,92 +473,344 @@ async fn list_files(
     }
 }
 
+/// Build the filter and page of a file listing from its query parameters.
+///
+/// `tag` may be repeated and `meta.<key>=<value>` filters on custom attributes; unknown
+/// parameters are ignored.
+fn parse_listing_query(query: Vec<(String, String)>) -> std::result::Result<(FileFilter, FilePage), String> {
+    fn number(name: &str, value: &str) -> std::result::Result<u64, String> {
+        value.parse().map_err(|_| format!("Invalid {}: expected a non-negative integer", name))
+    }
+    fn timestamp(name: &str, value: &str) -> std::result::Result<chrono::DateTime<chrono::Utc>, String> {
+        chrono::DateTime::parse_from_rfc3339(value)
+            .map(|time| time.with_timezone(&chrono::Utc))
+            .map_err(|_| format!("Invalid {}: expected an RFC 3339 timestamp", name))
+    }
+
+    let mut filter = FileFilter::default();
+    let mut page = FilePage {
+        sort: FileSort::default(),
+        descending: false,
+        limit: DEFAULT_PAGE_SIZE,
+        after: None,
+    };
+    let mut cursor = None;
+
+    for (name, value) in query {
+        match name.as_str() {
+            "tag" => filter.tags.push(value),
+            "q" => filter.name_contains = Some(value),
+            "content_type" => filter.content_type_prefix = Some(value),
+            "min_size" => filter.min_size = Some(number(&name, &value)?),
+            "max_size" => filter.max_size = Some(number(&name, &value)?),
+            "uploaded_after" => filter.uploaded_after = Some(timestamp(&name, &value)?),
+            "uploaded_before" => filter.uploaded_before = Some(timestamp(&name, &value)?),
+            "sort" => {
+                page.sort = match value.as_str() {
+                    "name" => FileSort::Name,
+                    "size" => FileSort::Size,
+                    "date" => FileSort::Date,
+                    _ => return Err("Invalid sort: expected name, size or date".to_string()),
+                }
+            }
+            "order" => {
+                page.descending = match value.as_str() {
+                    "asc" => false,
+                    "desc" => true,
+                    _ => return Err("Invalid order: expected asc or desc".to_string()),
+                }
+            }
+            "limit" => match value.parse() {
+                Ok(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => page.limit = limit,
+                _ => return Err(format!("Invalid limit: expected 1 to {}", MAX_PAGE_SIZE)),
+            },
+            "cursor" => cursor = Some(value),
+            _ => {
+                if let Some(key) = name.strip_prefix("meta.") {
+                    filter.attributes.push((key.to_string(), value));
+                }
+            }
+        }
+    }
+
+    if let Some(cursor) = cursor {
+        match FileCursor::decode(&cursor) {
+            Some(cursor) if cursor.sort == page.sort && cursor.descending == page.descending => page.after = Some(cursor),
+            Some(_) => return Err("The cursor belongs to a listing with a different sort order".to_string()),
+            None => return Err("Invalid cursor".to_string()),
+        }
+    }
+
+    Ok((filter, page))
+}
+
 async fn download_file(
     claims: Claims,
+    req: HttpRequest,
     path: web::Path<String>,
+    query: web::Query<DownloadQuery>,
     data: web::Data<AppState>,
-    auth_service: web::Data<AuthService>,
 ) -> Result<HttpResponse> {
     let file_id = path.into_inner();
     let user_id: i64 = claims.sub.parse().map_err(|_| {
         actix_web::error::ErrorBadRequest("Invalid user ID")
     })?;
 
-    // Find the file metadata and verify ownership
-    let metadata_store = data.file_metadata.lock().await;
-    if let Some(file_metadata) = metadata_store.get(&file_id) {
-        let s3_key = file_metadata.s3_key.clone();
-        let original_filename = file_metadata.filename.clone();
-        drop(metadata_store); // Release the lock before async operation
-
-        // Check if user owns this file
-        match auth_service.query_database(
-            "SELECT 1 FROM user_files WHERE user_id = $1 AND file_key = $2",
-            &[&user_id, &s3_key.as_str()]
-        ).await {
-            Ok(rows) => {
-                if rows.is_empty() {
-                    return Ok(HttpResponse::Forbidden().json(UploadResponse {
-                        success: false,
-                        message: "Access denied: You don't own this file".to_string(),
-                        file: None,
-                    }));
-                }
+    // The owner, members of its workspace and users granted read access may download the file
+    let file_metadata = match check_file_access(&data, &file_id, user_id, Permission::Read).await {
+        Ok(file_metadata) => file_metadata,
+        Err(response) => return Ok(response),
+    };
+    if query.workspace_id.is_some() && query.workspace_id != file_metadata.workspace_id {
+        return Ok(error_response(HttpResponse::NotFound(), "File not found"));
+    }
 
-                // User owns the file, proceed with download
-                match data.storage.download_file(&s3_key).await {
-                    Ok(file_content) => {
-                        return Ok(HttpResponse::Ok()
-                            .append_header(("Content-Disposition", format!("attachment; filename=\"{}\"", original_filename)))
-                            .body(file_content));
-                    }
-                    Err(e) => {
-                        eprintln!("Download error: {}", e);
-                        return Ok(HttpResponse::InternalServerError().json(UploadResponse {
-                            success: false,
-                            message: "Failed to download file from storage".to_string(),
-                            file: None,
-                        }));
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Database error: {}", e);
-                return Ok(HttpResponse::InternalServerError().json(UploadResponse {
-                    success: false,
-                    message: "Database error while checking file ownership".to_string(),
-                    file: None,
-                }));
-            }
+    // Stream the file (or the requested range) from storage
+    match download::serve_file(&req, data.storage.as_ref(), &file_metadata).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            eprintln!("Download error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(UploadResponse {
+                success: false,
+                message: "Failed to download file from storage".to_string(),
+                file: None,
+            }))
         }
     }
+}
 
-    Ok(HttpResponse::NotFound().json(UploadResponse {
-        success: false,
-        message: "File not found".to_string(),
-        file: None,
-    }))
+async fn delete_file(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    // The owner, workspace admins and users granted manage access may delete the file
+    let file_metadata = match check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        Ok(file_metadata) => file_metadata,
+        Err(response) => return Ok(response),
+    };
+
+    // The file keeps its row and objects in the trash until it is restored or purged
+    match data.file_metadata.trash_file(&file_id, user_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(UploadResponse {
+            success: true,
+            message: "File moved to trash".to_string(),
+            file: Some(file_metadata),
+        })),
+        Ok(false) => Ok(error_response(HttpResponse::NotFound(), "File not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(
+                HttpResponse::InternalServerError(),
+                "Failed to move file to trash; the file was not deleted",
+            ))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateFileRequest {
+    filename: Option<String>,
+    content_type: Option<String>,
+    // An empty description clears it
+    description: Option<String>,
+}
+
+// Longest accepted file description, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+async fn update_file(
+    claims: Claims,
+    path: web::Path<String>,
+    update_req: web::Json<UpdateFileRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let update_req = update_req.into_inner();
+    if update_req.filename.is_none() && update_req.content_type.is_none() && update_req.description.is_none() {
+        return Ok(error_response(HttpResponse::BadRequest(), "Nothing to update"));
+    }
+
+    let filename = match update_req.filename.as_deref().map(folders::validate_name).transpose() {
+        Ok(filename) => filename,
+        Err(message) => return Ok(error_response(HttpResponse::BadRequest(), message)),
+    };
+
+    let content_type = match update_req.content_type.as_deref().map(str::parse::<mime::Mime>).transpose() {
+        Ok(content_type) => content_type.map(|content_type| content_type.to_string()),
+        Err(_) => return Ok(error_response(HttpResponse::BadRequest(), "Invalid content type")),
+    };
+
+    if let Some(description) = &update_req.description {
+        if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+            return Ok(error_response(HttpResponse::BadRequest(), "Description is too long"));
+        }
+    }
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Write).await {
+        return Ok(response);
+    }
+
+    // The file exists, so no update means another file in its folder already has the new name
+    match data.file_metadata
+        .update_file(&file_id, filename.as_deref(), content_type.as_deref(), update_req.description.as_deref())
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(error_response(
+                HttpResponse::Conflict(),
+                "A file with this name already exists in this folder",
+            ));
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(error_response(HttpResponse::InternalServerError(), "Failed to update file metadata"));
+        }
+    }
+
+    match data.file_metadata.get_file(&file_id).await {
+        Ok(Some(record)) => Ok(HttpResponse::Ok().json(UploadResponse {
+            success: true,
+            message: "File updated successfully".to_string(),
+            file: Some(record.metadata),
+        })),
+        Ok(None) => Ok(error_response(HttpResponse::NotFound(), "File not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve updated file"))
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    println!("Initializing Cloudflare R2 storage...");
-
     // Load configuration
     let config = load_config().expect("Failed to load configuration");
+    let database_url = &config.postgres.postgres_url;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // Schema migrations: `migrate up|down [steps]|status`
+    if args.first().map(String::as_str) == Some("migrate") {
+        return migrations::run_command(database_url, &args[1..]).await.map_err(|e| {
+            eprintln!("Migration failed: {}", e);
+            std::io::Error::other("Migration failed")
+        });
+    }
+
+    if config.postgres.auto_migrate {
+        let applied = async {
+            migrations::Migrator::connect(database_url).await?.up().await
+        }
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to apply database migrations: {}", e);
+            std::io::Error::other("Database migration failed")
+        })?;
+        for migration in applied {
+            println!("Applied migration {} {}", migration.version, migration.name);
+        }
+    }
+
+    println!("Initializing {:?} storage...", config.storage.backend);
+    if config.encryption.enabled {
+        println!("Encrypting new objects with master key {}", config.encryption.active_key);
+    }
 
-    // Initialize CloudflareStorage
-    let storage = CloudflareStorage::new(config.cloudflare.bucket_name.clone())
+    // Initialize the configured storage backend
+    let storage = create_storage(&config)
         .await
         .map_err(|e| {
-            eprintln!("Failed to initialize Cloudflare storage: {}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, "Storage initialization failed")
+            eprintln!("Failed to initialize storage: {}", e);
+            std::io::Error::other("Storage initialization failed")
         })?;
 
+    let file_metadata = MetadataStore::new(database_url)
+        .await
+        .expect("Failed to connect to metadata database");
+
+    // One-time backfill of metadata for files uploaded before it was persisted
+    if args.first().map(String::as_str) == Some("backfill-metadata") {
+        return match file_metadata.backfill(storage.as_ref()).await {
+            Ok(report) => {
+                println!("Backfilled metadata for {} file(s)", report.updated);
+                for file_key in &report.missing {
+                    println!("  - {}: object not found in storage, skipped", file_key);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Metadata backfill failed: {}", e);
+                Err(std::io::Error::other("Metadata backfill failed"))
+            }
+        };
+    }
+
+    // Per-user quota overrides: `set-quota <user-id> <bytes|unlimited|default>`
+    if args.first().map(String::as_str) == Some("set-quota") {
+        let (user_id, quota) = match (args.get(1).and_then(|id| id.parse::<i64>().ok()), args.get(2)) {
+            (Some(user_id), Some(quota)) => (user_id, quota.as_str()),
+            _ => {
+                eprintln!("Usage: set-quota <user-id> <bytes|unlimited|default>");
+                return Err(std::io::Error::other("Invalid arguments"));
+            }
+        };
+        let result = match quota {
+            "default" => file_metadata.clear_user_quota(user_id).await.map(|_| ()),
+            "unlimited" => file_metadata.set_user_quota(user_id, None).await,
+            bytes => match bytes.parse::<u64>() {
+                Ok(bytes) => file_metadata.set_user_quota(user_id, Some(bytes)).await,
+                Err(_) => {
+                    eprintln!("Invalid quota {:?}: expected a number of bytes, unlimited or default", bytes);
+                    return Err(std::io::Error::other("Invalid arguments"));
+                }
+            },
+        };
+        return match result {
+            Ok(()) => {
+                println!("Quota of user {} set to {}", user_id, quota);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Failed to set quota: {}", e);
+                Err(std::io::Error::other("Failed to set quota"))
+            }
+        };
+    }
+
+    // Master key rotation: wrap every data key with the active master key
+    if args.first().map(String::as_str) == Some("rewrap-keys") {
+        return match storage::encryption::rewrap_data_keys(&config.encryption, database_url).await {
+            Ok(report) => {
+                println!("Re-wrapped {} data key(s) with master key {}", report.rewrapped, config.encryption.active_key);
+                for (file_key, e) in &report.failed {
+                    println!("  - {}: {}", file_key, e);
+                }
+                if report.failed.is_empty() {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::other("Some data keys could not be re-wrapped"))
+                }
+            }
+            Err(e) => {
+                eprintln!("Re-wrapping data keys failed: {}", e);
+                Err(std::io::Error::other("Re-wrapping data keys failed"))
+            }
+        };
+    }
+
     // Initialize AuthService
-    let database_url = &config.postgres.postgres_url;
     let jwt_secret = &config.backend.jwt_secret;
     let redis_url = &config.redis.redis_url;
     let token_ttl_seconds = config.redis.token_ttl_seconds;
@@ -252,11 +819,20 @@ async fn main() -> std::io::Result<()> {
     // Create application state
     let app_state = web::Data::new(AppState {
         storage,
-        file_metadata: Arc::new(Mutex::new(HashMap::new())),
+        file_metadata,
+        presign_expiry: Duration::from_secs(config.storage.presign_expiry_seconds),
+        version_retention: config.storage.version_retention.clamp(1, i32::MAX as u32) as i32,
+        resumable_upload_expiry: Duration::from_secs(config.storage.resumable_upload_expiry_seconds),
+        trash_retention: Duration::from_secs(config.storage.trash_retention_seconds),
+        default_quota: Some(config.quota.default_bytes).filter(|&bytes| bytes > 0),
+        upload_policy: policy::UploadPolicy::new(&config.upload_policy),
+        compression: policy::CompressionPolicy::new(&config.compression),
     });
 
     let auth_service_data = web::Data::new(auth_service);
 
+    cleanup::spawn_cleanup_task(app_state.clone());
+
     println!("Starting file upload server on http://localhost:8080");
 
     HttpServer::new(move || {
@@ -264,6 +840,7 @@ async fn main() -> std::io::Result<()> {
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
+            .expose_any_header()
             .max_age(3600);
 
         App::new()
@@ -278,11 +855,181 @@ async fn main() -> std::io::Result<()> {
                     .route("/logout_all", web::post().to(logout_all))
                     .route("/upload", web::post().to(upload_file))
                     .route("/files", web::get().to(list_files))
+                    .route("/files/{id}", web::patch().to(update_file))
+                    .route("/files/{id}", web::delete().to(delete_file))
+                    .route("/files/{id}/move", web::post().to(folders::move_file))
+                    .route("/files/{id}/tags", web::post().to(tags::add_tags))
+                    .route("/files/{id}/tags/{tag}", web::delete().to(tags::remove_tag))
+                    .route("/files/{id}/attributes", web::post().to(tags::set_attributes))
+                    .route("/files/{id}/attributes/{key}", web::delete().to(tags::remove_attribute))
+                    .route("/files/{id}/versions", web::get().to(versions::list_versions))
+                    .route("/files/{id}/versions/prune", web::post().to(versions::prune_versions))
+                    .route("/files/{id}/versions/{version}", web::get().to(versions::download_version))
+                    .route("/files/{id}/versions/{version}/restore", web::post().to(versions::restore_version))
+                    .route("/files/{id}/shares", web::get().to(shares::list_shares))
+                    .route("/files/{id}/shares", web::post().to(shares::create_share))
+                    .route("/files/{id}/shares/{token}", web::delete().to(shares::revoke_share))
+                    .route("/files/{id}/grants", web::get().to(grants::list_grants))
+                    .route("/files/{id}/grants", web::post().to(grants::grant_access))
+                    .route("/files/{id}/grants/{user_id}", web::delete().to(grants::revoke_access))
+                    .route("/shared-with-me", web::get().to(grants::list_shared_with_me))
+                    .route("/workspaces", web::get().to(workspaces::list_workspaces))
+                    .route("/workspaces", web::post().to(workspaces::create_workspace))
+                    .route("/workspaces/{id}", web::delete().to(workspaces::delete_workspace))
+                    .route("/workspaces/{id}/members", web::get().to(workspaces::list_members))
+                    .route("/workspaces/{id}/members/{user_id}", web::patch().to(workspaces::update_member))
+                    .route("/workspaces/{id}/members/{user_id}", web::delete().to(workspaces::remove_member))
+                    .route("/workspaces/{id}/invitations", web::get().to(workspaces::list_invitations))
+                    .route("/workspaces/{id}/invitations", web::post().to(workspaces::invite))
+                    .route("/workspaces/{id}/invitations/{invitation_id}", web::delete().to(workspaces::revoke_invitation))
+                    .route("/invitations", web::get().to(workspaces::my_invitations))
+                    .route("/invitations/{id}/accept", web::post().to(workspaces::accept_invitation))
+                    .route("/invitations/{id}/decline", web::post().to(workspaces::decline_invitation))
+                    .route("/trash", web::get().to(trash::list_trash))
+                    .route("/trash", web::delete().to(trash::empty_trash))
+                    .route("/trash/{id}", web::delete().to(trash::delete_trashed_file))
+                    .route("/trash/{id}/restore", web::post().to(trash::restore_file))
+                    .route("/folders", web::get().to(folders::list_root))
+                    .route("/folders", web::post().to(folders::create_folder))
+                    .route("/folders/{id}", web::get().to(folders::list_folder))
+                    .route("/folders/{id}", web::delete().to(folders::delete_folder))
+                    .route("/folders/{id}/rename", web::post().to(folders::rename_folder))
+                    .route("/folders/{id}/move", web::post().to(folders::move_folder))
+                    .route("/download/archive", web::post().to(archive::download_archive))
                     .route("/download/{id}", web::get().to(download_file))
+                    .route("/shared/{token}", web::get().to(shares::download_shared))
+                    .route("/shared/{token}", web::post().to(shares::download_shared))
+                    .route("/tus", web::post().to(tus::create_upload))
+                    .route("/tus", web::method(actix_web::http::Method::OPTIONS).to(tus::options))
+                    .route("/tus/{id}", web::head().to(tus::upload_offset))
+                    .route("/tus/{id}", web::patch().to(tus::append))
+                    .route("/tus/{id}", web::delete().to(tus::terminate))
+                    .route("/presigned/upload", web::post().to(presign::presign_upload))
+                    .route("/presigned/upload/{id}/complete", web::post().to(presign::complete_upload))
+                    .route("/presigned/download/{id}", web::get().to(presign::presign_download))
+                    .route("/usage", web::get().to(quota::get_usage))
                     .route("/me", web::get().to(me))
             )
     })
     .bind("127.0.0.1:8080")?
     .run()
     .await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine as _;
+
+    fn parse(query: &[(&str, &str)]) -> std::result::Result<(FileFilter, FilePage), String> {
+        parse_listing_query(query.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    fn cursor(json: &str) -> String {
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    #[test]
+    fn listing_defaults() {
+        let (filter, page) = parse(&[]).unwrap();
+        assert!(filter.tags.is_empty() && filter.attributes.is_empty() && filter.name_contains.is_none());
+        assert_eq!((page.sort, page.descending, page.limit), (FileSort::Date, false, DEFAULT_PAGE_SIZE));
+        assert!(page.after.is_none());
+    }
+
+    #[test]
+    fn listing_filters() {
+        let (filter, _) = parse(&[
+            ("tag", "work"),
+            ("tag", "2024"),
+            ("meta.project", "apollo"),
+            ("meta.", "empty key"),
+            ("meta.a.b", "dotted"),
+            ("q", "report"),
+            ("content_type", "image/"),
+            ("min_size", "10"),
+            ("max_size", "0"),
+            ("uploaded_after", "2024-01-01T00:00:00+02:00"),
+            ("unknown", "ignored"),
+        ])
+        .unwrap();
+        assert_eq!(filter.tags, ["work", "2024"]);
+        assert_eq!(
+            filter.attributes,
+            [
+                ("project".to_string(), "apollo".to_string()),
+                (String::new(), "empty key".to_string()),
+                ("a.b".to_string(), "dotted".to_string()),
+            ]
+        );
+        assert_eq!(filter.name_contains.as_deref(), Some("report"));
+        assert_eq!(filter.content_type_prefix.as_deref(), Some("image/"));
+        assert_eq!((filter.min_size, filter.max_size), (Some(10), Some(0)));
+        assert_eq!(filter.uploaded_after.unwrap().to_rfc3339(), "2023-12-31T22:00:00+00:00");
+        assert!(filter.uploaded_before.is_none());
+    }
+
+    #[test]
+    fn invalid_filters() {
+        assert!(parse(&[("min_size", "-1")]).is_err());
+        assert!(parse(&[("max_size", "1k")]).is_err());
+        assert!(parse(&[("uploaded_before", "2024-01-01")]).is_err());
+        assert!(parse(&[("uploaded_after", "yesterday")]).is_err());
+    }
+
+    #[test]
+    fn listing_order() {
+        let (_, page) = parse(&[("sort", "size"), ("order", "desc")]).unwrap();
+        assert_eq!((page.sort, page.descending), (FileSort::Size, true));
+        let (_, page) = parse(&[("sort", "name"), ("order", "asc")]).unwrap();
+        assert_eq!((page.sort, page.descending), (FileSort::Name, false));
+
+        assert_eq!(parse(&[("sort", "created")]).unwrap_err(), "Invalid sort: expected name, size or date");
+        assert!(parse(&[("sort", "Name")]).is_err());
+        assert!(parse(&[("order", "descending")]).is_err());
+    }
+
+    #[test]
+    fn limit_bounds() {
+        assert_eq!(parse(&[("limit", "1")]).unwrap().1.limit, 1);
+        assert_eq!(parse(&[("limit", &MAX_PAGE_SIZE.to_string())]).unwrap().1.limit, MAX_PAGE_SIZE);
+        for limit in ["0", "-5", "1001", "ten", ""] {
+            assert!(parse(&[("limit", limit)]).is_err(), "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn cursors() {
+        let name_cursor = cursor(r#"{"sort":"name","descending":false,"key":{"Name":"b.txt"},"row_id":42}"#);
+        let (_, page) = parse(&[("sort", "name"), ("cursor", &name_cursor)]).unwrap();
+        let after = page.after.unwrap();
+        assert_eq!((after.sort, after.descending), (FileSort::Name, false));
+        // The cursor may come before the sort it belongs to
+        assert!(parse(&[("cursor", &name_cursor), ("sort", "name")]).is_ok());
+    }
+
+    #[test]
+    fn cursors_of_other_listings() {
+        let name_cursor = cursor(r#"{"sort":"name","descending":false,"key":{"Name":"b.txt"},"row_id":42}"#);
+        let message = "The cursor belongs to a listing with a different sort order";
+        assert_eq!(parse(&[("cursor", &name_cursor)]).unwrap_err(), message);
+        assert_eq!(parse(&[("sort", "name"), ("order", "desc"), ("cursor", &name_cursor)]).unwrap_err(), message);
+        assert_eq!(parse(&[("sort", "size"), ("cursor", &name_cursor)]).unwrap_err(), message);
+    }
+
+    #[test]
+    fn malformed_cursors() {
+        for malformed in [
+            "",
+            "not base64!",
+            &cursor("not json"),
+            &cursor(r#"{"sort":"name","descending":false}"#),
+            &cursor(r#"{"sort":"owner","descending":false,"key":{"Name":"b.txt"},"row_id":42}"#),
+            // A key that does not fit the sort
+            &cursor(r#"{"sort":"name","descending":false,"key":{"Size":10},"row_id":42}"#),
+            &cursor(r#"{"sort":"date","descending":false,"key":{"Date":"not a date"},"row_id":42}"#),
+        ] {
+            assert_eq!(parse(&[("sort", "name"), ("cursor", malformed)]).unwrap_err(), "Invalid cursor", "{}", malformed);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use tokio_postgres::{Client, NoTls};
+
+/// Arbitrary key for the advisory lock that keeps concurrent migrators apart
+const MIGRATION_LOCK_KEY: i64 = 0x0061_7765_736f_6d65;
+
+/// A versioned schema change embedded in the binary
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("sql/", $name, ".up.sql")),
+            down: include_str!(concat!("sql/", $name, ".down.sql")),
+        }
+    };
+}
+
+/// All migrations, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_create_users_and_user_files"),
+    migration!(2, "0002_add_file_metadata"),
+    migration!(3, "0003_create_pending_object_deletions"),
+    migration!(4, "0004_create_pending_uploads"),
+    migration!(5, "0005_create_storage_objects"),
+    migration!(6, "0006_create_file_versions"),
+    migration!(7, "0007_create_folders"),
+    migration!(8, "0008_add_file_description"),
+    migration!(9, "0009_create_file_tags_and_attributes"),
+    migration!(10, "0010_create_user_quotas"),
+    migration!(11, "0011_create_tus_uploads"),
+    migration!(12, "0012_create_object_data_keys"),
+    migration!(13, "0013_add_content_encoding"),
+    migration!(14, "0014_create_share_links"),
+    migration!(15, "0015_create_file_grants"),
+    migration!(16, "0016_create_workspaces"),
+    migration!(17, "0017_add_file_trash"),
+    migration!(18, "0018_add_file_expiry"),
+];
+
+/// Whether a known migration has been applied, and when
+pub struct MigrationStatus {
+    pub migration: &'static Migration,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// Applies and reverts the embedded migrations, tracking them in `schema_migrations`
+pub struct Migrator {
+    client: Client,
+}
+
+impl Migrator {
+    pub async fn connect(database_url: &str) -> Result<Self, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("Migration connection error: {}", e);
+            }
+        });
+
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version BIGINT PRIMARY KEY,
+                    name VARCHAR(255) NOT NULL,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                )",
+            )
+            .await?;
+
+        Ok(Self { client })
+    }
+
+    async fn applied_versions(&self) -> Result<BTreeMap<i64, DateTime<Utc>>, tokio_postgres::Error> {
+        let rows = self.client
+            .query("SELECT version, applied_at FROM schema_migrations", &[])
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Report every known migration and whether it has been applied
+    pub async fn status(&self) -> Result<Vec<MigrationStatus>, tokio_postgres::Error> {
+        let applied = self.applied_versions().await?;
+        Ok(MIGRATIONS
+            .iter()
+            .map(|migration| MigrationStatus {
+                migration,
+                applied_at: applied.get(&migration.version).copied(),
+            })
+            .collect())
+    }
+
+    /// Apply all pending migrations, each in its own transaction
+    pub async fn up(&mut self) -> Result<Vec<&'static Migration>, Box<dyn std::error::Error>> {
+        self.client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY]).await?;
+        let result = self.apply_pending().await;
+        self.client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY]).await?;
+        result
+    }
+
+    async fn apply_pending(&mut self) -> Result<Vec<&'static Migration>, Box<dyn std::error::Error>> {
+        let applied = self.applied_versions().await?;
+        let mut newly_applied = Vec::new();
+
+        for migration in MIGRATIONS.iter().filter(|m| !applied.contains_key(&m.version)) {
+            let transaction = self.client.transaction().await?;
+            transaction.batch_execute(migration.up).await.map_err(|e| {
+                format!("Migration {} ({}) failed: {}", migration.version, migration.name, e)
+            })?;
+            transaction
+                .execute(
+                    "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                    &[&migration.version, &migration.name],
+                )
+                .await?;
+            transaction.commit().await?;
+            newly_applied.push(migration);
+        }
+
+        Ok(newly_applied)
+    }
+
+    /// Revert the `steps` most recently applied migrations
+    pub async fn down(&mut self, steps: usize) -> Result<Vec<&'static Migration>, Box<dyn std::error::Error>> {
+        self.client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY]).await?;
+        let result = self.revert_latest(steps).await;
+        self.client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY]).await?;
+        result
+    }
+
+    async fn revert_latest(&mut self, steps: usize) -> Result<Vec<&'static Migration>, Box<dyn std::error::Error>> {
+        let applied = self.applied_versions().await?;
+        let mut reverted = Vec::new();
+
+        for version in applied.keys().rev().take(steps) {
+            let migration = MIGRATIONS
+                .iter()
+                .find(|m| m.version == *version)
+                .ok_or_else(|| format!("Applied migration {} is unknown to this binary", version))?;
+
+            let transaction = self.client.transaction().await?;
+            transaction.batch_execute(migration.down).await.map_err(|e| {
+                format!("Reverting migration {} ({}) failed: {}", migration.version, migration.name, e)
+            })?;
+            transaction
+                .execute("DELETE FROM schema_migrations WHERE version = $1", &[&migration.version])
+                .await?;
+            transaction.commit().await?;
+            reverted.push(migration);
+        }
+
+        Ok(reverted)
+    }
+}
+
+/// Run the `migrate up|down [steps]|status` command
+pub async fn run_command(database_url: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut migrator = Migrator::connect(database_url).await?;
+
+    match args.first().map(String::as_str) {
+        None | Some("up") => {
+            let applied = migrator.up().await?;
+            if applied.is_empty() {
+                println!("Database schema is up to date");
+            }
+            for migration in applied {
+                println!("Applied {} {}", migration.version, migration.name);
+            }
+        }
+        Some("down") => {
+            let steps = match args.get(1) {
+                Some(steps) => steps.parse().map_err(|_| format!("Invalid number of steps: {}", steps))?,
+                None => 1,
+            };
+            for migration in migrator.down(steps).await? {
+                println!("Reverted {} {}", migration.version, migration.name);
+            }
+        }
+        Some("status") => {
+            for status in migrator.status().await? {
+                match status.applied_at {
+                    Some(applied_at) => println!(
+                        "{:>4} {:<40} applied {}",
+                        status.migration.version, status.migration.name, applied_at
+                    ),
+                    None => println!(
+                        "{:>4} {:<40} pending",
+                        status.migration.version, status.migration.name
+                    ),
+                }
+            }
+        }
+        Some(other) => {
+            return Err(format!("Unknown migrate command '{}', expected up, down [steps] or status", other).into());
+        }
+    }
+
+    Ok(())
+}
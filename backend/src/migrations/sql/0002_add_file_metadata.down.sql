@@ -0,0 +1,8 @@
+DROP INDEX IF EXISTS user_files_user_id_idx;
+
+ALTER TABLE user_files
+    DROP COLUMN IF EXISTS upload_time,
+    DROP COLUMN IF EXISTS content_type,
+    DROP COLUMN IF EXISTS size,
+    DROP COLUMN IF EXISTS filename,
+    DROP COLUMN IF EXISTS file_id;
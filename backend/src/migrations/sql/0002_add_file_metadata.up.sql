@@ -0,0 +1,8 @@
+ALTER TABLE user_files
+    ADD COLUMN IF NOT EXISTS file_id VARCHAR(64) UNIQUE,
+    ADD COLUMN IF NOT EXISTS filename VARCHAR(1024),
+    ADD COLUMN IF NOT EXISTS size BIGINT,
+    ADD COLUMN IF NOT EXISTS content_type VARCHAR(255),
+    ADD COLUMN IF NOT EXISTS upload_time TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS user_files_user_id_idx ON user_files (user_id);
@@ -0,0 +1,5 @@
+-- Objects whose file record is gone but which may still exist in storage
+CREATE TABLE IF NOT EXISTS pending_object_deletions (
+    file_key VARCHAR(255) PRIMARY KEY,
+    queued_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,11 @@
+-- Uploads handed out as presigned URLs that have not been completed yet
+CREATE TABLE IF NOT EXISTS pending_uploads (
+    file_id VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_key VARCHAR(255) NOT NULL,
+    filename VARCHAR(1024) NOT NULL,
+    content_type VARCHAR(255),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS pending_uploads_expires_at_idx ON pending_uploads (expires_at);
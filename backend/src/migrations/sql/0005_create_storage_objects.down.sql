@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS user_files_content_hash_idx;
+ALTER TABLE user_files DROP COLUMN IF EXISTS content_hash;
+DROP TABLE IF EXISTS storage_objects;
@@ -0,0 +1,12 @@
+-- Stored objects keyed by the SHA-256 of their content, shared by every file with identical bytes
+CREATE TABLE IF NOT EXISTS storage_objects (
+    content_hash VARCHAR(64) PRIMARY KEY,
+    file_key VARCHAR(255) NOT NULL,
+    size BIGINT NOT NULL,
+    ref_count BIGINT NOT NULL CHECK (ref_count >= 0)
+);
+
+ALTER TABLE user_files
+    ADD COLUMN IF NOT EXISTS content_hash VARCHAR(64) REFERENCES storage_objects(content_hash);
+
+CREATE INDEX IF NOT EXISTS user_files_content_hash_idx ON user_files (content_hash);
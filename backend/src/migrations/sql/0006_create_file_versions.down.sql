@@ -0,0 +1,38 @@
+DROP INDEX IF EXISTS user_files_user_id_filename_idx;
+
+ALTER TABLE user_files
+    ADD COLUMN size BIGINT,
+    ADD COLUMN content_type VARCHAR(255),
+    ADD COLUMN upload_time TIMESTAMPTZ,
+    ADD COLUMN content_hash VARCHAR(64) REFERENCES storage_objects(content_hash);
+
+CREATE INDEX IF NOT EXISTS user_files_content_hash_idx ON user_files (content_hash);
+
+-- Only the current version of each file survives; release the others
+INSERT INTO pending_object_deletions (file_key)
+SELECT v.file_key
+FROM file_versions v JOIN user_files f ON f.file_id = v.file_id
+WHERE v.version <> f.current_version AND v.content_hash IS NULL
+ON CONFLICT (file_key) DO NOTHING;
+
+UPDATE storage_objects s SET ref_count = s.ref_count - released.refs
+FROM (
+    SELECT v.content_hash, COUNT(*) AS refs
+    FROM file_versions v JOIN user_files f ON f.file_id = v.file_id
+    WHERE v.version <> f.current_version AND v.content_hash IS NOT NULL
+    GROUP BY v.content_hash
+) released
+WHERE s.content_hash = released.content_hash;
+
+UPDATE user_files f
+SET file_key = v.file_key, size = v.size, content_type = v.content_type,
+    upload_time = v.upload_time, content_hash = v.content_hash
+FROM file_versions v
+WHERE v.file_id = f.file_id AND v.version = f.current_version;
+
+ALTER TABLE user_files
+    ALTER COLUMN file_key SET NOT NULL,
+    DROP COLUMN current_version,
+    DROP COLUMN latest_version;
+
+DROP TABLE IF EXISTS file_versions;
@@ -0,0 +1,37 @@
+-- Every upload of a file is kept as a version; user_files now only describes the logical file
+CREATE TABLE IF NOT EXISTS file_versions (
+    id BIGSERIAL PRIMARY KEY,
+    file_id VARCHAR(64) NOT NULL REFERENCES user_files(file_id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    file_key VARCHAR(255) NOT NULL,
+    size BIGINT NOT NULL,
+    content_type VARCHAR(255),
+    upload_time TIMESTAMPTZ NOT NULL,
+    content_hash VARCHAR(64) REFERENCES storage_objects(content_hash),
+    UNIQUE (file_id, version)
+);
+
+CREATE INDEX IF NOT EXISTS file_versions_content_hash_idx ON file_versions (content_hash);
+
+-- Existing files become version 1, taking over their reference on the shared object
+INSERT INTO file_versions (file_id, version, file_key, size, content_type, upload_time, content_hash)
+SELECT file_id, 1, file_key, size, content_type, upload_time, content_hash
+FROM user_files WHERE file_id IS NOT NULL;
+
+-- file_key is kept for rows created before metadata was persisted, until they are backfilled
+ALTER TABLE user_files
+    ADD COLUMN current_version INTEGER,
+    ADD COLUMN latest_version INTEGER,
+    ALTER COLUMN file_key DROP NOT NULL;
+
+UPDATE user_files SET current_version = 1, latest_version = 1, file_key = NULL WHERE file_id IS NOT NULL;
+
+DROP INDEX IF EXISTS user_files_content_hash_idx;
+
+ALTER TABLE user_files
+    DROP COLUMN content_hash,
+    DROP COLUMN size,
+    DROP COLUMN content_type,
+    DROP COLUMN upload_time;
+
+CREATE INDEX IF NOT EXISTS user_files_user_id_filename_idx ON user_files (user_id, filename);
@@ -0,0 +1,4 @@
+ALTER TABLE pending_uploads DROP COLUMN IF EXISTS folder_id;
+DROP INDEX IF EXISTS user_files_folder_id_idx;
+ALTER TABLE user_files DROP COLUMN IF EXISTS folder_id;
+DROP TABLE IF EXISTS folders;
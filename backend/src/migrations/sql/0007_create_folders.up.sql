@@ -0,0 +1,18 @@
+-- Virtual folders; objects in storage keep their keys wherever a file is filed
+CREATE TABLE IF NOT EXISTS folders (
+    id BIGSERIAL PRIMARY KEY,
+    folder_id VARCHAR(64) UNIQUE NOT NULL,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    parent_id VARCHAR(64) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Folder names are unique among their siblings; top-level folders have no parent
+CREATE UNIQUE INDEX IF NOT EXISTS folders_parent_name_idx ON folders (user_id, COALESCE(parent_id, ''), name);
+
+ALTER TABLE user_files ADD COLUMN folder_id VARCHAR(64) REFERENCES folders(folder_id);
+CREATE INDEX IF NOT EXISTS user_files_folder_id_idx ON user_files (folder_id);
+
+-- A pending upload whose folder is deleted lands at the top level instead
+ALTER TABLE pending_uploads ADD COLUMN folder_id VARCHAR(64) REFERENCES folders(folder_id) ON DELETE SET NULL;
@@ -0,0 +1 @@
+ALTER TABLE user_files DROP COLUMN IF EXISTS description;
@@ -0,0 +1,2 @@
+-- Free-text description set by the owner of a file
+ALTER TABLE user_files ADD COLUMN description TEXT;
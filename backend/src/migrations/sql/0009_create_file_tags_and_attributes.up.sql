@@ -0,0 +1,18 @@
+-- Free-form labels attached to files
+CREATE TABLE IF NOT EXISTS file_tags (
+    file_id VARCHAR(64) NOT NULL REFERENCES user_files(file_id) ON DELETE CASCADE,
+    tag VARCHAR(255) NOT NULL,
+    PRIMARY KEY (file_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS file_tags_tag_idx ON file_tags (tag);
+
+-- Custom key/value attributes attached to files
+CREATE TABLE IF NOT EXISTS file_attributes (
+    file_id VARCHAR(64) NOT NULL REFERENCES user_files(file_id) ON DELETE CASCADE,
+    key VARCHAR(255) NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (file_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS file_attributes_key_value_idx ON file_attributes (key, value);
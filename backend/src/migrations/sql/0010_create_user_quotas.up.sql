@@ -0,0 +1,6 @@
+-- Per-user storage quota overrides; users without a row get the configured default.
+-- A NULL quota means the user has no limit.
+CREATE TABLE IF NOT EXISTS user_quotas (
+    user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    quota_bytes BIGINT CHECK (quota_bytes >= 0)
+);
@@ -0,0 +1,28 @@
+-- Resumable uploads in progress through the tus protocol
+CREATE TABLE IF NOT EXISTS tus_uploads (
+    upload_id VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_key VARCHAR(255) NOT NULL,
+    filename VARCHAR(1024) NOT NULL,
+    content_type VARCHAR(255),
+    folder_id VARCHAR(64) REFERENCES folders(folder_id) ON DELETE SET NULL,
+    upload_length BIGINT NOT NULL,
+    -- Bytes received so far: those in uploaded parts plus `tail_size` bytes buffered in a tail object
+    upload_offset BIGINT NOT NULL DEFAULT 0,
+    tail_size BIGINT NOT NULL DEFAULT 0,
+    -- Backend multipart upload, started once the first full part has been received
+    multipart_upload_id VARCHAR(1024),
+    -- Set while a PATCH request is appending to the upload
+    locked_until TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS tus_uploads_expires_at_idx ON tus_uploads (expires_at);
+
+-- Parts of the backend multipart upload behind a tus upload
+CREATE TABLE IF NOT EXISTS tus_upload_parts (
+    upload_id VARCHAR(64) NOT NULL REFERENCES tus_uploads(upload_id) ON DELETE CASCADE,
+    part_number INTEGER NOT NULL,
+    etag VARCHAR(255) NOT NULL,
+    PRIMARY KEY (upload_id, part_number)
+);
@@ -0,0 +1,10 @@
+-- Data keys of objects encrypted at rest, each wrapped by the master key `master_key_id`.
+-- Objects without a row are stored in plaintext.
+CREATE TABLE IF NOT EXISTS object_data_keys (
+    file_key VARCHAR(255) PRIMARY KEY,
+    master_key_id VARCHAR(255) NOT NULL,
+    wrapped_key BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS object_data_keys_master_key_id_idx ON object_data_keys (master_key_id);
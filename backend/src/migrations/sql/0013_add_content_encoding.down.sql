@@ -0,0 +1,2 @@
+ALTER TABLE file_versions DROP COLUMN IF EXISTS content_encoding;
+ALTER TABLE storage_objects DROP COLUMN IF EXISTS content_encoding;
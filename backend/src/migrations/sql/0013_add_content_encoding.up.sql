@@ -0,0 +1,4 @@
+-- Encoding objects are stored with (`zstd` or `gzip`); NULL for content stored as uploaded.
+-- Deduplicated versions share their object, so both record it.
+ALTER TABLE storage_objects ADD COLUMN IF NOT EXISTS content_encoding VARCHAR(16);
+ALTER TABLE file_versions ADD COLUMN IF NOT EXISTS content_encoding VARCHAR(16);
@@ -0,0 +1,15 @@
+-- Links giving anyone holding the token access to a file without signing in
+CREATE TABLE IF NOT EXISTS share_links (
+    token VARCHAR(64) PRIMARY KEY,
+    file_id VARCHAR(64) NOT NULL REFERENCES user_files(file_id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL,
+    -- PBKDF2 hash of the password, NULL if the link needs none
+    password_hash VARCHAR(255),
+    expires_at TIMESTAMPTZ,
+    max_downloads INTEGER,
+    download_count INTEGER NOT NULL DEFAULT 0,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS share_links_file_id_idx ON share_links (file_id);
@@ -0,0 +1,11 @@
+-- Access to a file granted by its owner to another user
+CREATE TABLE IF NOT EXISTS file_grants (
+    file_id VARCHAR(64) NOT NULL REFERENCES user_files(file_id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    permission VARCHAR(16) NOT NULL CHECK (permission IN ('read', 'write', 'manage')),
+    granted_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (file_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS file_grants_user_id_idx ON file_grants (user_id);
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS user_files_workspace_id_idx;
+ALTER TABLE user_files DROP COLUMN IF EXISTS workspace_id;
+DROP TABLE IF EXISTS workspace_invitations;
+DROP TABLE IF EXISTS workspace_members;
+DROP TABLE IF EXISTS workspaces;
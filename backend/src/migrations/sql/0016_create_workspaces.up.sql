@@ -0,0 +1,35 @@
+-- Team workspaces; files filed in a workspace belong to it rather than to the member who uploaded them
+CREATE TABLE IF NOT EXISTS workspaces (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(64) UNIQUE NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    created_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS workspace_members (
+    workspace_id VARCHAR(64) NOT NULL REFERENCES workspaces(workspace_id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role VARCHAR(16) NOT NULL CHECK (role IN ('owner', 'admin', 'member', 'viewer')),
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (workspace_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_idx ON workspace_members (user_id);
+
+-- Invitations are addressed to an email, so people can be invited before they sign up
+CREATE TABLE IF NOT EXISTS workspace_invitations (
+    invitation_id VARCHAR(64) PRIMARY KEY,
+    workspace_id VARCHAR(64) NOT NULL REFERENCES workspaces(workspace_id) ON DELETE CASCADE,
+    email VARCHAR(255) NOT NULL,
+    role VARCHAR(16) NOT NULL CHECK (role IN ('admin', 'member', 'viewer')),
+    invited_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS workspace_invitations_email_idx ON workspace_invitations (workspace_id, LOWER(email));
+CREATE INDEX IF NOT EXISTS workspace_invitations_lower_email_idx ON workspace_invitations (LOWER(email));
+
+-- Files without a workspace belong to `user_id`; in a workspace, `user_id` is the member who uploaded them
+ALTER TABLE user_files ADD COLUMN IF NOT EXISTS workspace_id VARCHAR(64) REFERENCES workspaces(workspace_id);
+CREATE INDEX IF NOT EXISTS user_files_workspace_id_idx ON user_files (workspace_id);
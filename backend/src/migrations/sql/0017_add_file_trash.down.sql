@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS user_files_deleted_at_idx;
+ALTER TABLE user_files DROP COLUMN IF EXISTS deleted_by;
+ALTER TABLE user_files DROP COLUMN IF EXISTS deleted_at;
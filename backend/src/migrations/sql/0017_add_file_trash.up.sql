@@ -0,0 +1,4 @@
+-- Deleted files move to the trash: they keep their row and versions until restored or purged
+ALTER TABLE user_files ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE user_files ADD COLUMN IF NOT EXISTS deleted_by BIGINT;
+CREATE INDEX IF NOT EXISTS user_files_deleted_at_idx ON user_files (deleted_at) WHERE deleted_at IS NOT NULL;
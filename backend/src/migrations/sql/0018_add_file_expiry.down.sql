@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS user_files_expires_at_idx;
+ALTER TABLE user_files DROP COLUMN IF EXISTS expires_at;
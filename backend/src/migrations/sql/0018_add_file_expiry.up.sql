@@ -0,0 +1,3 @@
+-- Files may be uploaded with an expiry time, after which the cleanup task deletes them
+ALTER TABLE user_files ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS user_files_expires_at_idx ON user_files (expires_at) WHERE expires_at IS NOT NULL;
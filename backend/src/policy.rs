@@ -0,0 +1,548 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{mime, HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use futures_util::{Stream, StreamExt};
+use std::path::Path;
+
+use crate::conf::{CompressionAlgorithm, CompressionConfig, TypeMismatchAction, UploadPolicyConfig};
+use crate::{error_response, AppState};
+use crate::storage::{ByteRange, Compression, ContentEncoding, ObjectStorage, StorageError};
+
+/// Number of leading bytes of an upload inspected to detect its type
+pub const SNIFF_LENGTH: usize = 512;
+
+/// Type assumed for uploads that declare none
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Types that are never compressed, whatever `[compression]` lists. Players and viewers read
+/// them with range requests, and a range of a compressed file is served by decompressing it
+/// from the start.
+const SEEKABLE_TYPES: &[&str] = &["audio/*", "video/*", "application/pdf"];
+
+/// A file format recognised by its leading bytes
+struct Signature {
+    mime: &'static str,
+    /// Byte sequences that must all appear at the given offsets
+    magic: &'static [(usize, &'static [u8])],
+    /// Other declared types consistent with the format; entries ending in `.` match as prefixes
+    aliases: &'static [&'static str],
+}
+
+impl Signature {
+    fn matches(&self, prefix: &[u8]) -> bool {
+        self.magic.iter().all(|(offset, magic)| {
+            prefix.get(*offset..offset + magic.len()) == Some(*magic)
+        })
+    }
+
+    /// Whether a client declaring `content_type` (an essence, lowercase) describes this format
+    fn accepts(&self, content_type: &str) -> bool {
+        content_type == self.mime
+            || self.aliases.iter().any(|alias| match alias.strip_suffix('.') {
+                Some(prefix) => content_type.starts_with(prefix),
+                None => content_type == *alias,
+            })
+    }
+}
+
+/// Formats detected by `sniff`; text formats have no reliable signature and are never detected
+const SIGNATURES: &[Signature] = &[
+    Signature { mime: "image/png", magic: &[(0, b"\x89PNG\r\n\x1a\n")], aliases: &[] },
+    Signature { mime: "image/jpeg", magic: &[(0, b"\xff\xd8\xff")], aliases: &["image/pjpeg"] },
+    Signature { mime: "image/gif", magic: &[(0, b"GIF8")], aliases: &[] },
+    Signature { mime: "image/webp", magic: &[(0, b"RIFF"), (8, b"WEBP")], aliases: &[] },
+    Signature { mime: "image/tiff", magic: &[(0, b"II*\0")], aliases: &[] },
+    Signature { mime: "image/tiff", magic: &[(0, b"MM\0*")], aliases: &[] },
+    Signature { mime: "image/vnd.microsoft.icon", magic: &[(0, b"\0\0\x01\0")], aliases: &["image/x-icon"] },
+    Signature { mime: "audio/wav", magic: &[(0, b"RIFF"), (8, b"WAVE")], aliases: &["audio/x-wav", "audio/wave", "audio/vnd.wave"] },
+    Signature { mime: "video/x-msvideo", magic: &[(0, b"RIFF"), (8, b"AVI ")], aliases: &["video/avi"] },
+    Signature { mime: "audio/mpeg", magic: &[(0, b"ID3")], aliases: &["audio/mp3"] },
+    Signature { mime: "audio/ogg", magic: &[(0, b"OggS")], aliases: &["audio/opus", "video/ogg", "application/ogg"] },
+    Signature { mime: "audio/flac", magic: &[(0, b"fLaC")], aliases: &["audio/x-flac"] },
+    Signature {
+        mime: "video/mp4",
+        magic: &[(4, b"ftyp")],
+        aliases: &[
+            "audio/mp4", "audio/x-m4a", "video/x-m4v", "video/quicktime", "video/3gpp", "video/3gpp2",
+            "image/heic", "image/heif", "image/avif",
+        ],
+    },
+    Signature { mime: "video/webm", magic: &[(0, b"\x1a\x45\xdf\xa3")], aliases: &["video/x-matroska", "audio/webm"] },
+    Signature { mime: "application/pdf", magic: &[(0, b"%PDF-")], aliases: &[] },
+    Signature {
+        mime: "application/zip",
+        magic: &[(0, b"PK\x03\x04")],
+        aliases: &[
+            "application/x-zip-compressed", "application/epub+zip", "application/java-archive",
+            "application/vnd.android.package-archive", "application/vnd.openxmlformats-officedocument.",
+            "application/vnd.oasis.opendocument.",
+        ],
+    },
+    Signature { mime: "application/zip", magic: &[(0, b"PK\x05\x06")], aliases: &["application/x-zip-compressed"] },
+    Signature {
+        mime: "application/x-ole-storage",
+        magic: &[(0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1")],
+        aliases: &["application/msword", "application/vnd.ms-excel", "application/vnd.ms-powerpoint", "application/x-msi"],
+    },
+    Signature { mime: "application/gzip", magic: &[(0, b"\x1f\x8b")], aliases: &["application/x-gzip"] },
+    Signature { mime: "application/x-bzip2", magic: &[(0, b"BZh")], aliases: &[] },
+    Signature { mime: "application/x-xz", magic: &[(0, b"\xfd7zXZ\0")], aliases: &[] },
+    Signature { mime: "application/zstd", magic: &[(0, b"\x28\xb5\x2f\xfd")], aliases: &[] },
+    Signature { mime: "application/x-7z-compressed", magic: &[(0, b"7z\xbc\xaf\x27\x1c")], aliases: &[] },
+    Signature { mime: "application/vnd.rar", magic: &[(0, b"Rar!\x1a\x07")], aliases: &["application/x-rar-compressed"] },
+    Signature { mime: "application/x-tar", magic: &[(257, b"ustar")], aliases: &[] },
+    Signature { mime: "application/vnd.sqlite3", magic: &[(0, b"SQLite format 3\0")], aliases: &["application/x-sqlite3"] },
+    Signature { mime: "application/wasm", magic: &[(0, b"\0asm")], aliases: &[] },
+    Signature {
+        mime: "application/x-elf",
+        magic: &[(0, b"\x7fELF")],
+        aliases: &["application/x-executable", "application/x-sharedlib"],
+    },
+    Signature {
+        mime: "application/vnd.microsoft.portable-executable",
+        magic: &[(0, b"MZ")],
+        aliases: &["application/x-msdownload", "application/x-dosexec"],
+    },
+];
+
+/// Detect the format of a file from its first bytes
+fn sniff(prefix: &[u8]) -> Option<&'static Signature> {
+    SIGNATURES.iter().find(|signature| signature.matches(prefix))
+}
+
+/// Whether `content_type` names a format `sniff` could have recognised
+fn is_detectable(content_type: &str) -> bool {
+    SIGNATURES.iter().any(|signature| signature.accepts(content_type))
+}
+
+/// Whether a configured pattern such as `image/*` or `application/pdf` covers `content_type`
+fn type_matches(pattern: &str, content_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(top_level) => content_type.split('/').next() == Some(top_level),
+        None => pattern == content_type,
+    }
+}
+
+/// Lowercase extension of `filename` without the dot, empty if it has none
+fn extension(filename: &str) -> String {
+    Path::new(filename)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Why an upload was refused by the upload policy
+#[derive(Debug)]
+pub enum PolicyViolation {
+    /// The file is larger than the maximum file size, given in bytes
+    TooLarge(u64),
+    DisallowedExtension(String),
+    DisallowedType(String),
+    InvalidType,
+    /// The content is not of the declared type; `detected` is `None` for content of no known format
+    TypeMismatch { declared: String, detected: Option<&'static str> },
+}
+
+impl PolicyViolation {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PolicyViolation::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            PolicyViolation::TooLarge(limit) => format!("File exceeds the maximum upload size of {} bytes", limit),
+            PolicyViolation::DisallowedExtension(extension) if extension.is_empty() => {
+                "Files without an extension are not accepted".to_string()
+            }
+            PolicyViolation::DisallowedExtension(extension) => {
+                format!("Files with the extension .{} are not accepted", extension)
+            }
+            PolicyViolation::DisallowedType(content_type) => format!("Files of type {} are not accepted", content_type),
+            PolicyViolation::InvalidType => "Invalid content type".to_string(),
+            PolicyViolation::TypeMismatch { declared, detected: Some(detected) } => {
+                format!("The file was declared as {} but its content is {}", declared, detected)
+            }
+            PolicyViolation::TypeMismatch { declared, detected: None } => {
+                format!("The file was declared as {} but its content does not match", declared)
+            }
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        error_response(HttpResponse::build(self.status()), &self.message())
+    }
+}
+
+/// Limits on the size, name and type of uploaded files, from the `[upload_policy]` section
+pub struct UploadPolicy {
+    max_file_size: Option<u64>,
+    allowed_types: Vec<String>,
+    denied_types: Vec<String>,
+    allowed_extensions: Vec<String>,
+    denied_extensions: Vec<String>,
+    sniff_content: bool,
+    on_type_mismatch: TypeMismatchAction,
+}
+
+impl UploadPolicy {
+    pub fn new(config: &UploadPolicyConfig) -> Self {
+        let lowercase = |values: &[String]| -> Vec<String> {
+            values.iter().map(|value| value.trim().trim_start_matches('.').to_lowercase()).collect()
+        };
+        Self {
+            max_file_size: Some(config.max_file_size).filter(|&size| size > 0),
+            allowed_types: lowercase(&config.allowed_types),
+            denied_types: lowercase(&config.denied_types),
+            allowed_extensions: lowercase(&config.allowed_extensions),
+            denied_extensions: lowercase(&config.denied_extensions),
+            sniff_content: config.sniff_content,
+            on_type_mismatch: config.on_type_mismatch,
+        }
+    }
+
+    /// Largest accepted file in bytes, `None` if unlimited
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    /// Whether uploads have their type detected from their content
+    pub fn sniffs_content(&self) -> bool {
+        self.sniff_content
+    }
+
+    pub fn check_size(&self, size: u64) -> Result<(), PolicyViolation> {
+        match self.max_file_size {
+            Some(limit) if size > limit => Err(PolicyViolation::TooLarge(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_filename(&self, filename: &str) -> Result<(), PolicyViolation> {
+        let extension = extension(filename);
+        let allowed = self.allowed_extensions.is_empty() || self.allowed_extensions.contains(&extension);
+        if !allowed || self.denied_extensions.contains(&extension) {
+            return Err(PolicyViolation::DisallowedExtension(extension));
+        }
+        Ok(())
+    }
+
+    /// Decide the type a file is stored with and check that it is accepted.
+    ///
+    /// `prefix` holds the first bytes of the content, up to `SNIFF_LENGTH`; when it is empty only
+    /// the declared type is checked. A detected type replaces a missing or generic declared type,
+    /// and a contradicting one is handled as configured by `on_type_mismatch`.
+    pub fn resolve_content_type(&self, declared: Option<&str>, prefix: &[u8]) -> Result<Option<String>, PolicyViolation> {
+        let declared_essence = match declared {
+            Some(declared) => match declared.parse::<mime::Mime>() {
+                Ok(parsed) => Some(parsed.essence_str().to_lowercase()),
+                Err(_) => return Err(PolicyViolation::InvalidType),
+            },
+            None => None,
+        };
+
+        let content_type = if self.sniff_content && !prefix.is_empty() {
+            match (sniff(prefix), declared_essence.as_deref()) {
+                (Some(signature), Some(essence)) if signature.accepts(essence) => declared.map(str::to_string),
+                (Some(signature), None | Some(OCTET_STREAM)) => Some(signature.mime.to_string()),
+                (Some(signature), Some(essence)) => Some(self.mismatch(essence, Some(signature.mime))?),
+                // Content claiming a format with a signature, without having it, is not what it says
+                (None, Some(essence)) if is_detectable(essence) => Some(self.mismatch(essence, None)?),
+                (None, _) => declared.map(str::to_string),
+            }
+        } else {
+            declared.map(str::to_string)
+        };
+
+        let essence = content_type
+            .as_deref()
+            .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+            .map(|parsed| parsed.essence_str().to_lowercase())
+            .unwrap_or_else(|| OCTET_STREAM.to_string());
+        let allowed = self.allowed_types.is_empty() || self.allowed_types.iter().any(|pattern| type_matches(pattern, &essence));
+        if !allowed || self.denied_types.iter().any(|pattern| type_matches(pattern, &essence)) {
+            return Err(PolicyViolation::DisallowedType(essence));
+        }
+
+        Ok(content_type)
+    }
+
+    fn mismatch(&self, declared: &str, detected: Option<&'static str>) -> Result<String, PolicyViolation> {
+        match self.on_type_mismatch {
+            TypeMismatchAction::Override => Ok(detected.unwrap_or(OCTET_STREAM).to_string()),
+            TypeMismatchAction::Reject => Err(PolicyViolation::TypeMismatch {
+                declared: declared.to_string(),
+                detected,
+            }),
+        }
+    }
+}
+
+/// Which uploads are compressed before they are stored, from the `[compression]` configuration
+pub struct CompressionPolicy {
+    /// `None` when compression is disabled
+    compression: Option<Compression>,
+    content_types: Vec<String>,
+}
+
+impl CompressionPolicy {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let encoding = match config.algorithm {
+            CompressionAlgorithm::Zstd => ContentEncoding::Zstd,
+            CompressionAlgorithm::Gzip => ContentEncoding::Gzip,
+        };
+        Self {
+            compression: config.enabled.then_some(Compression { encoding, level: config.level }),
+            content_types: config.content_types.iter().map(|pattern| pattern.trim().to_lowercase()).collect(),
+        }
+    }
+
+    /// How an upload stored with `content_type` is compressed, `None` if it is stored as is
+    pub fn compression_for(&self, content_type: Option<&str>) -> Option<Compression> {
+        let essence = content_type?.parse::<mime::Mime>().ok()?.essence_str().to_lowercase();
+        if SEEKABLE_TYPES.iter().any(|pattern| type_matches(pattern, &essence)) {
+            return None;
+        }
+        self.compression
+            .filter(|_| self.content_types.iter().any(|pattern| type_matches(pattern, &essence)))
+    }
+}
+
+/// `Content-Length` declared by a request, if any
+pub fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Read at least `SNIFF_LENGTH` bytes from the start of `body`, or all of it if it is shorter.
+///
+/// The returned bytes have to be put back in front of the rest of the body before it is stored.
+/// The body may have ended by then, so it has to be safe to poll again, e.g. by fusing it.
+pub async fn read_prefix<S, E>(body: &mut S) -> Result<Bytes, E>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut prefix = BytesMut::new();
+    while prefix.len() < SNIFF_LENGTH {
+        match body.next().await {
+            Some(chunk) => prefix.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    Ok(prefix.freeze())
+}
+
+/// Read the first bytes of a stored object of `size` bytes for sniffing
+async fn read_object_prefix(storage: &dyn ObjectStorage, key: &str, size: u64) -> Result<Bytes, StorageError> {
+    if size == 0 {
+        return Ok(Bytes::new());
+    }
+    let range = ByteRange {
+        start: 0,
+        end: size.min(SNIFF_LENGTH as u64) - 1,
+    };
+    let mut object = storage.get_object(key, Some(range)).await?;
+    read_prefix(&mut object.body).await
+}
+
+/// Apply the upload policy to a file that reached storage without passing through the server,
+/// returning the type to record it with
+pub async fn check_stored_object(
+    data: &AppState,
+    file_key: &str,
+    size: u64,
+    declared_type: Option<&str>,
+) -> Result<Option<String>, HttpResponse> {
+    data.upload_policy.check_size(size).map_err(|violation| violation.response())?;
+
+    let prefix = if data.upload_policy.sniffs_content() {
+        match read_object_prefix(data.storage.as_ref(), file_key, size).await {
+            Ok(prefix) => prefix,
+            Err(e) => {
+                eprintln!("Storage error: {}", e);
+                return Err(error_response(HttpResponse::InternalServerError(), "Failed to verify the uploaded file"));
+            }
+        }
+    } else {
+        Bytes::new()
+    };
+
+    data.upload_policy
+        .resolve_content_type(declared_type, &prefix)
+        .map_err(|violation| violation.response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const PDF: &[u8] = b"%PDF-1.7\n";
+
+    fn upload_policy(on_type_mismatch: TypeMismatchAction) -> UploadPolicy {
+        UploadPolicy::new(&UploadPolicyConfig { on_type_mismatch, ..UploadPolicyConfig::default() })
+    }
+
+    fn resolve(declared: Option<&str>, prefix: &[u8]) -> Result<Option<String>, PolicyViolation> {
+        upload_policy(TypeMismatchAction::Override).resolve_content_type(declared, prefix)
+    }
+
+    fn reject(declared: Option<&str>, prefix: &[u8]) -> Result<Option<String>, PolicyViolation> {
+        upload_policy(TypeMismatchAction::Reject).resolve_content_type(declared, prefix)
+    }
+
+    #[test]
+    fn sniffs_signatures() {
+        assert_eq!(sniff(PNG).map(|signature| signature.mime), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 ").map(|signature| signature.mime), Some("image/webp"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVEfmt ").map(|signature| signature.mime), Some("audio/wav"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42").map(|signature| signature.mime), Some("video/mp4"));
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff(&tar).map(|signature| signature.mime), Some("application/x-tar"));
+        assert!(sniff(b"plain text").is_none());
+    }
+
+    #[test]
+    fn sniffs_short_prefixes() {
+        // Prefixes ending before or within a signature match nothing, without reading past their end
+        assert!(sniff(b"\x89PN").is_none());
+        assert!(sniff(b"RIFF\0\0\0\0WE").is_none());
+        assert!(sniff(&[0u8; 260]).is_none());
+        assert!(sniff(b"").is_none());
+    }
+
+    #[test]
+    fn keeps_matching_declared_types() {
+        assert_eq!(resolve(Some("image/png"), PNG).unwrap().as_deref(), Some("image/png"));
+        // Parameters and case of the declared type are kept
+        assert_eq!(resolve(Some("Image/PNG; q=1"), PNG).unwrap().as_deref(), Some("Image/PNG; q=1"));
+        // Aliases, including prefixes, describe the detected format as well
+        let docx = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+        assert_eq!(resolve(Some(docx), b"PK\x03\x04").unwrap().as_deref(), Some(docx));
+        assert_eq!(resolve(Some("audio/mp4"), b"\0\0\0\x18ftypM4A ").unwrap().as_deref(), Some("audio/mp4"));
+    }
+
+    #[test]
+    fn fills_in_missing_and_generic_types() {
+        assert_eq!(resolve(None, PNG).unwrap().as_deref(), Some("image/png"));
+        assert_eq!(resolve(Some("application/octet-stream"), PDF).unwrap().as_deref(), Some("application/pdf"));
+        assert_eq!(resolve(None, b"plain text").unwrap(), None);
+        assert_eq!(resolve(None, b"").unwrap(), None);
+    }
+
+    #[test]
+    fn trusts_declared_types_of_unknown_content() {
+        assert_eq!(resolve(Some("text/csv"), b"a,b\n1,2\n").unwrap().as_deref(), Some("text/csv"));
+        assert_eq!(reject(Some("application/json"), b"{}").unwrap().as_deref(), Some("application/json"));
+    }
+
+    #[test]
+    fn declared_type_mismatching_content() {
+        assert_eq!(resolve(Some("image/jpeg"), PNG).unwrap().as_deref(), Some("image/png"));
+        assert_eq!(resolve(Some("text/plain"), PDF).unwrap().as_deref(), Some("application/pdf"));
+        match reject(Some("Image/JPEG"), PNG) {
+            Err(PolicyViolation::TypeMismatch { declared, detected }) => {
+                assert_eq!((declared.as_str(), detected), ("image/jpeg", Some("image/png")));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn declared_format_missing_its_signature() {
+        // Content of no known format is not the format it claims to be
+        assert_eq!(resolve(Some("image/png"), b"plain text").unwrap().as_deref(), Some("application/octet-stream"));
+        assert!(matches!(
+            reject(Some("application/pdf"), b"plain text"),
+            Err(PolicyViolation::TypeMismatch { detected: None, .. })
+        ));
+        // A file shorter than the signature of its declared format cannot be of that format
+        assert_eq!(resolve(Some("image/png"), b"\x89PN").unwrap().as_deref(), Some("application/octet-stream"));
+        assert!(matches!(
+            reject(Some("image/png"), b"\x89PN"),
+            Err(PolicyViolation::TypeMismatch { detected: None, .. })
+        ));
+    }
+
+    #[test]
+    fn checks_declared_type_only_without_content() {
+        assert_eq!(reject(Some("image/png"), b"").unwrap().as_deref(), Some("image/png"));
+        assert!(matches!(resolve(Some("not a type"), PNG), Err(PolicyViolation::InvalidType)));
+
+        let policy = UploadPolicy::new(&UploadPolicyConfig { sniff_content: false, ..UploadPolicyConfig::default() });
+        assert_eq!(policy.resolve_content_type(Some("image/jpeg"), PNG).unwrap().as_deref(), Some("image/jpeg"));
+        assert_eq!(policy.resolve_content_type(None, PNG).unwrap(), None);
+    }
+
+    #[test]
+    fn checks_resolved_type() {
+        let policy = UploadPolicy::new(&UploadPolicyConfig {
+            allowed_types: vec!["image/*".to_string()],
+            denied_types: vec!["image/gif".to_string()],
+            ..UploadPolicyConfig::default()
+        });
+        assert!(policy.resolve_content_type(None, PNG).is_ok());
+        // The detected type is checked, not the declared one
+        assert!(matches!(
+            policy.resolve_content_type(Some("image/png"), PDF),
+            Err(PolicyViolation::DisallowedType(essence)) if essence == "application/pdf"
+        ));
+        assert!(matches!(policy.resolve_content_type(None, b"GIF89a"), Err(PolicyViolation::DisallowedType(_))));
+        // Untyped content counts as application/octet-stream
+        assert!(matches!(policy.resolve_content_type(None, b"text"), Err(PolicyViolation::DisallowedType(_))));
+    }
+
+    #[tokio::test]
+    async fn reads_prefixes() {
+        let chunks = |sizes: &[usize]| {
+            let chunks: Vec<Result<Bytes, ()>> = sizes.iter().map(|&size| Ok(Bytes::from(vec![1u8; size]))).collect();
+            futures_util::stream::iter(chunks).fuse()
+        };
+
+        let mut body = chunks(&[100, 300, 200, 50]);
+        assert_eq!(read_prefix(&mut body).await.unwrap().len(), 600);
+        assert_eq!(body.next().await.unwrap().unwrap().len(), 50);
+
+        let mut body = chunks(&[10, 20]);
+        assert_eq!(read_prefix(&mut body).await.unwrap().len(), 30);
+        assert!(body.next().await.is_none());
+    }
+
+    fn compression_policy(content_types: &[&str]) -> CompressionPolicy {
+        CompressionPolicy::new(&CompressionConfig {
+            enabled: true,
+            content_types: content_types.iter().map(|content_type| content_type.to_string()).collect(),
+            ..CompressionConfig::default()
+        })
+    }
+
+    #[test]
+    fn compresses_listed_types() {
+        let policy = compression_policy(&["text/*", "application/json"]);
+        assert!(policy.compression_for(Some("text/plain; charset=utf-8")).is_some());
+        assert!(policy.compression_for(Some("Application/JSON")).is_some());
+        assert!(policy.compression_for(Some("image/png")).is_none());
+        assert!(policy.compression_for(None).is_none());
+    }
+
+    #[test]
+    fn never_compresses_seekable_types() {
+        let policy = compression_policy(&["*/*"]);
+        assert!(policy.compression_for(Some("text/csv")).is_some());
+        assert!(policy.compression_for(Some("video/mp4")).is_none());
+        assert!(policy.compression_for(Some("audio/mpeg")).is_none());
+        assert!(policy.compression_for(Some("application/pdf")).is_none());
+    }
+
+    #[test]
+    fn disabled_compresses_nothing() {
+        let policy = CompressionPolicy::new(&CompressionConfig::default());
+        assert!(policy.compression_for(Some("text/plain")).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use actix_web::{web, HttpResponse, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::Claims;
+use crate::storage::{object_key, FileMetadata, PendingUpload, Permission, PresignedRequest, StorageError};
+use crate::{check_file_access, cleanup, folders, policy, quota, versions, AppState, UploadResponse};
+
+/// How long after its URL expires a presigned upload can still be completed
+const COMPLETION_GRACE: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Deserialize)]
+pub struct PresignUploadRequest {
+    pub filename: String,
+    pub content_type: Option<String>,
+    /// Folder to upload into, the top level if absent
+    pub folder_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PresignResponse {
+    pub success: bool,
+    pub message: String,
+    pub file_id: Option<String>,
+    pub request: Option<PresignedRequest>,
+}
+
+fn presign_error(e: StorageError) -> HttpResponse {
+    let message = match e {
+        StorageError::Unsupported(what) => {
+            return HttpResponse::NotImplemented().json(PresignResponse {
+                success: false,
+                message: format!("Presigned URLs are not supported by the configured storage: {}", what),
+                file_id: None,
+                request: None,
+            });
+        }
+        e => {
+            eprintln!("Presign error: {}", e);
+            "Failed to presign storage request".to_string()
+        }
+    };
+
+    HttpResponse::InternalServerError().json(PresignResponse {
+        success: false,
+        message,
+        file_id: None,
+        request: None,
+    })
+}
+
+// Hand out a URL for uploading a file directly to storage
+pub async fn presign_upload(
+    claims: Claims,
+    upload_req: web::Json<PresignUploadRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let mut upload_req = upload_req.into_inner();
+    upload_req.filename = match folders::validate_name(&upload_req.filename) {
+        Ok(filename) => filename,
+        Err(message) => {
+            return Ok(HttpResponse::BadRequest().json(PresignResponse {
+                success: false,
+                message: message.to_string(),
+                file_id: None,
+                request: None,
+            }));
+        }
+    };
+
+    // The content is only seen once the upload is completed; refuse what can be refused already
+    if let Err(violation) = data.upload_policy.check_filename(&upload_req.filename) {
+        return Ok(violation.response());
+    }
+    if let Err(violation) = data.upload_policy.resolve_content_type(upload_req.content_type.as_deref(), &[]) {
+        return Ok(violation.response());
+    }
+
+    if let Some(folder_id) = &upload_req.folder_id {
+        if let Err(response) = folders::check_folder_ownership(&data, folder_id, user_id).await {
+            return Ok(response);
+        }
+    }
+
+    let file_id = Uuid::new_v4().to_string();
+    let file_key = object_key(&file_id, &upload_req.filename);
+
+    let request = match data.storage
+        .presign_put(&file_key, upload_req.content_type.as_deref(), data.presign_expiry)
+        .await
+    {
+        Ok(request) => request,
+        Err(e) => return Ok(presign_error(e)),
+    };
+
+    let pending = PendingUpload {
+        file_id: file_id.clone(),
+        user_id,
+        file_key,
+        filename: upload_req.filename,
+        content_type: upload_req.content_type,
+        expires_at: request.expires_at + chrono::Duration::from_std(COMPLETION_GRACE).unwrap_or_default(),
+        folder_id: upload_req.folder_id,
+    };
+
+    match data.file_metadata.create_pending_upload(&pending).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(PresignResponse {
+            success: true,
+            message: "Upload the file with the presigned request, then complete the upload".to_string(),
+            file_id: Some(file_id),
+            request: Some(request),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(PresignResponse {
+                success: false,
+                message: "Failed to record pending upload".to_string(),
+                file_id: None,
+                request: None,
+            }))
+        }
+    }
+}
+
+// Record the metadata of a file uploaded through a presigned URL
+pub async fn complete_upload(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let pending = match data.file_metadata.get_pending_upload(&file_id).await {
+        Ok(Some(pending)) if pending.user_id == user_id => pending,
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Forbidden().json(UploadResponse {
+                success: false,
+                message: "Access denied: You didn't start this upload".to_string(),
+                file: None,
+            }));
+        }
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(UploadResponse {
+                success: false,
+                message: "Pending upload not found or expired".to_string(),
+                file: None,
+            }));
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(UploadResponse {
+                success: false,
+                message: "Database error while looking up the upload".to_string(),
+                file: None,
+            }));
+        }
+    };
+
+    // The object's size and type come from storage, not from the client
+    let object = match data.storage.head_object(&pending.file_key).await {
+        Ok(object) => object,
+        Err(StorageError::NotFound(_)) => {
+            return Ok(HttpResponse::Conflict().json(UploadResponse {
+                success: false,
+                message: "The file has not been uploaded to storage yet".to_string(),
+                file: None,
+            }));
+        }
+        Err(e) => {
+            eprintln!("Storage error: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(UploadResponse {
+                success: false,
+                message: "Failed to verify the uploaded file".to_string(),
+                file: None,
+            }));
+        }
+    };
+
+    // Claim the upload so that completing it twice cannot record the object twice
+    let pending = match data.file_metadata.take_pending_upload(&file_id).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(UploadResponse {
+                success: false,
+                message: "Pending upload not found or expired".to_string(),
+                file: None,
+            }));
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(UploadResponse {
+                success: false,
+                message: "Database error while looking up the upload".to_string(),
+                file: None,
+            }));
+        }
+    };
+
+    // The size is only known now, so an upload beyond the quota is discarded rather than refused
+    match quota::remaining_quota(&data, user_id).await {
+        Ok(Some(remaining)) if object.size > remaining => {
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &pending.file_key).await;
+            return Ok(quota::quota_exceeded_response());
+        }
+        Ok(_) => {}
+        Err(response) => {
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &pending.file_key).await;
+            return Ok(response);
+        }
+    }
+
+    let declared_type = pending.content_type.as_deref().or(object.content_type.as_deref());
+    let content_type = match policy::check_stored_object(&data, &pending.file_key, object.size, declared_type).await {
+        Ok(content_type) => content_type,
+        Err(response) => {
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &pending.file_key).await;
+            return Ok(response);
+        }
+    };
+
+    let file_metadata = FileMetadata {
+        id: pending.file_id,
+        filename: pending.filename,
+        size: object.size,
+        content_type,
+        upload_time: Utc::now(),
+        s3_key: pending.file_key,
+        // The server never sees the bytes, so the content cannot be deduplicated
+        content_hash: None,
+        version: 1,
+        folder_id: pending.folder_id,
+        workspace_id: None,
+        description: None,
+        tags: Vec::new(),
+        attributes: BTreeMap::new(),
+        content_encoding: None,
+        expires_at: None,
+    };
+
+    match data.file_metadata.insert_file(user_id, &file_metadata).await {
+        Ok(stored) => {
+            if stored.version > 1 {
+                if let Err(e) = versions::prune(&data, &stored.id).await {
+                    eprintln!("Failed to prune versions of {}: {}", stored.id, e);
+                }
+            }
+
+            Ok(HttpResponse::Ok().json(UploadResponse {
+                success: true,
+                message: "File uploaded successfully".to_string(),
+                file: Some(stored),
+            }))
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &file_metadata.s3_key).await;
+            Ok(HttpResponse::InternalServerError().json(UploadResponse {
+                success: false,
+                message: "Failed to save file metadata".to_string(),
+                file: None,
+            }))
+        }
+    }
+}
+
+// Hand out a URL for downloading a file directly from storage
+pub async fn presign_download(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    // The owner and users granted read access may download the file
+    let file_metadata = match check_file_access(&data, &file_id, user_id, Permission::Read).await {
+        Ok(file_metadata) => file_metadata,
+        Err(response) => return Ok(response),
+    };
+
+    // The stored bytes of a compressed file are not what was uploaded
+    if file_metadata.content_encoding.is_some() {
+        return Ok(presign_error(StorageError::Unsupported("presigned downloads of compressed files".to_string())));
+    }
+
+    match data.storage
+        .presign_get(&file_metadata.s3_key, &file_metadata.filename, data.presign_expiry)
+        .await
+    {
+        Ok(request) => Ok(HttpResponse::Ok().json(PresignResponse {
+            success: true,
+            message: "Download the file with the presigned request".to_string(),
+            file_id: Some(file_metadata.id),
+            request: Some(request),
+        })),
+        Err(e) => Ok(presign_error(e)),
+    }
+}
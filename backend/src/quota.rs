@@ -0,0 +1,57 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Serialize;
+
+use crate::auth::Claims;
+use crate::storage::StorageUsage;
+use crate::{error_response, AppState};
+
+#[derive(Serialize)]
+pub struct UsageResponse {
+    pub success: bool,
+    pub message: String,
+    pub usage: Option<StorageUsage>,
+}
+
+pub const QUOTA_EXCEEDED_MESSAGE: &str = "Upload exceeds your remaining storage quota";
+
+/// Answer to an upload that does not fit into the remaining quota
+pub fn quota_exceeded_response() -> HttpResponse {
+    error_response(HttpResponse::PayloadTooLarge(), QUOTA_EXCEEDED_MESSAGE)
+}
+
+/// Bytes `user_id` may still upload, `None` if unlimited, answering with an error response on failure
+pub async fn remaining_quota(data: &AppState, user_id: i64) -> Result<Option<u64>, HttpResponse> {
+    match data.file_metadata.storage_usage(user_id, data.default_quota).await {
+        Ok(usage) => Ok(usage.remaining_bytes),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(HttpResponse::InternalServerError(), "Failed to check storage quota"))
+        }
+    }
+}
+
+// Report the storage used by the current user and what is left of their quota
+pub async fn get_usage(
+    claims: Claims,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    match data.file_metadata.storage_usage(user_id, data.default_quota).await {
+        Ok(usage) => Ok(HttpResponse::Ok().json(UsageResponse {
+            success: true,
+            message: "Storage usage retrieved successfully".to_string(),
+            usage: Some(usage),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(UsageResponse {
+                success: false,
+                message: "Failed to retrieve storage usage".to_string(),
+                usage: None,
+            }))
+        }
+    }
+}
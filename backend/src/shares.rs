@@ -0,0 +1,463 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+
+use crate::auth::Claims;
+use crate::storage::{FileMetadata, Permission, ShareLink};
+use crate::{check_file_access, download, error_response, AppState};
+
+/// Header carrying the password of a protected share link on `GET` requests
+pub const SHARE_PASSWORD_HEADER: &str = "X-Share-Password";
+
+/// Longest accepted share link password, in bytes
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Random bytes in a share link token
+const TOKEN_BYTES: usize = 32;
+
+const PASSWORD_SALT_BYTES: usize = 16;
+
+/// PBKDF2-HMAC-SHA256 rounds for new password hashes; stored hashes record their own
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Prefix identifying the scheme of a stored password hash
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+
+const DOWNLOAD_LIMIT_REACHED: &str = "This share link has reached its download limit";
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareRequest {
+    /// When the link stops working; it never expires if unset
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Password downloaders have to give; the link is open to anyone with the token if unset
+    pub password: Option<String>,
+    /// Number of downloads allowed; unlimited if unset
+    pub max_downloads: Option<i32>,
+}
+
+/// Password of a protected share link sent from an HTML form
+#[derive(Debug, Deserialize)]
+pub struct SharePasswordForm {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkResponse {
+    pub success: bool,
+    pub message: String,
+    pub share: Option<ShareLink>,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinksResponse {
+    pub success: bool,
+    pub message: String,
+    pub shares: Vec<ShareLink>,
+}
+
+/// A new random token, URL-safe
+fn generate_token() -> std::result::Result<String, ring::error::Unspecified> {
+    let mut token = [0u8; TOKEN_BYTES];
+    SystemRandom::new().fill(&mut token)?;
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
+
+/// Hash `password` with a random salt as `pbkdf2-sha256$<iterations>$<salt>$<hash>`
+fn hash_password(password: &str) -> std::result::Result<String, ring::error::Unspecified> {
+    let mut salt = [0u8; PASSWORD_SALT_BYTES];
+    SystemRandom::new().fill(&mut salt)?;
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).ok_or(ring::error::Unspecified)?;
+    let mut hash = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+    Ok(format!(
+        "{}${}${}${}",
+        PASSWORD_SCHEME,
+        PBKDF2_ITERATIONS,
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    ))
+}
+
+/// Whether `password` matches a hash made by `hash_password`, compared in constant time
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    let fields: Vec<&str> = password_hash.split('$').collect();
+    let [scheme, iterations, salt, hash] = fields[..] else {
+        return false;
+    };
+    let iterations = iterations.parse().ok().and_then(NonZeroU32::new);
+    match (iterations, STANDARD_NO_PAD.decode(salt), STANDARD_NO_PAD.decode(hash)) {
+        (Some(iterations), Ok(salt), Ok(hash)) if scheme == PASSWORD_SCHEME => {
+            pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Why a share link can no longer be used, if it cannot; the download limit is checked again when counting
+fn unavailable_reason(link: &ShareLink) -> Option<&'static str> {
+    if link.revoked_at.is_some() {
+        return Some("This share link has been revoked");
+    }
+    if link.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Some("This share link has expired");
+    }
+    if link.max_downloads.is_some_and(|max_downloads| link.download_count >= max_downloads) {
+        return Some(DOWNLOAD_LIMIT_REACHED);
+    }
+    None
+}
+
+/// Whether serving `req` uses up one of the downloads of a share link: resuming or seeking within
+/// a download does not count as another one
+fn counts_download(req: &HttpRequest, file: &FileMetadata) -> bool {
+    !download::resumes_download(req, file)
+}
+
+fn share_error(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(ShareLinkResponse {
+        success: false,
+        message: message.to_string(),
+        share: None,
+    })
+}
+
+// Create a share link for a file
+pub async fn create_share(
+    claims: Claims,
+    path: web::Path<String>,
+    share_req: web::Json<CreateShareRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+    let share_req = share_req.into_inner();
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        return Ok(response);
+    }
+
+    if share_req.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Ok(share_error(HttpResponse::BadRequest(), "Expiry time must be in the future"));
+    }
+    if share_req.max_downloads.is_some_and(|max_downloads| max_downloads < 1) {
+        return Ok(share_error(HttpResponse::BadRequest(), "Maximum downloads must be at least 1"));
+    }
+    let password_hash = match share_req.password {
+        Some(password) if password.is_empty() => {
+            return Ok(share_error(HttpResponse::BadRequest(), "Password must not be empty"));
+        }
+        Some(password) if password.len() > MAX_PASSWORD_LENGTH => {
+            return Ok(share_error(HttpResponse::BadRequest(), "Password is too long"));
+        }
+        // Hashing is deliberately slow, so it is kept off the request threads
+        Some(password) => match web::block(move || hash_password(&password)).await {
+            Ok(Ok(password_hash)) => Some(password_hash),
+            _ => return Ok(share_error(HttpResponse::InternalServerError(), "Failed to hash password")),
+        },
+        None => None,
+    };
+
+    let token = match generate_token() {
+        Ok(token) => token,
+        Err(_) => return Ok(share_error(HttpResponse::InternalServerError(), "Failed to generate share token")),
+    };
+
+    let link = ShareLink {
+        token,
+        file_id,
+        user_id,
+        password_hash,
+        expires_at: share_req.expires_at,
+        max_downloads: share_req.max_downloads,
+        download_count: 0,
+        revoked_at: None,
+        created_at: Utc::now(),
+    };
+
+    match data.file_metadata.create_share_link(&link).await {
+        Ok(()) => Ok(HttpResponse::Created().json(ShareLinkResponse {
+            success: true,
+            message: "Share link created successfully".to_string(),
+            share: Some(link),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(share_error(HttpResponse::InternalServerError(), "Failed to create share link"))
+        }
+    }
+}
+
+// List the share links of a file
+pub async fn list_shares(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.list_share_links(&file_id).await {
+        Ok(shares) => Ok(HttpResponse::Ok().json(ShareLinksResponse {
+            success: true,
+            message: "Share links retrieved successfully".to_string(),
+            shares,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve share links"))
+        }
+    }
+}
+
+// Revoke a share link of a file
+pub async fn revoke_share(
+    claims: Claims,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (file_id, token) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.revoke_share_link(&file_id, &token).await {
+        Ok(Some(link)) => Ok(HttpResponse::Ok().json(ShareLinkResponse {
+            success: true,
+            message: "Share link revoked successfully".to_string(),
+            share: Some(link),
+        })),
+        Ok(None) => Ok(share_error(HttpResponse::NotFound(), "Share link not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(share_error(HttpResponse::InternalServerError(), "Failed to revoke share link"))
+        }
+    }
+}
+
+// Download a file through a share link; no sign-in needed
+pub async fn download_shared(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form: Option<web::Form<SharePasswordForm>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let token = path.into_inner();
+
+    let link = match data.file_metadata.get_share_link(&token).await {
+        Ok(Some(link)) => link,
+        Ok(None) => return Ok(error_response(HttpResponse::NotFound(), "Share link not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(error_response(HttpResponse::InternalServerError(), "Database error while checking share link"));
+        }
+    };
+    if let Some(reason) = unavailable_reason(&link) {
+        return Ok(error_response(HttpResponse::Gone(), reason));
+    }
+
+    if let Some(password_hash) = link.password_hash.clone() {
+        let password = match form {
+            Some(form) => Some(form.into_inner().password),
+            None => req
+                .headers()
+                .get(SHARE_PASSWORD_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        };
+        let Some(password) = password else {
+            return Ok(error_response(HttpResponse::Unauthorized(), "This share link is protected by a password"));
+        };
+        let matches = password.len() <= MAX_PASSWORD_LENGTH
+            && web::block(move || verify_password(&password, &password_hash)).await.unwrap_or(false);
+        if !matches {
+            return Ok(error_response(HttpResponse::Forbidden(), "Incorrect password"));
+        }
+    }
+
+    // The link only works as long as its creator could still create it
+    let file = match check_file_access(&data, &link.file_id, link.user_id, Permission::Manage).await {
+        Ok(file) => file,
+        Err(response) if response.status() == StatusCode::FORBIDDEN => {
+            return Ok(error_response(HttpResponse::Gone(), "This share link is no longer valid"));
+        }
+        Err(response) => return Ok(response),
+    };
+
+    let counted = counts_download(&req, &file);
+    if counted {
+        match data.file_metadata.count_share_download(&token).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(error_response(HttpResponse::Gone(), DOWNLOAD_LIMIT_REACHED)),
+            Err(e) => {
+                eprintln!("Database error: {}", e);
+                return Ok(error_response(HttpResponse::InternalServerError(), "Database error while checking share link"));
+            }
+        }
+    }
+
+    match download::serve_file(&req, data.storage.as_ref(), &file).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            eprintln!("Download error: {}", e);
+            // Nothing was sent, so the download does not use up the limit
+            if counted {
+                if let Err(e) = data.file_metadata.uncount_share_download(&token).await {
+                    eprintln!("Database error: {}", e);
+                }
+            }
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to download file from storage"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+    use chrono::Duration;
+
+    fn link(max_downloads: Option<i32>, download_count: i32) -> ShareLink {
+        ShareLink {
+            token: "token".to_string(),
+            file_id: "file".to_string(),
+            user_id: 1,
+            password_hash: None,
+            expires_at: None,
+            max_downloads,
+            download_count,
+            revoked_at: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn file(size: u64) -> FileMetadata {
+        FileMetadata {
+            id: "file".to_string(),
+            filename: "file.bin".to_string(),
+            size,
+            content_type: None,
+            upload_time: Utc::now(),
+            s3_key: "file.bin".to_string(),
+            content_hash: None,
+            version: 1,
+            folder_id: None,
+            workspace_id: None,
+            description: None,
+            tags: Vec::new(),
+            attributes: Default::default(),
+            content_encoding: None,
+            expires_at: None,
+        }
+    }
+
+    /// A hash of `password` like `hash_password` makes, with `iterations` rounds
+    fn hash_with_iterations(password: &str, iterations: u32) -> String {
+        let salt = [7u8; PASSWORD_SALT_BYTES];
+        let mut hash = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(iterations).unwrap(),
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        format!("{}${}${}${}", PASSWORD_SCHEME, iterations, STANDARD_NO_PAD.encode(salt), STANDARD_NO_PAD.encode(hash))
+    }
+
+    #[test]
+    fn verifies_hashed_passwords() {
+        let password_hash = hash_password("correct horse").unwrap();
+        assert!(password_hash.starts_with(&format!("{}${}$", PASSWORD_SCHEME, PBKDF2_ITERATIONS)));
+        assert!(verify_password("correct horse", &password_hash));
+        assert!(!verify_password("Correct horse", &password_hash));
+    }
+
+    #[test]
+    fn verifies_hashes_with_their_own_iterations() {
+        let password_hash = hash_with_iterations("secret", 1000);
+        assert!(verify_password("secret", &password_hash));
+        assert!(!verify_password("secret", &password_hash.replacen("$1000$", "$1001$", 1)));
+        assert!(!verify_password("Secret", &password_hash));
+        assert!(!verify_password("secret ", &password_hash));
+        assert!(!verify_password("", &password_hash));
+    }
+
+    #[test]
+    fn rejects_malformed_password_hashes() {
+        let password_hash = hash_with_iterations("secret", 1000);
+        let fields: Vec<&str> = password_hash.split('$').collect();
+        let malformed = [
+            String::new(),
+            "secret".to_string(),
+            fields[..3].join("$"),
+            format!("{}$extra", password_hash),
+            password_hash.replacen(PASSWORD_SCHEME, "pbkdf2-sha512", 1),
+            password_hash.replacen("$1000$", "$0$", 1),
+            password_hash.replacen("$1000$", "$-1000$", 1),
+            password_hash.replacen("$1000$", "$many$", 1),
+            format!("{}${}${}$not base64!", fields[0], fields[1], fields[2]),
+            format!("{}${}$not base64!${}", fields[0], fields[1], fields[3]),
+            format!("{}${}${}${}", fields[0], fields[1], fields[2], &fields[3][..10]),
+        ];
+        for password_hash in malformed {
+            assert!(!verify_password("secret", &password_hash), "{}", password_hash);
+        }
+    }
+
+    #[test]
+    fn reports_unavailable_links() {
+        assert_eq!(unavailable_reason(&link(None, 0)), None);
+        assert_eq!(unavailable_reason(&link(None, 1000)), None);
+
+        let revoked = ShareLink { revoked_at: Some(Utc::now()), ..link(None, 0) };
+        assert_eq!(unavailable_reason(&revoked), Some("This share link has been revoked"));
+
+        let expired = ShareLink { expires_at: Some(Utc::now() - Duration::seconds(1)), ..link(None, 0) };
+        assert_eq!(unavailable_reason(&expired), Some("This share link has expired"));
+        let expiring = ShareLink { expires_at: Some(Utc::now() + Duration::hours(1)), ..link(None, 0) };
+        assert_eq!(unavailable_reason(&expiring), None);
+    }
+
+    #[test]
+    fn stops_links_at_their_download_limit() {
+        assert_eq!(unavailable_reason(&link(Some(1), 0)), None);
+        assert_eq!(unavailable_reason(&link(Some(3), 2)), None);
+        assert_eq!(unavailable_reason(&link(Some(3), 3)), Some(DOWNLOAD_LIMIT_REACHED));
+        assert_eq!(unavailable_reason(&link(Some(3), 4)), Some(DOWNLOAD_LIMIT_REACHED));
+    }
+
+    #[test]
+    fn counts_downloads_starting_at_the_first_byte() {
+        let file = file(1000);
+        let range = |value: &str| TestRequest::default().insert_header((header::RANGE, value)).to_http_request();
+
+        assert!(counts_download(&TestRequest::default().to_http_request(), &file));
+        assert!(counts_download(&range("bytes=0-99"), &file));
+        assert!(counts_download(&range("bytes=0-"), &file));
+        assert!(!counts_download(&range("bytes=500-"), &file));
+        assert!(!counts_download(&range("bytes=-100"), &file));
+
+        // Ranges that are not served as such send the whole file
+        assert!(counts_download(&range("bytes=1000-"), &file));
+        assert!(counts_download(&range("lines=5-"), &file));
+    }
+}
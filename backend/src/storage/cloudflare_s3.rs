@@ -1,31 +1,39 @@
+use async_trait::async_trait;
 use aws_sdk_s3 as s3;
-use aws_smithy_types::date_time::Format::DateTime;
-use crate::conf::{load_config, validate_config};
-
-#[derive(Debug)]
-pub enum StorageError {
-    BucketNotFound(String),
-    CredentialsInvalid,
-    NetworkError(String),
-    ConfigurationError(String),
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata};
+use aws_sdk_s3::presigning::{self, PresigningConfig};
+use aws_smithy_types::byte_stream::ByteStream;
+use actix_web::http::header::{ContentDisposition, TryIntoHeaderValue};
+use chrono::{TimeZone, Utc};
+use std::time::Duration;
+
+use super::{ByteRange, CompletedPart, ObjectBody, ObjectInfo, ObjectStorage, PresignedRequest, StorageError};
+use crate::conf::CloudflareConfig;
+
+/// Object storage backed by a Cloudflare R2 bucket
+pub struct CloudflareStorage {
+    client: s3::Client,
+    bucket_name: String,
+}
+
+impl CloudflareStorage {
+    /// Create a new CloudflareStorage instance
+    pub async fn new(config: &CloudflareConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let client = create_s3_client(config).await;
+        Ok(Self {
+            client,
+            bucket_name: config.bucket_name.clone(),
+        })
+    }
 }
 
 /// Create S3 client with Cloudflare R2 configuration
-pub async fn create_s3_client() -> Result<s3::Client, Box<dyn std::error::Error>> {
-    let app_config = load_config()?;
-
-    let account_id = &app_config.cloudflare.account_id;
-    let access_key_id = &app_config.cloudflare.access_key_id;
-    let access_key_secret = &app_config.cloudflare.access_key_secret;
-    // print the account id and access key id
-    println!("account_id: {}, access_key_id: {}", account_id, access_key_id);
-
-    // Configure the client
-    let aws_config = aws_config::from_env()
-        .endpoint_url(format!("https://{}.r2.cloudflarestorage.com", account_id))
-        .credentials_provider(aws_sdk_s3::config::Credentials::new(
-            access_key_id.clone(),
-            access_key_secret.clone(),
+pub async fn create_s3_client(config: &CloudflareConfig) -> s3::Client {
+    let aws_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .endpoint_url(format!("https://{}.r2.cloudflarestorage.com", config.account_id))
+        .credentials_provider(s3::config::Credentials::new(
+            config.access_key_id.clone(),
+            config.access_key_secret.clone(),
             None, // session token is not used with R2
             None,
             "R2",
@@ -34,47 +42,272 @@ pub async fn create_s3_client() -> Result<s3::Client, Box<dyn std::error::Error>
         .load()
         .await;
 
-    Ok(s3::Client::new(&aws_config))
+    s3::Client::new(&aws_config)
+}
+
+fn network_error<E: std::error::Error + ProvideErrorMetadata>(e: E) -> StorageError {
+    match e.code() {
+        Some("NoSuchBucket") => StorageError::BucketNotFound(e.message().unwrap_or_default().to_string()),
+        Some("AccessDenied" | "InvalidAccessKeyId" | "SignatureDoesNotMatch") => StorageError::CredentialsInvalid,
+        _ => StorageError::NetworkError(DisplayErrorContext(e).to_string()),
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let bucket_name = "sdk-example";
+fn presigning_config(expires_in: Duration) -> Result<PresigningConfig, StorageError> {
+    PresigningConfig::expires_in(expires_in).map_err(|e| StorageError::ConfigurationError(e.to_string()))
+}
 
-    let client = create_s3_client().await?;
+fn presigned_request(request: presigning::PresignedRequest, expires_in: Duration) -> PresignedRequest {
+    PresignedRequest {
+        method: request.method().to_string(),
+        url: request.uri().to_string(),
+        headers: request
+            .headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        expires_at: Utc::now() + chrono::Duration::from_std(expires_in).unwrap_or_default(),
+    }
+}
 
-    // List buckets
-    let list_buckets_output = client.list_buckets().send().await?;
+#[async_trait]
+impl ObjectStorage for CloudflareStorage {
+    async fn put_object(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let mut put_object = self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .body(ByteStream::from(content));
 
-    println!("Buckets:");
-    for bucket in list_buckets_output.buckets() {
-        println!("  - {}: {}",
-            bucket.name().unwrap_or_default(),
-            bucket.creation_date().map_or_else(
-                || "Unknown creation date".to_string(),
-                |date| date.fmt(DateTime).unwrap()
-            )
-        );
+        if let Some(ct) = content_type {
+            put_object = put_object.content_type(ct);
+        }
+
+        put_object.send().await.map_err(network_error)?;
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str, range: Option<ByteRange>) -> Result<ObjectBody, StorageError> {
+        let mut request = self.client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key);
+
+        if let Some(range) = range {
+            request = request.range(format!("bytes={}-{}", range.start, range.end));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                err if err.is_no_such_key() => StorageError::NotFound(key.to_string()),
+                err => network_error(err),
+            })?;
+
+        let content_length = response.content_length().unwrap_or_default().max(0) as u64;
+        let body = futures_util::stream::unfold(response.body, |mut body| async move {
+            body.next()
+                .await
+                .map(|chunk| (chunk.map_err(|e| StorageError::NetworkError(e.to_string())), body))
+        });
+
+        Ok(ObjectBody {
+            body: Box::pin(body),
+            content_length,
+        })
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), StorageError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await
+            .map_err(network_error)?;
+
+        Ok(())
+    }
+
+    async fn list_objects(&self) -> Result<Vec<String>, StorageError> {
+        let mut keys = Vec::new();
+        let mut pages = self.client
+            .list_objects_v2()
+            .bucket(&self.bucket_name)
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(network_error)?;
+            keys.extend(
+                page.contents()
+                    .iter()
+                    .filter_map(|obj| obj.key().map(|k| k.to_string())),
+            );
+        }
+
+        Ok(keys)
+    }
+
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, StorageError> {
+        let response = self.client
+            .head_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                err if err.is_not_found() => StorageError::NotFound(key.to_string()),
+                err => network_error(err),
+            })?;
+
+        Ok(ObjectInfo {
+            size: response.content_length().unwrap_or_default().max(0) as u64,
+            content_type: response.content_type().map(|ct| ct.to_string()),
+            last_modified: response
+                .last_modified()
+                .and_then(|t| Utc.timestamp_opt(t.secs(), t.subsec_nanos()).single()),
+        })
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, StorageError> {
+        let mut request = self.client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key);
+
+        if let Some(ct) = content_type {
+            request = request.content_type(ct);
+        }
+
+        let response = request.send().await.map_err(network_error)?;
+        response
+            .upload_id()
+            .map(|id| id.to_string())
+            .ok_or_else(|| StorageError::NetworkError("R2 did not return an upload id".to_string()))
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        content: Vec<u8>,
+        _last: bool,
+    ) -> Result<CompletedPart, StorageError> {
+        let response = self.client
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(content))
+            .send()
+            .await
+            .map_err(network_error)?;
+
+        Ok(CompletedPart {
+            part_number,
+            etag: response.e_tag().unwrap_or_default().to_string(),
+        })
     }
 
-    // List objects in a specific bucket
-    let list_objects_output = client
-        .list_objects_v2()
-        .bucket(bucket_name)
-        .send()
-        .await?;
-
-    println!("\nObjects in {}:", bucket_name);
-    for object in list_objects_output.contents() {
-        println!("  - {}: {} bytes, last modified: {}",
-            object.key().unwrap_or_default(),
-            object.size().unwrap_or_default(),
-            object.last_modified().map_or_else(
-                || "Unknown".to_string(),
-                |date| date.fmt(DateTime).unwrap()
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), StorageError> {
+        let parts = parts
+            .into_iter()
+            .map(|part| {
+                s3::types::CompletedPart::builder()
+                    .part_number(part.part_number)
+                    .e_tag(part.etag)
+                    .build()
+            })
+            .collect();
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                s3::types::CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
             )
-        );
+            .send()
+            .await
+            .map_err(network_error)?;
+
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+            .map_err(network_error)?;
+
+        Ok(())
+    }
+
+    async fn presign_put(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<PresignedRequest, StorageError> {
+        let mut request = self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(key);
+
+        if let Some(ct) = content_type {
+            request = request.content_type(ct);
+        }
+
+        let presigned = request
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(network_error)?;
+        Ok(presigned_request(presigned, expires_in))
     }
 
-    Ok(())
-}
\ No newline at end of file
+    async fn presign_get(
+        &self,
+        key: &str,
+        filename: &str,
+        expires_in: Duration,
+    ) -> Result<PresignedRequest, StorageError> {
+        let disposition = ContentDisposition::attachment(filename)
+            .try_into_value()
+            .ok()
+            .and_then(|value| value.to_str().ok().map(|v| v.to_string()));
+
+        let presigned = self.client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(key)
+            .set_response_content_disposition(disposition)
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(network_error)?;
+        Ok(presigned_request(presigned, expires_in))
+    }
+}
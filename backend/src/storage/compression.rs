@@ -0,0 +1,184 @@
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use super::{FileMetadata, ObjectBody, ObjectStorage, ObjectStream, StorageError};
+
+/// Encoding content is stored with, named as in HTTP's `Content-Encoding`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Zstd,
+    Gzip,
+}
+
+impl ContentEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = StorageError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "zstd" => Ok(ContentEncoding::Zstd),
+            "gzip" => Ok(ContentEncoding::Gzip),
+            _ => Err(StorageError::Unsupported(format!("Unknown content encoding {}", name))),
+        }
+    }
+}
+
+/// How an upload is compressed before it is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub encoding: ContentEncoding,
+    /// Compression level; the algorithm's default when unset
+    pub level: Option<i32>,
+}
+
+/// A streaming encoder or decoder collecting its output in memory until it is taken
+enum Codec {
+    ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    GzipEncoder(flate2::write::GzEncoder<Vec<u8>>),
+    ZstdDecoder(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    GzipDecoder(flate2::write::GzDecoder<Vec<u8>>),
+}
+
+impl Codec {
+    fn encoder(compression: Compression) -> io::Result<Self> {
+        Ok(match compression.encoding {
+            // Level 0 selects zstd's default
+            ContentEncoding::Zstd => Codec::ZstdEncoder(zstd::stream::write::Encoder::new(
+                Vec::new(),
+                compression.level.unwrap_or(0),
+            )?),
+            ContentEncoding::Gzip => Codec::GzipEncoder(flate2::write::GzEncoder::new(
+                Vec::new(),
+                compression
+                    .level
+                    .map(|level| flate2::Compression::new(level.clamp(0, 9) as u32))
+                    .unwrap_or_default(),
+            )),
+        })
+    }
+
+    fn decoder(encoding: ContentEncoding) -> io::Result<Self> {
+        Ok(match encoding {
+            ContentEncoding::Zstd => Codec::ZstdDecoder(zstd::stream::write::Decoder::new(Vec::new())?),
+            ContentEncoding::Gzip => Codec::GzipDecoder(flate2::write::GzDecoder::new(Vec::new())),
+        })
+    }
+
+    /// Feed `input` through and take the output produced so far.
+    ///
+    /// Encoders are not flushed, so they emit whole blocks; decoders are, so decompressed
+    /// content is passed on as soon as it is available.
+    fn write(&mut self, input: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            Codec::ZstdEncoder(encoder) => {
+                encoder.write_all(input)?;
+                encoder.get_mut()
+            }
+            Codec::GzipEncoder(encoder) => {
+                encoder.write_all(input)?;
+                encoder.get_mut()
+            }
+            Codec::ZstdDecoder(decoder) => {
+                decoder.write_all(input)?;
+                decoder.flush()?;
+                decoder.get_mut()
+            }
+            Codec::GzipDecoder(decoder) => {
+                decoder.write_all(input)?;
+                decoder.flush()?;
+                decoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    /// End the stream and take the remaining output
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Codec::ZstdEncoder(encoder) => encoder.finish()?,
+            Codec::GzipEncoder(encoder) => encoder.finish()?,
+            Codec::ZstdDecoder(mut decoder) => {
+                decoder.flush()?;
+                decoder.into_inner()
+            }
+            Codec::GzipDecoder(decoder) => decoder.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+fn codec_error(e: io::Error) -> StorageError {
+    StorageError::Io(format!("Failed to transcode content: {}", e))
+}
+
+/// Pass every chunk of `body` through `codec`, ending the stream at the first error
+fn transcode<S, E>(body: S, codec: io::Result<Codec>) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: From<StorageError>,
+{
+    stream::unfold((body, Some(codec)), |(mut body, mut codec)| async move {
+        let mut current = match codec.take()? {
+            Ok(current) => current,
+            Err(e) => return Some((Err(codec_error(e).into()), (body, None))),
+        };
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => match current.write(&chunk) {
+                    Ok(output) if output.is_empty() => continue,
+                    Ok(output) => return Some((Ok(output), (body, Some(Ok(current))))),
+                    Err(e) => return Some((Err(codec_error(e).into()), (body, None))),
+                },
+                Some(Err(e)) => return Some((Err(e), (body, None))),
+                None => {
+                    return match current.finish() {
+                        Ok(output) if output.is_empty() => None,
+                        Ok(output) => Some((Ok(output), (body, None))),
+                        Err(e) => Some((Err(codec_error(e).into()), (body, None))),
+                    };
+                }
+            }
+        }
+    })
+}
+
+/// Compress `body` as configured by `compression`
+pub fn compress<S, E>(body: S, compression: Compression) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: From<StorageError>,
+{
+    transcode(body, Codec::encoder(compression))
+}
+
+/// Decompress the content of an object stored with `encoding`
+pub fn decompress(body: ObjectStream, encoding: ContentEncoding) -> ObjectStream {
+    Box::pin(transcode(body, Codec::decoder(encoding)))
+}
+
+/// The encoding `file` is stored with, `None` if it holds the content as uploaded
+pub fn stored_encoding(file: &FileMetadata) -> Result<Option<ContentEncoding>, StorageError> {
+    file.content_encoding.as_deref().map(str::parse).transpose()
+}
+
+/// The whole content of `file` as uploaded, decompressed on the way if it is stored compressed
+pub async fn get_content(storage: &dyn ObjectStorage, file: &FileMetadata) -> Result<ObjectBody, StorageError> {
+    let object = storage.get_object(&file.s3_key, None).await?;
+    Ok(match stored_encoding(file)? {
+        Some(encoding) => ObjectBody {
+            body: decompress(object.body, encoding),
+            content_length: file.size,
+        },
+        None => object,
+    })
+}
@@ -0,0 +1,747 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{stream, StreamExt};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, MAX_TAG_LEN, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::{Client, NoTls, Row};
+
+use super::transfer::MULTIPART_PART_SIZE;
+use super::{
+    ByteRange, CompletedPart, ObjectBody, ObjectInfo, ObjectStorage, ObjectStream, PresignedRequest, StorageError,
+};
+use crate::conf::EncryptionConfig;
+
+/// Plaintext bytes per encrypted segment; every segment but the last of an object is full
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Bytes added to every segment: its nonce in front and its authentication tag at the end
+const SEGMENT_OVERHEAD: usize = NONCE_LEN + MAX_TAG_LEN;
+
+const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE + SEGMENT_OVERHEAD;
+
+/// Segments in every multipart part but the last, which are exactly `MULTIPART_PART_SIZE` long
+const SEGMENTS_PER_PART: u64 = (MULTIPART_PART_SIZE / SEGMENT_SIZE) as u64;
+
+/// Length of master and data keys: AES-256
+const KEY_LEN: usize = 32;
+
+/// Number of data keys re-wrapped per query
+const REWRAP_BATCH_SIZE: i64 = 500;
+
+fn encryption_error(message: impl Into<String>) -> StorageError {
+    StorageError::Encryption(message.into())
+}
+
+fn key_store_error(e: tokio_postgres::Error) -> StorageError {
+    encryption_error(format!("data key store: {}", e))
+}
+
+fn aes_key(bytes: &[u8]) -> Result<LessSafeKey, StorageError> {
+    UnboundKey::new(&AES_256_GCM, bytes)
+        .map(LessSafeKey::new)
+        .map_err(|_| encryption_error("invalid AES-256 key"))
+}
+
+/// Plaintext size of the encrypted segments in `size` bytes
+fn plaintext_size(size: u64) -> u64 {
+    let segments = size.div_ceil(ENCRYPTED_SEGMENT_SIZE as u64);
+    size.saturating_sub(segments * SEGMENT_OVERHEAD as u64)
+}
+
+/// Additional data authenticated with the segment at `position`.
+///
+/// Flagging the final segment (the STREAM construction) makes an object cut short at a segment
+/// boundary, or extended beyond its final segment, fail to decrypt.
+fn segment_aad(position: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&position.to_be_bytes());
+    aad[8] = u8::from(last);
+    aad
+}
+
+/// Encrypt `content` as consecutive segments, the first of which is segment
+/// `first_segment` of its object. With `last`, the content ends the object and its last segment is
+/// flagged final; empty content then still gets an empty final segment.
+fn encrypt(
+    random: &dyn SecureRandom,
+    key: &LessSafeKey,
+    first_segment: u64,
+    content: &[u8],
+    last: bool,
+) -> Result<Vec<u8>, StorageError> {
+    let mut segments: Vec<&[u8]> = content.chunks(SEGMENT_SIZE).collect();
+    if segments.is_empty() && last {
+        segments.push(&[]);
+    }
+    let mut encrypted = Vec::with_capacity(content.len() + segments.len() * SEGMENT_OVERHEAD);
+
+    let count = segments.len();
+    for (index, segment) in segments.into_iter().enumerate() {
+        let mut nonce = [0u8; NONCE_LEN];
+        random.fill(&mut nonce).map_err(|_| encryption_error("no randomness available"))?;
+        encrypted.extend_from_slice(&nonce);
+        let start = encrypted.len();
+        encrypted.extend_from_slice(segment);
+        let aad = segment_aad(first_segment + index as u64, last && index + 1 == count);
+        let tag = key
+            .seal_in_place_separate_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut encrypted[start..])
+            .map_err(|_| encryption_error("failed to encrypt segment"))?;
+        encrypted.extend_from_slice(tag.as_ref());
+    }
+    Ok(encrypted)
+}
+
+/// A data key encrypted under a master key
+struct WrappedKey {
+    master_key_id: String,
+    /// Nonce followed by the sealed key
+    wrapped: Vec<u8>,
+}
+
+fn wrapped_key_from_row(row: &Row) -> WrappedKey {
+    WrappedKey {
+        master_key_id: row.get("master_key_id"),
+        wrapped: row.get("wrapped_key"),
+    }
+}
+
+/// Master keys from the `[encryption]` section, which wrap the data keys of the objects
+struct Keyring {
+    keys: HashMap<String, LessSafeKey>,
+    /// Id of the key new data keys are wrapped with
+    active: String,
+    random: SystemRandom,
+}
+
+impl Keyring {
+    fn new(config: &EncryptionConfig) -> Result<Self, StorageError> {
+        let mut keys = HashMap::new();
+        for (id, encoded) in &config.master_keys {
+            let bytes = STANDARD.decode(encoded.trim()).map_err(|_| {
+                StorageError::ConfigurationError(format!("Master key {} is not valid base64", id))
+            })?;
+            if bytes.len() != KEY_LEN {
+                return Err(StorageError::ConfigurationError(format!("Master key {} must be {} bytes long", id, KEY_LEN)));
+            }
+            keys.insert(id.clone(), aes_key(&bytes)?);
+        }
+        if !keys.contains_key(&config.active_key) {
+            return Err(StorageError::ConfigurationError(format!(
+                "The active master key {:?} is not among the master keys",
+                config.active_key
+            )));
+        }
+
+        Ok(Self {
+            keys,
+            active: config.active_key.clone(),
+            random: SystemRandom::new(),
+        })
+    }
+
+    fn random_bytes<const N: usize>(&self) -> Result<[u8; N], StorageError> {
+        let mut bytes = [0u8; N];
+        self.random.fill(&mut bytes).map_err(|_| encryption_error("no randomness available"))?;
+        Ok(bytes)
+    }
+
+    /// Encrypt the data key of `file_key` under the active master key; the wrapped key only
+    /// unwraps for that object
+    fn wrap(&self, file_key: &str, data_key: &[u8]) -> Result<WrappedKey, StorageError> {
+        let nonce = self.random_bytes::<NONCE_LEN>()?;
+        let mut sealed = data_key.to_vec();
+        self.keys[&self.active]
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(file_key.as_bytes()), &mut sealed)
+            .map_err(|_| encryption_error(format!("failed to wrap the data key of {}", file_key)))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&sealed);
+        Ok(WrappedKey {
+            master_key_id: self.active.clone(),
+            wrapped,
+        })
+    }
+
+    fn unwrap(&self, file_key: &str, key: &WrappedKey) -> Result<Vec<u8>, StorageError> {
+        let master = self.keys.get(&key.master_key_id).ok_or_else(|| {
+            encryption_error(format!("the data key of {} is wrapped by unknown master key {}", file_key, key.master_key_id))
+        })?;
+        if key.wrapped.len() < NONCE_LEN {
+            return Err(encryption_error(format!("the data key of {} is malformed", file_key)));
+        }
+        let (nonce, sealed) = key.wrapped.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| encryption_error("invalid nonce"))?;
+        let mut sealed = sealed.to_vec();
+        let data_key = master
+            .open_in_place(nonce, Aad::from(file_key.as_bytes()), &mut sealed)
+            .map_err(|_| encryption_error(format!("the data key of {} cannot be unwrapped", file_key)))?;
+        Ok(data_key.to_vec())
+    }
+}
+
+/// Wrapped data keys of encrypted objects, kept in the `object_data_keys` table
+struct DataKeyStore {
+    client: Client,
+}
+
+impl DataKeyStore {
+    async fn connect(database_url: &str) -> Result<Self, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("Data key store connection error: {}", e);
+            }
+        });
+
+        Ok(Self { client })
+    }
+
+    async fn get(&self, file_key: &str) -> Result<Option<WrappedKey>, tokio_postgres::Error> {
+        let row = self.client
+            .query_opt(
+                "SELECT master_key_id, wrapped_key FROM object_data_keys WHERE file_key = $1",
+                &[&file_key],
+            )
+            .await?;
+        Ok(row.as_ref().map(wrapped_key_from_row))
+    }
+
+    /// Store `key` for `file_key` unless the object has a data key already, and return the key it ends up with
+    async fn insert(&self, file_key: &str, key: &WrappedKey) -> Result<WrappedKey, tokio_postgres::Error> {
+        let row = self.client
+            .query_one(
+                "WITH inserted AS (
+                     INSERT INTO object_data_keys (file_key, master_key_id, wrapped_key) VALUES ($1, $2, $3)
+                     ON CONFLICT (file_key) DO NOTHING
+                     RETURNING master_key_id, wrapped_key
+                 )
+                 SELECT master_key_id, wrapped_key FROM inserted
+                 UNION ALL
+                 SELECT master_key_id, wrapped_key FROM object_data_keys WHERE file_key = $1",
+                &[&file_key, &key.master_key_id, &key.wrapped],
+            )
+            .await?;
+        Ok(wrapped_key_from_row(&row))
+    }
+
+    async fn delete(&self, file_key: &str) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .execute("DELETE FROM object_data_keys WHERE file_key = $1", &[&file_key])
+            .await?;
+        Ok(())
+    }
+
+    /// Data keys not wrapped by `master_key_id`, in key order after `after`
+    async fn wrapped_by_others(
+        &self,
+        master_key_id: &str,
+        after: &str,
+    ) -> Result<Vec<(String, WrappedKey)>, tokio_postgres::Error> {
+        let rows = self.client
+            .query(
+                "SELECT file_key, master_key_id, wrapped_key FROM object_data_keys
+                 WHERE master_key_id <> $1 AND file_key > $2
+                 ORDER BY file_key LIMIT $3",
+                &[&master_key_id, &after, &REWRAP_BATCH_SIZE],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| (row.get("file_key"), wrapped_key_from_row(row))).collect())
+    }
+
+    /// Replace the wrapped data key of `file_key`, unless it was re-wrapped in the meantime
+    async fn replace(&self, file_key: &str, previous_master_key_id: &str, key: &WrappedKey) -> Result<bool, tokio_postgres::Error> {
+        let updated = self.client
+            .execute(
+                "UPDATE object_data_keys SET master_key_id = $3, wrapped_key = $4
+                 WHERE file_key = $1 AND master_key_id = $2",
+                &[&file_key, &previous_master_key_id, &key.master_key_id, &key.wrapped],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+}
+
+/// Storage backend wrapper encrypting objects at rest with AES-256-GCM.
+///
+/// Every object gets its own random data key, stored in PostgreSQL wrapped by the active master
+/// key. Objects are split into 64 KiB segments, each sealed with a random nonce and bound to
+/// its position and to whether it is the final one, so ranges can be read without decrypting
+/// the whole object and truncated objects are detected. Objects without a data key, e.g. stored
+/// before encryption was enabled, are read as they are.
+pub struct EncryptedStorage {
+    inner: Arc<dyn ObjectStorage>,
+    keyring: Keyring,
+    data_keys: DataKeyStore,
+}
+
+impl EncryptedStorage {
+    pub async fn new(
+        inner: Arc<dyn ObjectStorage>,
+        config: &EncryptionConfig,
+        database_url: &str,
+    ) -> Result<Self, StorageError> {
+        Ok(Self {
+            inner,
+            keyring: Keyring::new(config)?,
+            data_keys: DataKeyStore::connect(database_url).await.map_err(key_store_error)?,
+        })
+    }
+
+    /// Data key of `file_key`, `None` if the object is stored in plaintext
+    async fn data_key(&self, file_key: &str) -> Result<Option<LessSafeKey>, StorageError> {
+        match self.data_keys.get(file_key).await.map_err(key_store_error)? {
+            Some(wrapped) => Ok(Some(aes_key(&self.keyring.unwrap(file_key, &wrapped)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Data key to write `file_key` with, created for objects that have none yet
+    async fn write_key(&self, file_key: &str) -> Result<LessSafeKey, StorageError> {
+        if let Some(key) = self.data_key(file_key).await? {
+            return Ok(key);
+        }
+        let data_key = self.keyring.random_bytes::<KEY_LEN>()?;
+        let wrapped = self.keyring.wrap(file_key, &data_key)?;
+        // A concurrent writer may have created a key first; everyone uses the stored one
+        let stored = self.data_keys.insert(file_key, &wrapped).await.map_err(key_store_error)?;
+        aes_key(&self.keyring.unwrap(file_key, &stored)?)
+    }
+}
+
+/// A read of a plaintext range, or of the whole object, translated to the segments holding it
+struct SegmentRead {
+    range: Option<ByteRange>,
+    /// Position of the first segment read
+    first_segment: u64,
+    /// Plaintext bytes to drop from the first segment, before the range starts
+    skip: usize,
+    /// Encrypted bytes to fetch: the segments covering the range, plus the first byte of the
+    /// segment after them, which tells whether the last of them is the final one
+    encrypted_range: Option<ByteRange>,
+}
+
+impl SegmentRead {
+    fn new(range: Option<ByteRange>) -> Self {
+        let Some(range) = range else {
+            return Self {
+                range,
+                first_segment: 0,
+                skip: 0,
+                encrypted_range: None,
+            };
+        };
+        let first = range.start / SEGMENT_SIZE as u64;
+        let last = range.end / SEGMENT_SIZE as u64;
+        Self {
+            range: Some(range),
+            first_segment: first,
+            skip: (range.start - first * SEGMENT_SIZE as u64) as usize,
+            encrypted_range: Some(ByteRange {
+                start: first * ENCRYPTED_SEGMENT_SIZE as u64,
+                end: (last + 1) * ENCRYPTED_SEGMENT_SIZE as u64,
+            }),
+        }
+    }
+
+    /// Decrypt `object`, fetched for `encrypted_range`, into the plaintext that was asked for
+    fn decrypt(self, object: ObjectBody, data_key: LessSafeKey) -> ObjectBody {
+        // The byte beyond the requested segments is only looked at, never decrypted
+        let segments_size = self.encrypted_range.map_or(object.content_length, |encrypted_range| {
+            object.content_length.min(encrypted_range.length() - 1)
+        });
+        let available = plaintext_size(segments_size).saturating_sub(self.skip as u64);
+        let content_length = self.range.map_or(available, |range| range.length().min(available));
+
+        let decryptor = Decryptor {
+            body: object.body,
+            key: data_key,
+            buffer: BytesMut::with_capacity(ENCRYPTED_SEGMENT_SIZE + 1),
+            segment: self.first_segment,
+            skip: self.skip,
+            remaining: content_length,
+            // Reading the whole object includes checking that it ends with its final segment
+            final_pending: self.range.is_none(),
+        };
+        let body = stream::unfold(decryptor, |mut decryptor| async move {
+            decryptor.next_chunk().await.map(|chunk| (chunk, decryptor))
+        });
+
+        ObjectBody {
+            body: Box::pin(body),
+            content_length,
+        }
+    }
+}
+
+/// Decrypts the segments of an object as they are streamed from the backend
+struct Decryptor {
+    body: ObjectStream,
+    key: LessSafeKey,
+    buffer: BytesMut,
+    /// Position of the next segment within the object
+    segment: u64,
+    /// Plaintext bytes to drop from the next segment, before the requested range starts
+    skip: usize,
+    /// Plaintext bytes still to be returned
+    remaining: u64,
+    /// Whether the final segment has yet to be read, even once every plaintext byte was returned
+    final_pending: bool,
+}
+
+impl Decryptor {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, StorageError>> {
+        if self.remaining == 0 && !self.final_pending {
+            return None;
+        }
+        let chunk = self.decrypt_segment().await;
+        if chunk.is_err() {
+            self.remaining = 0;
+            self.final_pending = false;
+        }
+        Some(chunk)
+    }
+
+    async fn decrypt_segment(&mut self) -> Result<Bytes, StorageError> {
+        // Reading one byte beyond the segment tells whether another one follows it
+        while self.buffer.len() <= ENCRYPTED_SEGMENT_SIZE {
+            match self.body.next().await {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk?),
+                None => break,
+            }
+        }
+        if self.buffer.len() < SEGMENT_OVERHEAD {
+            return Err(encryption_error("encrypted object is truncated"));
+        }
+
+        // The segment the object ends with must be its final one, and no segment may follow that
+        let last = self.buffer.len() <= ENCRYPTED_SEGMENT_SIZE;
+        let mut segment = self.buffer.split_to(self.buffer.len().min(ENCRYPTED_SEGMENT_SIZE));
+        let nonce = Nonce::try_assume_unique_for_key(&segment[..NONCE_LEN]).map_err(|_| encryption_error("invalid nonce"))?;
+        let aad = segment_aad(self.segment, last);
+        let plaintext_len = self.key
+            .open_in_place(nonce, Aad::from(aad), &mut segment[NONCE_LEN..])
+            .map_err(|_| encryption_error(format!("segment {} of encrypted object failed authentication", self.segment)))?
+            .len();
+        self.segment += 1;
+        if last {
+            self.final_pending = false;
+        }
+
+        segment.advance(NONCE_LEN);
+        segment.truncate(plaintext_len);
+        segment.advance(self.skip.min(plaintext_len));
+        self.skip = 0;
+        segment.truncate(self.remaining.min(segment.len() as u64) as usize);
+        self.remaining -= segment.len() as u64;
+        Ok(segment.freeze())
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for EncryptedStorage {
+    async fn put_object(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let data_key = self.write_key(key).await?;
+        let encrypted = encrypt(&self.keyring.random, &data_key, 0, &content, true)?;
+        self.inner.put_object(key, encrypted, content_type).await
+    }
+
+    async fn get_object(&self, key: &str, range: Option<ByteRange>) -> Result<ObjectBody, StorageError> {
+        let Some(data_key) = self.data_key(key).await? else {
+            return self.inner.get_object(key, range).await;
+        };
+
+        // Only the segments covering the range are fetched
+        let read = SegmentRead::new(range);
+        let object = self.inner.get_object(key, read.encrypted_range).await?;
+        Ok(read.decrypt(object, data_key))
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete_object(key).await?;
+        self.data_keys.delete(key).await.map_err(key_store_error)
+    }
+
+    async fn list_objects(&self) -> Result<Vec<String>, StorageError> {
+        self.inner.list_objects().await
+    }
+
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, StorageError> {
+        let mut info = self.inner.head_object(key).await?;
+        if self.data_keys.get(key).await.map_err(key_store_error)?.is_some() {
+            info.size = plaintext_size(info.size);
+        }
+        Ok(info)
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, StorageError> {
+        self.write_key(key).await?;
+        self.inner.create_multipart_upload(key, content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        content: Vec<u8>,
+        last: bool,
+    ) -> Result<CompletedPart, StorageError> {
+        // Uploads started before encryption was enabled are finished in plaintext
+        let Some(data_key) = self.data_key(key).await? else {
+            return self.inner.upload_part(key, upload_id, part_number, content, last).await;
+        };
+
+        // Segment positions assume that every part but the last is exactly one part size
+        if content.len() > MULTIPART_PART_SIZE || (!last && content.len() != MULTIPART_PART_SIZE) {
+            return Err(encryption_error(format!(
+                "every part of an encrypted object but the last must be {} bytes",
+                MULTIPART_PART_SIZE
+            )));
+        }
+        let first_segment = (part_number as u64).saturating_sub(1) * SEGMENTS_PER_PART;
+        let encrypted = encrypt(&self.keyring.random, &data_key, first_segment, &content, last)?;
+        self.inner.upload_part(key, upload_id, part_number, encrypted, last).await
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), StorageError> {
+        self.inner.complete_multipart_upload(key, upload_id, parts).await
+    }
+
+    async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.inner.abort_multipart_upload(key, upload_id).await?;
+        // The data key created for the upload is only kept if an object by that key exists
+        if let Err(StorageError::NotFound(_)) = self.inner.head_object(key).await {
+            self.data_keys.delete(key).await.map_err(key_store_error)?;
+        }
+        Ok(())
+    }
+
+    async fn presign_put(
+        &self,
+        _key: &str,
+        _content_type: Option<&str>,
+        _expires_in: Duration,
+    ) -> Result<PresignedRequest, StorageError> {
+        Err(StorageError::Unsupported("presigned uploads with encryption enabled".to_string()))
+    }
+
+    async fn presign_get(
+        &self,
+        key: &str,
+        filename: &str,
+        expires_in: Duration,
+    ) -> Result<PresignedRequest, StorageError> {
+        if self.data_keys.get(key).await.map_err(key_store_error)?.is_some() {
+            return Err(StorageError::Unsupported("presigned downloads of encrypted objects".to_string()));
+        }
+        self.inner.presign_get(key, filename, expires_in).await
+    }
+}
+
+/// Outcome of re-wrapping data keys under the active master key
+pub struct RewrapReport {
+    pub rewrapped: u64,
+    /// Objects whose data key could not be unwrapped, e.g. because its master key is missing
+    pub failed: Vec<(String, StorageError)>,
+}
+
+/// Re-wrap every data key that is not wrapped by the active master key yet.
+///
+/// Only the wrapped keys in PostgreSQL change; object bodies are neither read nor rewritten.
+/// Once no key is left wrapped by a retired master key, it can be removed from the configuration.
+pub async fn rewrap_data_keys(config: &EncryptionConfig, database_url: &str) -> Result<RewrapReport, StorageError> {
+    let keyring = Keyring::new(config)?;
+    let data_keys = DataKeyStore::connect(database_url).await.map_err(key_store_error)?;
+    let mut report = RewrapReport {
+        rewrapped: 0,
+        failed: Vec::new(),
+    };
+
+    let mut after = String::new();
+    loop {
+        let batch = data_keys.wrapped_by_others(&keyring.active, &after).await.map_err(key_store_error)?;
+        let Some((last, _)) = batch.last() else {
+            return Ok(report);
+        };
+        after = last.clone();
+
+        for (file_key, wrapped) in batch {
+            let rewrapped = match keyring
+                .unwrap(&file_key, &wrapped)
+                .and_then(|data_key| keyring.wrap(&file_key, &data_key))
+            {
+                Ok(rewrapped) => rewrapped,
+                Err(e) => {
+                    report.failed.push((file_key, e));
+                    continue;
+                }
+            };
+            if data_keys.replace(&file_key, &wrapped.master_key_id, &rewrapped).await.map_err(key_store_error)? {
+                report.rewrapped += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> LessSafeKey {
+        aes_key(&[7u8; KEY_LEN]).unwrap()
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn encrypted(content: &[u8]) -> Vec<u8> {
+        encrypt(&SystemRandom::new(), &test_key(), 0, content, true).unwrap()
+    }
+
+    /// Read `range` of the encrypted object `stored` like `get_object` does, from a backend that
+    /// serves ranges clamped to the object and in small chunks
+    async fn read(stored: &[u8], range: Option<ByteRange>) -> Result<Vec<u8>, StorageError> {
+        let read = SegmentRead::new(range);
+        let fetched = match read.encrypted_range {
+            Some(range) if range.start >= stored.len() as u64 => Vec::new(),
+            Some(range) => stored[range.start as usize..stored.len().min(range.end as usize + 1)].to_vec(),
+            None => stored.to_vec(),
+        };
+        let object = ObjectBody {
+            content_length: fetched.len() as u64,
+            body: Box::pin(stream::iter(
+                fetched.chunks(1000).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect::<Vec<_>>(),
+            )),
+        };
+
+        let mut body = read.decrypt(object, test_key());
+        let mut plaintext = Vec::new();
+        while let Some(chunk) = body.body.next().await {
+            plaintext.extend_from_slice(&chunk?);
+        }
+        assert_eq!(plaintext.len() as u64, body.content_length);
+        Ok(plaintext)
+    }
+
+    #[tokio::test]
+    async fn round_trips_objects_of_every_shape() {
+        for len in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE, 3 * SEGMENT_SIZE + 17] {
+            let content = content(len);
+            let stored = encrypted(&content);
+            assert_eq!(plaintext_size(stored.len() as u64), len as u64);
+            assert_eq!(read(&stored, None).await.unwrap(), content, "length {}", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_objects_encrypted_in_parts() {
+        let content = content(5 * SEGMENT_SIZE + 9);
+        let random = SystemRandom::new();
+        let mut stored = encrypt(&random, &test_key(), 0, &content[..2 * SEGMENT_SIZE], false).unwrap();
+        stored.extend(encrypt(&random, &test_key(), 2, &content[2 * SEGMENT_SIZE..], true).unwrap());
+        assert_eq!(read(&stored, None).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn rejects_objects_cut_at_a_segment_boundary() {
+        let stored = encrypted(&content(3 * SEGMENT_SIZE + 17));
+        for segments in 0..=3 {
+            let truncated = &stored[..segments * ENCRYPTED_SEGMENT_SIZE];
+            assert!(read(truncated, None).await.is_err(), "{} segments", segments);
+        }
+        let stored = encrypted(&content(2 * SEGMENT_SIZE));
+        assert!(read(&stored[..ENCRYPTED_SEGMENT_SIZE], None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_objects_cut_within_a_segment() {
+        let stored = encrypted(&content(2 * SEGMENT_SIZE + 17));
+        assert!(read(&stored[..stored.len() - 1], None).await.is_err());
+        assert!(read(&stored[..ENCRYPTED_SEGMENT_SIZE + 10], None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_segments_after_the_final_one() {
+        let content = content(SEGMENT_SIZE + 5);
+        let random = SystemRandom::new();
+        let mut stored = encrypted(&content);
+        stored.extend(encrypt(&random, &test_key(), 2, b"appended", true).unwrap());
+        assert!(read(&stored, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_segments() {
+        let mut stored = encrypted(&content(SEGMENT_SIZE + 5));
+        stored[NONCE_LEN + 3] ^= 1;
+        assert!(read(&stored, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn decrypts_ranges() {
+        let content = content(4 * SEGMENT_SIZE + 100);
+        let stored = encrypted(&content);
+        let len = content.len() as u64;
+        let segment = SEGMENT_SIZE as u64;
+        for (start, end) in [
+            (0, 0),
+            (0, len - 1),
+            (5, 10),
+            (segment - 1, segment),
+            (segment, 2 * segment - 1),
+            (segment + 3, 3 * segment + 7),
+            (4 * segment, len - 1),
+            (len - 1, len - 1),
+        ] {
+            let range = ByteRange { start, end };
+            let plaintext = read(&stored, Some(range)).await.unwrap();
+            assert_eq!(plaintext, &content[start as usize..=end as usize], "range {}-{}", start, end);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_ranges_reaching_a_cut_end() {
+        let content = content(4 * SEGMENT_SIZE + 100);
+        let stored = encrypted(&content);
+        let truncated = &stored[..3 * ENCRYPTED_SEGMENT_SIZE];
+        let range = ByteRange {
+            start: 2 * SEGMENT_SIZE as u64,
+            end: content.len() as u64 - 1,
+        };
+        assert!(read(truncated, Some(range)).await.is_err());
+
+        // Ranges ending before the cut only read segments that are intact
+        let range = ByteRange { start: 0, end: 10 };
+        assert_eq!(read(truncated, Some(range)).await.unwrap(), &content[..=10]);
+    }
+
+    #[tokio::test]
+    async fn rejects_objects_without_a_final_segment() {
+        // As left behind by an upload whose last part never arrived
+        let content = content(2 * SEGMENT_SIZE);
+        let stored = encrypt(&SystemRandom::new(), &test_key(), 0, &content, false).unwrap();
+        assert!(read(&stored, None).await.is_err());
+        let range = ByteRange { start: 0, end: 10 };
+        assert_eq!(read(&stored, Some(range)).await.unwrap(), &content[..=10]);
+    }
+}
@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use super::{ByteRange, CompletedPart, ObjectBody, ObjectInfo, ObjectStorage, StorageError};
+
+/// Directory below the root where parts of unfinished multipart uploads are staged
+const MULTIPART_DIR: &str = ".multipart";
+
+/// Object storage backed by a directory on the local disk.
+///
+/// Content types are not persisted, so `head_object` always reports `None`.
+pub struct LocalFsStorage {
+    root: PathBuf,
+}
+
+impl LocalFsStorage {
+    /// Create a new LocalFsStorage rooted at `root`, creating the directory if needed
+    pub async fn new(root: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    /// Map an object key to a path below the root, rejecting keys that would escape it
+    fn object_path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let relative = Path::new(key);
+        let is_plain = !key.is_empty()
+            && relative.components().all(|c| matches!(c, Component::Normal(_)))
+            && !relative.starts_with(MULTIPART_DIR);
+        if !is_plain {
+            return Err(StorageError::ConfigurationError(format!("Invalid object key: {}", key)));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Staging directory for the parts of a multipart upload
+    fn upload_dir(&self, upload_id: &str) -> Result<PathBuf, StorageError> {
+        if Uuid::parse_str(upload_id).is_err() {
+            return Err(StorageError::NotFound(format!("upload {}", upload_id)));
+        }
+        Ok(self.root.join(MULTIPART_DIR).join(upload_id))
+    }
+}
+
+fn not_found_or_io(key: &str, e: std::io::Error) -> StorageError {
+    if e.kind() == ErrorKind::NotFound {
+        StorageError::NotFound(key.to_string())
+    } else {
+        e.into()
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for LocalFsStorage {
+    async fn put_object(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        _content_type: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let path = self.object_path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, content).await?;
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str, range: Option<ByteRange>) -> Result<ObjectBody, StorageError> {
+        let path = self.object_path(key)?;
+        let mut file = fs::File::open(&path).await.map_err(|e| not_found_or_io(key, e))?;
+        let size = file.metadata().await?.len();
+
+        let (start, length) = match range {
+            Some(range) => (range.start.min(size), range.length().min(size.saturating_sub(range.start))),
+            None => (0, size),
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let body = ReaderStream::new(file.take(length)).map(|chunk| chunk.map_err(StorageError::from));
+
+        Ok(ObjectBody {
+            body: Box::pin(body),
+            content_length: length,
+        })
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), StorageError> {
+        let path = self.object_path(key)?;
+        match fs::remove_file(&path).await {
+            // Deleting a missing object is not an error, matching S3 semantics
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list_objects(&self) -> Result<Vec<String>, StorageError> {
+        let mut keys = Vec::new();
+        let mut pending = vec![self.root.clone()];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path == self.root.join(MULTIPART_DIR) {
+                    continue;
+                }
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                    keys.push(relative.to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, StorageError> {
+        let path = self.object_path(key)?;
+        let metadata = fs::metadata(&path).await.map_err(|e| not_found_or_io(key, e))?;
+
+        Ok(ObjectInfo {
+            size: metadata.len(),
+            content_type: None,
+            last_modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        })
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        _content_type: Option<&str>,
+    ) -> Result<String, StorageError> {
+        self.object_path(key)?;
+        let upload_id = Uuid::new_v4().to_string();
+        fs::create_dir_all(self.upload_dir(&upload_id)?).await?;
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        content: Vec<u8>,
+        _last: bool,
+    ) -> Result<CompletedPart, StorageError> {
+        let dir = self.upload_dir(upload_id)?;
+        fs::write(dir.join(part_number.to_string()), content)
+            .await
+            .map_err(|e| not_found_or_io(key, e))?;
+
+        Ok(CompletedPart {
+            part_number,
+            etag: part_number.to_string(),
+        })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), StorageError> {
+        let dir = self.upload_dir(upload_id)?;
+        let path = self.object_path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut output = fs::File::create(&path).await?;
+        for part in parts {
+            let mut input = fs::File::open(dir.join(part.part_number.to_string()))
+                .await
+                .map_err(|e| not_found_or_io(key, e))?;
+            tokio::io::copy(&mut input, &mut output).await?;
+        }
+        output.sync_all().await?;
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(&self, _key: &str, upload_id: &str) -> Result<(), StorageError> {
+        match fs::remove_dir_all(self.upload_dir(upload_id)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_stored_objects() {
+        let root = std::env::temp_dir().join(format!("local-fs-test-{}", Uuid::new_v4()));
+        let storage = LocalFsStorage::new(&root).await.unwrap();
+        assert!(storage.list_objects().await.unwrap().is_empty());
+
+        storage.put_object("b.txt", b"b".to_vec(), None).await.unwrap();
+        storage.put_object("a/c.txt", b"c".to_vec(), None).await.unwrap();
+        storage.put_object("a.txt", b"a".to_vec(), None).await.unwrap();
+        // Parts staged for unfinished multipart uploads are not listed
+        let upload_id = storage.create_multipart_upload("pending.txt", None).await.unwrap();
+        storage.upload_part("pending.txt", &upload_id, 1, b"part".to_vec(), true).await.unwrap();
+        assert_eq!(storage.list_objects().await.unwrap(), ["a.txt", "a/c.txt", "b.txt"]);
+
+        storage.delete_object("a.txt").await.unwrap();
+        assert_eq!(storage.list_objects().await.unwrap(), ["a/c.txt", "b.txt"]);
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use uuid::Uuid;
+
+use super::{ByteRange, CompletedPart, ObjectBody, ObjectInfo, ObjectStorage, StorageError};
+
+struct StoredObject {
+    content: Bytes,
+    content_type: Option<String>,
+    last_modified: DateTime<Utc>,
+}
+
+/// Object storage kept entirely in process memory; everything is lost on restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<HashMap<String, StoredObject>>,
+    multipart_uploads: RwLock<HashMap<String, PendingUpload>>,
+}
+
+struct PendingUpload {
+    key: String,
+    content_type: Option<String>,
+    parts: BTreeMap<i32, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for MemoryStorage {
+    async fn put_object(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let mut objects = self.objects.write().unwrap();
+        objects.insert(
+            key.to_string(),
+            StoredObject {
+                content: Bytes::from(content),
+                content_type: content_type.map(|ct| ct.to_string()),
+                last_modified: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str, range: Option<ByteRange>) -> Result<ObjectBody, StorageError> {
+        let content = {
+            let objects = self.objects.read().unwrap();
+            objects
+                .get(key)
+                .map(|obj| obj.content.clone())
+                .ok_or_else(|| StorageError::NotFound(key.to_string()))?
+        };
+
+        let content = match range {
+            Some(range) => {
+                let size = content.len() as u64;
+                let start = range.start.min(size) as usize;
+                let end = (range.end + 1).min(size) as usize;
+                content.slice(start..end.max(start))
+            }
+            None => content,
+        };
+
+        Ok(ObjectBody {
+            content_length: content.len() as u64,
+            body: Box::pin(futures_util::stream::once(async move { Ok(content) })),
+        })
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), StorageError> {
+        self.objects.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list_objects(&self) -> Result<Vec<String>, StorageError> {
+        let objects = self.objects.read().unwrap();
+        let mut keys: Vec<String> = objects.keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, StorageError> {
+        let objects = self.objects.read().unwrap();
+        let obj = objects
+            .get(key)
+            .ok_or_else(|| StorageError::NotFound(key.to_string()))?;
+
+        Ok(ObjectInfo {
+            size: obj.content.len() as u64,
+            content_type: obj.content_type.clone(),
+            last_modified: Some(obj.last_modified),
+        })
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, StorageError> {
+        let upload_id = Uuid::new_v4().to_string();
+        self.multipart_uploads.write().unwrap().insert(
+            upload_id.clone(),
+            PendingUpload {
+                key: key.to_string(),
+                content_type: content_type.map(|ct| ct.to_string()),
+                parts: BTreeMap::new(),
+            },
+        );
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        content: Vec<u8>,
+        _last: bool,
+    ) -> Result<CompletedPart, StorageError> {
+        let mut uploads = self.multipart_uploads.write().unwrap();
+        let upload = uploads
+            .get_mut(upload_id)
+            .filter(|upload| upload.key == key)
+            .ok_or_else(|| StorageError::NotFound(format!("{} (upload {})", key, upload_id)))?;
+        upload.parts.insert(part_number, content);
+
+        Ok(CompletedPart {
+            part_number,
+            etag: part_number.to_string(),
+        })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), StorageError> {
+        let mut upload = self.multipart_uploads
+            .write()
+            .unwrap()
+            .remove(upload_id)
+            .filter(|upload| upload.key == key)
+            .ok_or_else(|| StorageError::NotFound(format!("{} (upload {})", key, upload_id)))?;
+
+        let mut content = Vec::new();
+        for part in parts {
+            let data = upload.parts.remove(&part.part_number).ok_or_else(|| {
+                StorageError::NotFound(format!("{} (part {})", key, part.part_number))
+            })?;
+            content.extend_from_slice(&data);
+        }
+
+        self.put_object(key, content, upload.content_type.as_deref()).await
+    }
+
+    async fn abort_multipart_upload(&self, _key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.multipart_uploads.write().unwrap().remove(upload_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_stored_objects() {
+        let storage = MemoryStorage::new();
+        assert!(storage.list_objects().await.unwrap().is_empty());
+
+        storage.put_object("b.txt", b"b".to_vec(), None).await.unwrap();
+        storage.put_object("a/c.txt", b"c".to_vec(), None).await.unwrap();
+        storage.put_object("a.txt", b"a".to_vec(), None).await.unwrap();
+        // Unfinished multipart uploads are not objects yet
+        storage.create_multipart_upload("pending.txt", None).await.unwrap();
+        assert_eq!(storage.list_objects().await.unwrap(), ["a.txt", "a/c.txt", "b.txt"]);
+
+        storage.delete_object("a.txt").await.unwrap();
+        assert_eq!(storage.list_objects().await.unwrap(), ["a/c.txt", "b.txt"]);
+    }
+}
@@ -0,0 +1,2052 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Error, NoTls, Row};
+use uuid::Uuid;
+
+use super::{CompletedPart, FileMetadata, ObjectStorage, StorageError};
+
+/// Columns selected to build a `FileMetadata`, in the order `file_from_row` expects
+const FILE_COLUMNS: &str = "f.file_id, f.filename, v.size, v.content_type, v.upload_time, v.file_key, v.content_hash, \
+    v.version, f.folder_id, f.description, \
+    ARRAY(SELECT tag FROM file_tags t WHERE t.file_id = f.file_id ORDER BY tag), \
+    ARRAY(SELECT key FROM file_attributes a WHERE a.file_id = f.file_id ORDER BY key), \
+    ARRAY(SELECT value FROM file_attributes a WHERE a.file_id = f.file_id ORDER BY key), v.content_encoding, \
+    f.workspace_id, f.expires_at";
+
+/// Files joined with their versions, aliased as `f` and `v`
+const FILE_VERSIONS: &str = "user_files f JOIN file_versions v ON v.file_id = f.file_id";
+
+/// Restricts `FILE_VERSIONS` to the current version of each file
+const CURRENT_VERSION: &str = "v.version = f.current_version";
+
+/// Restricts `user_files f` to files that are not in the trash
+const NOT_TRASHED: &str = "f.deleted_at IS NULL";
+
+/// Restricts `user_files f` to files that have not passed their expiry time
+const NOT_EXPIRED: &str = "(f.expires_at IS NULL OR f.expires_at > NOW())";
+
+/// Statement tail releasing the versions deleted by a preceding `removed` CTE.
+///
+/// Shared objects lose one reference per removed version and objects without a content hash
+/// are queued for removal. Each removed version is returned as `file_key, content_hash` with
+/// the remaining reference count of its shared object.
+const RELEASE_REMOVED_VERSIONS: &str = ", released AS (
+         UPDATE storage_objects SET ref_count = storage_objects.ref_count - removed_refs.refs
+         FROM (
+             SELECT content_hash, COUNT(*) AS refs FROM removed
+             WHERE content_hash IS NOT NULL GROUP BY content_hash
+         ) removed_refs
+         WHERE storage_objects.content_hash = removed_refs.content_hash
+         RETURNING storage_objects.content_hash, storage_objects.ref_count
+     ), queued AS (
+         INSERT INTO pending_object_deletions (file_key)
+         SELECT file_key FROM removed WHERE content_hash IS NULL
+         ON CONFLICT (file_key) DO NOTHING
+     )
+     SELECT removed.file_key, removed.content_hash, released.ref_count
+     FROM removed LEFT JOIN released ON released.content_hash = removed.content_hash";
+
+/// A stored file together with the user owning it
+pub struct FileRecord {
+    pub user_id: i64,
+    pub metadata: FileMetadata,
+}
+
+/// Who a file belongs to: the user who uploaded it, or a workspace shared by its members
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileOwner {
+    User(i64),
+    Workspace(String),
+}
+
+impl FileOwner {
+    /// The owner of `file`, uploaded by `user_id`
+    pub fn of(file: &FileMetadata, user_id: i64) -> Self {
+        match &file.workspace_id {
+            Some(workspace_id) => FileOwner::Workspace(workspace_id.clone()),
+            None => FileOwner::User(user_id),
+        }
+    }
+
+    /// Condition selecting the files of this owner from `user_files f`, given `param` as `$1`
+    fn condition(&self) -> &'static str {
+        match self {
+            FileOwner::User(_) => "f.user_id = $1 AND f.workspace_id IS NULL",
+            FileOwner::Workspace(_) => "f.workspace_id = $1",
+        }
+    }
+
+    fn param(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            FileOwner::User(user_id) => user_id,
+            FileOwner::Workspace(workspace_id) => workspace_id,
+        }
+    }
+}
+
+/// Criteria a listed file has to match; empty criteria match every file
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    /// Tags the file must all have
+    pub tags: Vec<String>,
+    /// Attributes the file must all have with exactly these values
+    pub attributes: Vec<(String, String)>,
+    /// Case-insensitive substring of the filename
+    pub name_contains: Option<String>,
+    /// Case-insensitive prefix of the content type, e.g. `image/`
+    pub content_type_prefix: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Inclusive lower bound of the upload time
+    pub uploaded_after: Option<DateTime<Utc>>,
+    /// Exclusive upper bound of the upload time
+    pub uploaded_before: Option<DateTime<Utc>>,
+}
+
+/// Field a file listing is ordered by; ties are broken by the order files were created in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSort {
+    Name,
+    Size,
+    /// Upload time of the current version
+    #[default]
+    Date,
+}
+
+/// Sort key of the last file on a page
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum CursorKey {
+    Name(String),
+    Size(i64),
+    Date(DateTime<Utc>),
+}
+
+/// Position of the last file on a listing page, from which the next page continues.
+///
+/// Handed to clients as an opaque string; it is only valid for the same sort order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileCursor {
+    pub sort: FileSort,
+    pub descending: bool,
+    key: CursorKey,
+    /// `user_files.id` of the last file, breaking ties between equal sort keys
+    row_id: i64,
+}
+
+impl FileCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// Decode a cursor produced by `encode`, or `None` if it was tampered with
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        let cursor: Self = serde_json::from_slice(&bytes).ok()?;
+        let key_matches = matches!(
+            (cursor.sort, &cursor.key),
+            (FileSort::Name, CursorKey::Name(_)) | (FileSort::Size, CursorKey::Size(_)) | (FileSort::Date, CursorKey::Date(_))
+        );
+        key_matches.then_some(cursor)
+    }
+}
+
+/// Order and page of a file listing
+#[derive(Debug)]
+pub struct FilePage {
+    pub sort: FileSort,
+    pub descending: bool,
+    /// Maximum number of files returned
+    pub limit: i64,
+    /// Continue after this position instead of starting from the beginning
+    pub after: Option<FileCursor>,
+}
+
+/// Escape `%`, `_` and `\` so that `text` matches literally in a `LIKE` pattern
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// An upload handed out as a presigned URL, waiting for the client to report completion
+pub struct PendingUpload {
+    pub file_id: String,
+    pub user_id: i64,
+    pub file_key: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub folder_id: Option<String>,
+}
+
+/// A resumable upload in progress through the tus protocol
+pub struct TusUpload {
+    pub upload_id: String,
+    pub user_id: i64,
+    pub file_key: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub folder_id: Option<String>,
+    /// Total size announced by the client
+    pub upload_length: u64,
+    /// Bytes received so far
+    pub upload_offset: u64,
+    /// Received bytes not yet in a part, kept in a separate tail object
+    pub tail_size: u64,
+    pub multipart_upload_id: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl TusUpload {
+    /// Bytes already uploaded as parts of the multipart upload
+    pub fn committed_size(&self) -> u64 {
+        self.upload_offset - self.tail_size
+    }
+}
+
+/// Access to a file granted to a user other than its owner; each level includes the ones before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Download the file and its versions
+    Read,
+    /// Also change its metadata, tags and attributes, and restore versions
+    Write,
+    /// Also delete it, prune its versions and manage its share links and grants
+    Manage,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Manage => "manage",
+        }
+    }
+
+    /// The permission stored as `name`; the table only admits known names, anything else reads as `Read`
+    fn from_column(name: &str) -> Self {
+        match name {
+            "manage" => Permission::Manage,
+            "write" => Permission::Write,
+            _ => Permission::Read,
+        }
+    }
+}
+
+/// A grant of access to a file, with the user it was granted to
+#[derive(Serialize, Clone)]
+pub struct FileGrant {
+    pub file_id: String,
+    pub user_id: i64,
+    pub email: String,
+    pub name: String,
+    pub permission: Permission,
+    /// User who granted the access
+    pub granted_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A file another user granted access to, as listed for the grantee
+#[derive(Serialize)]
+pub struct SharedFile {
+    #[serde(flatten)]
+    pub file: FileMetadata,
+    pub permission: Permission,
+    pub owner_email: String,
+}
+
+/// Role of a member in a workspace; each role includes the ones before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRole {
+    /// List and download the workspace's files
+    Viewer,
+    /// Also upload files and edit them
+    Member,
+    /// Also delete files, manage their sharing, and invite and manage members
+    Admin,
+    /// Also appoint admins and owners and delete the workspace
+    Owner,
+}
+
+impl WorkspaceRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WorkspaceRole::Viewer => "viewer",
+            WorkspaceRole::Member => "member",
+            WorkspaceRole::Admin => "admin",
+            WorkspaceRole::Owner => "owner",
+        }
+    }
+
+    /// The role stored as `name`; the tables only admit known names, anything else reads as `Viewer`
+    fn from_column(name: &str) -> Self {
+        match name {
+            "owner" => WorkspaceRole::Owner,
+            "admin" => WorkspaceRole::Admin,
+            "member" => WorkspaceRole::Member,
+            _ => WorkspaceRole::Viewer,
+        }
+    }
+
+    /// Access the role gives to the files of the workspace
+    pub fn permission(self) -> Permission {
+        match self {
+            WorkspaceRole::Viewer => Permission::Read,
+            WorkspaceRole::Member => Permission::Write,
+            WorkspaceRole::Admin | WorkspaceRole::Owner => Permission::Manage,
+        }
+    }
+}
+
+/// A workspace, as seen by one of its members
+#[derive(Serialize, Clone)]
+pub struct Workspace {
+    pub id: String,
+    pub name: String,
+    /// Role of the member the workspace was looked up for
+    pub role: WorkspaceRole,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A member of a workspace
+#[derive(Serialize, Clone)]
+pub struct WorkspaceMember {
+    pub user_id: i64,
+    pub email: String,
+    pub name: String,
+    pub role: WorkspaceRole,
+    pub joined_at: DateTime<Utc>,
+}
+
+/// An invitation to join a workspace, addressed to whoever signs in with `email`
+#[derive(Serialize, Clone)]
+pub struct WorkspaceInvitation {
+    pub id: String,
+    pub workspace_id: String,
+    pub workspace_name: String,
+    pub email: String,
+    /// Role the invitee gets on accepting
+    pub role: WorkspaceRole,
+    /// Member who sent the invitation
+    pub invited_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A file in the trash, waiting to be restored or purged
+#[derive(Serialize)]
+pub struct TrashedFile {
+    #[serde(flatten)]
+    pub file: FileMetadata,
+    pub deleted_at: DateTime<Utc>,
+    /// User who moved the file to the trash
+    pub deleted_by: Option<i64>,
+    /// When the file is removed for good unless it is restored first
+    pub purge_at: DateTime<Utc>,
+}
+
+/// A link giving anyone holding its token access to the current version of a file
+#[derive(Serialize, Clone)]
+pub struct ShareLink {
+    pub token: String,
+    pub file_id: String,
+    /// User who created the link
+    #[serde(skip)]
+    pub user_id: i64,
+    /// Hash of the password protecting the link, if any; clients only learn whether there is one
+    #[serde(rename = "has_password", serialize_with = "serialize_is_some")]
+    pub password_hash: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Number of downloads allowed, `None` if unlimited
+    pub max_downloads: Option<i32>,
+    pub download_count: i32,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+fn serialize_is_some<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(value.is_some())
+}
+
+/// A virtual folder; folders only exist in Postgres and never affect object keys
+#[derive(Serialize, Clone)]
+pub struct Folder {
+    pub id: String,
+    pub name: String,
+    /// Parent folder, `None` for top-level folders
+    pub parent_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A folder together with the user owning it
+pub struct FolderRecord {
+    pub user_id: i64,
+    pub folder: Folder,
+}
+
+/// Storage consumed by a user, measured against their quota
+#[derive(Debug, Serialize)]
+pub struct StorageUsage {
+    /// Size of every kept version of the user's files; content shared through deduplication counts for each file
+    pub bytes_used: u64,
+    /// Files outside the trash; trashed files still count towards `bytes_used`
+    pub file_count: u64,
+    pub trashed_file_count: u64,
+    /// `None` when the user has no limit
+    pub quota_bytes: Option<u64>,
+    pub remaining_bytes: Option<u64>,
+}
+
+/// Outcome of backfilling metadata for `user_files` rows created before it was persisted
+#[derive(Debug, Default)]
+pub struct BackfillReport {
+    pub updated: usize,
+    pub missing: Vec<String>,
+}
+
+/// Postgres-backed store for file metadata, kept in the `user_files` table
+pub struct MetadataStore {
+    client: Client,
+}
+
+fn file_from_row(row: &Row) -> FileMetadata {
+    let size: i64 = row.get(2);
+    let attribute_keys: Vec<String> = row.get(11);
+    let attribute_values: Vec<String> = row.get(12);
+    FileMetadata {
+        id: row.get(0),
+        filename: row.get(1),
+        size: size as u64,
+        content_type: row.get(3),
+        upload_time: row.get(4),
+        s3_key: row.get(5),
+        content_hash: row.get(6),
+        version: row.get(7),
+        folder_id: row.get(8),
+        description: row.get(9),
+        tags: row.get(10),
+        attributes: attribute_keys.into_iter().zip(attribute_values).collect(),
+        content_encoding: row.get(13),
+        workspace_id: row.get(14),
+        expires_at: row.get(15),
+    }
+}
+
+fn pending_upload_from_row(row: &Row) -> PendingUpload {
+    PendingUpload {
+        file_id: row.get(0),
+        user_id: row.get(1),
+        file_key: row.get(2),
+        filename: row.get(3),
+        content_type: row.get(4),
+        expires_at: row.get(5),
+        folder_id: row.get(6),
+    }
+}
+
+/// Columns selected to build a `TusUpload`, in the order `tus_upload_from_row` expects
+const TUS_UPLOAD_COLUMNS: &str = "upload_id, user_id, file_key, filename, content_type, folder_id, upload_length, \
+    upload_offset, tail_size, multipart_upload_id, expires_at";
+
+fn tus_upload_from_row(row: &Row) -> TusUpload {
+    TusUpload {
+        upload_id: row.get(0),
+        user_id: row.get(1),
+        file_key: row.get(2),
+        filename: row.get(3),
+        content_type: row.get(4),
+        folder_id: row.get(5),
+        upload_length: row.get::<_, i64>(6) as u64,
+        upload_offset: row.get::<_, i64>(7) as u64,
+        tail_size: row.get::<_, i64>(8) as u64,
+        multipart_upload_id: row.get(9),
+        expires_at: row.get(10),
+    }
+}
+
+/// Columns selected to build a `FileGrant` from `file_grants g JOIN users u`, in the order `file_grant_from_row` expects
+const FILE_GRANT_COLUMNS: &str = "g.file_id, g.user_id, u.email, u.name, g.permission, g.granted_by, g.created_at";
+
+fn file_grant_from_row(row: &Row) -> FileGrant {
+    let permission: String = row.get(4);
+    FileGrant {
+        file_id: row.get(0),
+        user_id: row.get(1),
+        email: row.get(2),
+        name: row.get(3),
+        permission: Permission::from_column(&permission),
+        granted_by: row.get(5),
+        created_at: row.get(6),
+    }
+}
+
+/// Columns selected to build a `Workspace` from `workspaces w JOIN workspace_members m`, in the order
+/// `workspace_from_row` expects
+const WORKSPACE_COLUMNS: &str = "w.workspace_id, w.name, m.role, w.created_at";
+
+fn workspace_from_row(row: &Row) -> Workspace {
+    let role: String = row.get(2);
+    Workspace {
+        id: row.get(0),
+        name: row.get(1),
+        role: WorkspaceRole::from_column(&role),
+        created_at: row.get(3),
+    }
+}
+
+/// Columns selected to build a `WorkspaceMember` from `workspace_members m JOIN users u`, in the order
+/// `workspace_member_from_row` expects
+const WORKSPACE_MEMBER_COLUMNS: &str = "m.user_id, u.email, u.name, m.role, m.joined_at";
+
+fn workspace_member_from_row(row: &Row) -> WorkspaceMember {
+    let role: String = row.get(3);
+    WorkspaceMember {
+        user_id: row.get(0),
+        email: row.get(1),
+        name: row.get(2),
+        role: WorkspaceRole::from_column(&role),
+        joined_at: row.get(4),
+    }
+}
+
+/// Columns selected to build a `WorkspaceInvitation` from `workspace_invitations i JOIN workspaces w`, in the
+/// order `workspace_invitation_from_row` expects
+const WORKSPACE_INVITATION_COLUMNS: &str = "i.invitation_id, i.workspace_id, w.name, i.email, i.role, i.invited_by, i.created_at";
+
+fn workspace_invitation_from_row(row: &Row) -> WorkspaceInvitation {
+    let role: String = row.get(4);
+    WorkspaceInvitation {
+        id: row.get(0),
+        workspace_id: row.get(1),
+        workspace_name: row.get(2),
+        email: row.get(3),
+        role: WorkspaceRole::from_column(&role),
+        invited_by: row.get(5),
+        created_at: row.get(6),
+    }
+}
+
+/// Columns selected to build a `ShareLink`, in the order `share_link_from_row` expects
+const SHARE_LINK_COLUMNS: &str = "token, file_id, user_id, password_hash, expires_at, max_downloads, download_count, \
+    revoked_at, created_at";
+
+fn share_link_from_row(row: &Row) -> ShareLink {
+    ShareLink {
+        token: row.get(0),
+        file_id: row.get(1),
+        user_id: row.get(2),
+        password_hash: row.get(3),
+        expires_at: row.get(4),
+        max_downloads: row.get(5),
+        download_count: row.get(6),
+        revoked_at: row.get(7),
+        created_at: row.get(8),
+    }
+}
+
+fn folder_from_row(row: &Row) -> Folder {
+    Folder {
+        id: row.get(0),
+        name: row.get(1),
+        parent_id: row.get(2),
+        created_at: row.get(3),
+    }
+}
+
+/// `file` as stored, given a row of `file_id, version, file_key, content_encoding` returned by an insert
+fn stored_file(file: &FileMetadata, row: &Row) -> FileMetadata {
+    FileMetadata {
+        id: row.get(0),
+        version: row.get(1),
+        s3_key: row.get(2),
+        content_encoding: row.get(3),
+        ..file.clone()
+    }
+}
+
+impl MetadataStore {
+    pub async fn new(database_url: &str) -> Result<Self, Error> {
+        let (client, connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("Metadata store connection error: {}", e);
+            }
+        });
+
+        Ok(Self { client })
+    }
+
+    /// Record a file newly uploaded by `user_id` and return it as stored.
+    ///
+    /// The file belongs to the workspace named by `file.workspace_id`, or to the user if unset.
+    /// Uploading a filename its owner already has in the same folder adds a new current version
+    /// to that file instead of creating another one, so the returned id and version may differ
+    /// from `file`'s. Expired files that were not swept yet are not reused.
+    ///
+    /// Files with a content hash take a reference on the shared object for that hash. When the
+    /// content was already stored, the returned `s3_key` and `content_encoding` are the existing
+    /// object's and the caller should discard the object it just uploaded.
+    pub async fn insert_file(&self, user_id: i64, file: &FileMetadata) -> Result<FileMetadata, Error> {
+        let owner = FileOwner::of(file, user_id);
+        let query = format!(
+            "SELECT f.file_id FROM user_files f
+             WHERE {} AND {} AND {} AND f.filename = $2 AND f.folder_id IS NOT DISTINCT FROM $3 AND f.file_id IS NOT NULL
+             ORDER BY f.id DESC LIMIT 1",
+            owner.condition(),
+            NOT_TRASHED,
+            NOT_EXPIRED
+        );
+        let existing = self.client.query_opt(&query, &[owner.param(), &file.filename, &file.folder_id]).await?;
+
+        if let Some(existing) = existing {
+            let file_id: String = existing.get(0);
+            // A file deleted or expired in the meantime gets no new version; it is created afresh below
+            if let Some(mut stored) = self.add_version(&file_id, file).await? {
+                // The new version belongs to a file that may already be described, tagged and expiring
+                if let Some(record) = self.get_file(&file_id).await? {
+                    stored.description = record.metadata.description;
+                    stored.tags = record.metadata.tags;
+                    stored.attributes = record.metadata.attributes;
+                    stored.expires_at = record.metadata.expires_at;
+                }
+                return Ok(stored);
+            }
+        }
+
+        let row = self.client
+            .query_one(
+                "WITH file AS (
+                     INSERT INTO user_files (user_id, file_id, filename, folder_id, workspace_id, expires_at, current_version, latest_version)
+                     VALUES ($1, $2, $3, $9, $11, $12, 1, 1)
+                     RETURNING file_id, current_version
+                 ), object AS (
+                     INSERT INTO storage_objects (content_hash, file_key, size, ref_count, content_encoding)
+                     SELECT $8, $4, $5, 1, $10 WHERE $8::VARCHAR IS NOT NULL
+                     ON CONFLICT (content_hash) DO UPDATE SET ref_count = storage_objects.ref_count + 1
+                     RETURNING file_key, content_encoding
+                 )
+                 INSERT INTO file_versions (file_id, version, file_key, size, content_type, upload_time, content_hash, content_encoding)
+                 SELECT file_id, current_version, COALESCE((SELECT file_key FROM object), $4), $5, $6, $7, $8,
+                        CASE WHEN EXISTS (SELECT 1 FROM object) THEN (SELECT content_encoding FROM object) ELSE $10 END
+                 FROM file
+                 RETURNING file_id, version, file_key, content_encoding",
+                &[
+                    &user_id,
+                    &file.id,
+                    &file.filename,
+                    &file.s3_key,
+                    &(file.size as i64),
+                    &file.content_type,
+                    &file.upload_time,
+                    &file.content_hash,
+                    &file.folder_id,
+                    &file.content_encoding,
+                    &file.workspace_id,
+                    &file.expires_at,
+                ],
+            )
+            .await?;
+        Ok(stored_file(file, &row))
+    }
+
+    /// Add `file`'s content as the new current version of the file `file_id`.
+    ///
+    /// The file keeps its expiry time unless `file` sets one. Returns `None` if the file has expired.
+    async fn add_version(&self, file_id: &str, file: &FileMetadata) -> Result<Option<FileMetadata>, Error> {
+        let row = self.client
+            .query_opt(
+                "WITH file AS (
+                     UPDATE user_files SET latest_version = latest_version + 1, current_version = latest_version + 1,
+                         expires_at = COALESCE($8, expires_at)
+                     WHERE file_id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+                     RETURNING file_id, current_version
+                 ), object AS (
+                     INSERT INTO storage_objects (content_hash, file_key, size, ref_count, content_encoding)
+                     SELECT $6, $2, $3, 1, $7 FROM file WHERE $6::VARCHAR IS NOT NULL
+                     ON CONFLICT (content_hash) DO UPDATE SET ref_count = storage_objects.ref_count + 1
+                     RETURNING file_key, content_encoding
+                 )
+                 INSERT INTO file_versions (file_id, version, file_key, size, content_type, upload_time, content_hash, content_encoding)
+                 SELECT file_id, current_version, COALESCE((SELECT file_key FROM object), $2), $3, $4, $5, $6,
+                        CASE WHEN EXISTS (SELECT 1 FROM object) THEN (SELECT content_encoding FROM object) ELSE $7 END
+                 FROM file
+                 RETURNING file_id, version, file_key, content_encoding",
+                &[
+                    &file_id,
+                    &file.s3_key,
+                    &(file.size as i64),
+                    &file.content_type,
+                    &file.upload_time,
+                    &file.content_hash,
+                    &file.content_encoding,
+                    &file.expires_at,
+                ],
+            )
+            .await?;
+        Ok(row.map(|row| stored_file(file, &row)))
+    }
+
+    /// Look up the current version of a file by id, together with its owner; files in the trash are not found
+    pub async fn get_file(&self, file_id: &str) -> Result<Option<FileRecord>, Error> {
+        let query = format!(
+            "SELECT {}, f.user_id FROM {} WHERE f.file_id = $1 AND {} AND {}",
+            FILE_COLUMNS, FILE_VERSIONS, NOT_TRASHED, CURRENT_VERSION
+        );
+        let row = self.client.query_opt(&query, &[&file_id]).await?;
+
+        Ok(row.map(|row| FileRecord {
+            user_id: row.get("user_id"),
+            metadata: file_from_row(&row),
+        }))
+    }
+
+    /// Look up a specific version of a file, together with its owner; files in the trash are not found
+    pub async fn get_file_version(&self, file_id: &str, version: i32) -> Result<Option<FileRecord>, Error> {
+        let query = format!(
+            "SELECT {}, f.user_id FROM {} WHERE f.file_id = $1 AND {} AND v.version = $2",
+            FILE_COLUMNS, FILE_VERSIONS, NOT_TRASHED
+        );
+        let row = self.client.query_opt(&query, &[&file_id, &version]).await?;
+
+        Ok(row.map(|row| FileRecord {
+            user_id: row.get("user_id"),
+            metadata: file_from_row(&row),
+        }))
+    }
+
+    /// List all versions of a file that are still kept, newest first
+    pub async fn list_file_versions(&self, file_id: &str) -> Result<Vec<FileMetadata>, Error> {
+        let query = format!(
+            "SELECT {} FROM {} WHERE f.file_id = $1 ORDER BY v.version DESC",
+            FILE_COLUMNS, FILE_VERSIONS
+        );
+        let rows = self.client.query(&query, &[&file_id]).await?;
+        Ok(rows.iter().map(file_from_row).collect())
+    }
+
+    /// Make a kept version the current version of its file again.
+    ///
+    /// Returns false if the file or version does not exist.
+    pub async fn restore_version(&self, file_id: &str, version: i32) -> Result<bool, Error> {
+        let updated = self.client
+            .execute(
+                "UPDATE user_files SET current_version = $2
+                 WHERE file_id = $1
+                   AND EXISTS (SELECT 1 FROM file_versions WHERE file_id = $1 AND version = $2)",
+                &[&file_id, &version],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Delete all but the `keep` most recent versions of a file and return the object keys that
+    /// are no longer referenced. The current version is always kept.
+    pub async fn prune_versions(&self, file_id: &str, keep: i32) -> Result<Vec<String>, Error> {
+        let query = format!(
+            "WITH removed AS (
+                 DELETE FROM file_versions v USING user_files f
+                 WHERE f.file_id = $1 AND v.file_id = f.file_id
+                   AND v.version <> f.current_version AND v.version <= f.latest_version - $2
+                 RETURNING v.file_key, v.content_hash
+             ){}",
+            RELEASE_REMOVED_VERSIONS
+        );
+        let rows = self.client.query(&query, &[&file_id, &keep]).await?;
+        self.released_object_keys(&rows).await
+    }
+
+    /// List one page of the current versions of the files of `owner` that match `filter`.
+    ///
+    /// Pages are read with keyset pagination, so deep pages cost no more than the first one.
+    /// Returns the cursor of the next page too, unless this was the last one.
+    pub async fn list_user_files(
+        &self,
+        owner: &FileOwner,
+        filter: &FileFilter,
+        page: &FilePage,
+    ) -> Result<(Vec<FileMetadata>, Option<FileCursor>), Error> {
+        let (attribute_keys, attribute_values): (Vec<&str>, Vec<&str>) = filter.attributes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .unzip();
+        let name_pattern = filter.name_contains.as_deref().map(|name| format!("%{}%", escape_like(name)));
+        let content_type_pattern = filter.content_type_prefix.as_deref().map(|prefix| format!("{}%", escape_like(prefix)));
+        let min_size = filter.min_size.map(|size| size as i64);
+        let max_size = filter.max_size.map(|size| size as i64);
+        // One extra row tells whether there is a next page
+        let limit = page.limit + 1;
+
+        let sort_column = match page.sort {
+            FileSort::Name => "f.filename",
+            FileSort::Size => "v.size",
+            FileSort::Date => "v.upload_time",
+        };
+        let (direction, comparison) = if page.descending { ("DESC", "<") } else { ("ASC", ">") };
+
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+            owner.param(),
+            &filter.tags,
+            &attribute_keys,
+            &attribute_values,
+            &name_pattern,
+            &content_type_pattern,
+            &min_size,
+            &max_size,
+            &filter.uploaded_after,
+            &filter.uploaded_before,
+            &limit,
+        ];
+
+        let mut after = String::new();
+        if let Some(cursor) = &page.after {
+            after = format!("AND ({}, f.id) {} ($12, $13)", sort_column, comparison);
+            params.push(match &cursor.key {
+                CursorKey::Name(name) => name,
+                CursorKey::Size(size) => size,
+                CursorKey::Date(date) => date,
+            });
+            params.push(&cursor.row_id);
+        }
+
+        let query = format!(
+            "SELECT {}, f.id FROM {} WHERE {} AND {} AND {} AND {}
+               AND NOT EXISTS (
+                   SELECT 1 FROM unnest($2::VARCHAR[]) wanted(tag)
+                   WHERE NOT EXISTS (SELECT 1 FROM file_tags t WHERE t.file_id = f.file_id AND t.tag = wanted.tag)
+               )
+               AND NOT EXISTS (
+                   SELECT 1 FROM unnest($3::VARCHAR[], $4::TEXT[]) wanted(key, value)
+                   WHERE NOT EXISTS (
+                       SELECT 1 FROM file_attributes a
+                       WHERE a.file_id = f.file_id AND a.key = wanted.key AND a.value = wanted.value
+                   )
+               )
+               AND ($5::VARCHAR IS NULL OR f.filename ILIKE $5)
+               AND ($6::VARCHAR IS NULL OR v.content_type ILIKE $6)
+               AND ($7::BIGINT IS NULL OR v.size >= $7)
+               AND ($8::BIGINT IS NULL OR v.size <= $8)
+               AND ($9::TIMESTAMPTZ IS NULL OR v.upload_time >= $9)
+               AND ($10::TIMESTAMPTZ IS NULL OR v.upload_time < $10)
+               {}
+             ORDER BY {} {}, f.id {}
+             LIMIT $11",
+            FILE_COLUMNS,
+            FILE_VERSIONS,
+            owner.condition(),
+            NOT_TRASHED,
+            NOT_EXPIRED,
+            CURRENT_VERSION,
+            after,
+            sort_column,
+            direction,
+            direction
+        );
+        let mut rows = self.client.query(&query, &params).await?;
+
+        let next = if rows.len() as i64 > page.limit {
+            rows.truncate(page.limit as usize);
+            rows.last().map(|row| {
+                let file = file_from_row(row);
+                FileCursor {
+                    sort: page.sort,
+                    descending: page.descending,
+                    key: match page.sort {
+                        FileSort::Name => CursorKey::Name(file.filename),
+                        FileSort::Size => CursorKey::Size(file.size as i64),
+                        FileSort::Date => CursorKey::Date(file.upload_time),
+                    },
+                    row_id: row.get("id"),
+                }
+            })
+        } else {
+            None
+        };
+
+        Ok((rows.iter().map(file_from_row).collect(), next))
+    }
+
+    /// Attach tags to a file; tags it already has are left alone
+    pub async fn add_tags(&self, file_id: &str, tags: &[String]) -> Result<(), Error> {
+        self.client
+            .execute(
+                "INSERT INTO file_tags (file_id, tag) SELECT $1, unnest($2::VARCHAR[])
+                 ON CONFLICT (file_id, tag) DO NOTHING",
+                &[&file_id, &tags],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Detach a tag from a file, returning false if the file did not have it
+    pub async fn remove_tag(&self, file_id: &str, tag: &str) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute("DELETE FROM file_tags WHERE file_id = $1 AND tag = $2", &[&file_id, &tag])
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Set custom attributes on a file, replacing the values of keys it already has
+    pub async fn set_attributes(&self, file_id: &str, attributes: &BTreeMap<String, String>) -> Result<(), Error> {
+        let keys: Vec<&String> = attributes.keys().collect();
+        let values: Vec<&String> = attributes.values().collect();
+        self.client
+            .execute(
+                "INSERT INTO file_attributes (file_id, key, value)
+                 SELECT $1, wanted.key, wanted.value FROM unnest($2::VARCHAR[], $3::TEXT[]) wanted(key, value)
+                 ON CONFLICT (file_id, key) DO UPDATE SET value = EXCLUDED.value",
+                &[&file_id, &keys, &values],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Remove a custom attribute from a file, returning false if the file did not have it
+    pub async fn remove_attribute(&self, file_id: &str, key: &str) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute("DELETE FROM file_attributes WHERE file_id = $1 AND key = $2", &[&file_id, &key])
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Delete the records of the files of `user_files f` matching `condition` with all their versions
+    /// and return the object keys that are no longer referenced, `None` if no file matched.
+    ///
+    /// Those keys are queued for removal from storage in the same statement that drops their
+    /// last reference, so an object is never forgotten even if removing it fails.
+    async fn delete_files(&self, condition: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Vec<String>>, Error> {
+        let query = format!(
+            "WITH deleted AS (
+                 DELETE FROM user_files f WHERE {} RETURNING f.file_id
+             ), removed AS (
+                 DELETE FROM file_versions WHERE file_id IN (SELECT file_id FROM deleted)
+                 RETURNING file_key, content_hash
+             ){}",
+            condition, RELEASE_REMOVED_VERSIONS
+        );
+        let rows = self.client.query(&query, params).await?;
+
+        // Every file has at least one version, so no rows means there was no file
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.released_object_keys(&rows).await?))
+    }
+
+    /// Move a file to the trash on behalf of `user_id`; returns false if it does not exist or is already there
+    pub async fn trash_file(&self, file_id: &str, user_id: i64) -> Result<bool, Error> {
+        let updated = self.client
+            .execute(
+                "UPDATE user_files SET deleted_at = NOW(), deleted_by = $2 WHERE file_id = $1 AND deleted_at IS NULL",
+                &[&file_id, &user_id],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Look up the current version of a file in the trash, together with its owner
+    pub async fn get_trashed_file(&self, file_id: &str) -> Result<Option<FileRecord>, Error> {
+        let query = format!(
+            "SELECT {}, f.user_id FROM {} WHERE f.file_id = $1 AND f.deleted_at IS NOT NULL AND {}",
+            FILE_COLUMNS, FILE_VERSIONS, CURRENT_VERSION
+        );
+        let row = self.client.query_opt(&query, &[&file_id]).await?;
+
+        Ok(row.map(|row| FileRecord {
+            user_id: row.get("user_id"),
+            metadata: file_from_row(&row),
+        }))
+    }
+
+    /// Files of `owner` in the trash, most recently deleted first, each purged `retention` after it was deleted
+    pub async fn list_trash(&self, owner: &FileOwner, retention: Duration) -> Result<Vec<TrashedFile>, Error> {
+        let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+        let query = format!(
+            "SELECT {}, f.deleted_at, f.deleted_by FROM {}
+             WHERE {} AND f.deleted_at IS NOT NULL AND {} AND {}
+             ORDER BY f.deleted_at DESC, f.id DESC",
+            FILE_COLUMNS,
+            FILE_VERSIONS,
+            owner.condition(),
+            NOT_EXPIRED,
+            CURRENT_VERSION
+        );
+        let rows = self.client.query(&query, &[owner.param()]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let deleted_at: DateTime<Utc> = row.get("deleted_at");
+                TrashedFile {
+                    file: file_from_row(row),
+                    deleted_at,
+                    deleted_by: row.get("deleted_by"),
+                    purge_at: deleted_at.checked_add_signed(retention).unwrap_or(DateTime::<Utc>::MAX_UTC),
+                }
+            })
+            .collect())
+    }
+
+    /// Take a file out of the trash, back to where it was.
+    ///
+    /// Returns false if it is not in the trash, or another file of its owner has taken its name in
+    /// the meantime.
+    pub async fn restore_file(&self, file_id: &str) -> Result<bool, Error> {
+        let updated = self.client
+            .execute(
+                "UPDATE user_files f SET deleted_at = NULL, deleted_by = NULL
+                 WHERE f.file_id = $1 AND f.deleted_at IS NOT NULL AND NOT EXISTS (
+                     SELECT 1 FROM user_files other
+                     WHERE other.workspace_id IS NOT DISTINCT FROM f.workspace_id
+                       AND (other.workspace_id IS NOT NULL OR other.user_id = f.user_id)
+                       AND other.folder_id IS NOT DISTINCT FROM f.folder_id
+                       AND other.filename = f.filename AND other.deleted_at IS NULL AND other.file_id <> f.file_id
+                       AND (other.expires_at IS NULL OR other.expires_at > NOW())
+                 )",
+                &[&file_id],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Delete a file in the trash for good and return the object keys no longer referenced; `None` if it is not in the trash
+    pub async fn delete_trashed_file(&self, file_id: &str) -> Result<Option<Vec<String>>, Error> {
+        self.delete_files("f.file_id = $1 AND f.deleted_at IS NOT NULL", &[&file_id]).await
+    }
+
+    /// Delete every file of `owner` in the trash for good and return the object keys no longer referenced
+    pub async fn empty_trash(&self, owner: &FileOwner) -> Result<Vec<String>, Error> {
+        let condition = format!("{} AND f.deleted_at IS NOT NULL", owner.condition());
+        Ok(self.delete_files(&condition, &[owner.param()]).await?.unwrap_or_default())
+    }
+
+    /// Delete every file moved to the trash before `deleted_before` for good and return the object
+    /// keys no longer referenced
+    pub async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<Vec<String>, Error> {
+        Ok(self.delete_files("f.deleted_at < $1", &[&deleted_before]).await?.unwrap_or_default())
+    }
+
+    /// Delete every file that expired by `now` for good, in the trash or not, and return the object
+    /// keys no longer referenced
+    pub async fn purge_expired_files(&self, now: DateTime<Utc>) -> Result<Vec<String>, Error> {
+        Ok(self.delete_files("f.expires_at <= $1", &[&now]).await?.unwrap_or_default())
+    }
+
+    /// Object keys no longer referenced after a `RELEASE_REMOVED_VERSIONS` statement.
+    ///
+    /// Shared objects whose reference count dropped to zero are forgotten and queued here.
+    async fn released_object_keys(&self, rows: &[Row]) -> Result<Vec<String>, Error> {
+        let mut file_keys = Vec::new();
+        let mut unreferenced = Vec::new();
+
+        for row in rows {
+            let file_key: String = row.get(0);
+            let content_hash: Option<String> = row.get(1);
+            let ref_count: Option<i64> = row.get(2);
+
+            match (content_hash, ref_count) {
+                (None, _) => file_keys.push(file_key),
+                (Some(content_hash), Some(0)) if !unreferenced.contains(&content_hash) => {
+                    unreferenced.push(content_hash);
+                }
+                _ => {}
+            }
+        }
+
+        for content_hash in &unreferenced {
+            file_keys.extend(self.drop_unreferenced_object(content_hash).await?);
+        }
+        Ok(file_keys)
+    }
+
+    /// Rename a file, correct the content type of its current version or set its description.
+    ///
+    /// `None` leaves a field unchanged and an empty description clears it. Returns false if the
+    /// file does not exist or its folder already holds another file with the new name.
+    pub async fn update_file(
+        &self,
+        file_id: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        description: Option<&str>,
+    ) -> Result<bool, Error> {
+        let row = self.client
+            .query_opt(
+                "WITH file AS (
+                     UPDATE user_files f
+                     SET filename = COALESCE($2, f.filename),
+                         description = CASE WHEN $4::TEXT IS NULL THEN f.description ELSE NULLIF($4, '') END
+                     WHERE f.file_id = $1 AND ($2::VARCHAR IS NULL OR NOT EXISTS (
+                         SELECT 1 FROM user_files other
+                         WHERE other.workspace_id IS NOT DISTINCT FROM f.workspace_id
+                           AND (other.workspace_id IS NOT NULL OR other.user_id = f.user_id)
+                           AND other.folder_id IS NOT DISTINCT FROM f.folder_id
+                           AND other.filename = $2 AND other.deleted_at IS NULL AND other.file_id <> f.file_id
+                           AND (other.expires_at IS NULL OR other.expires_at > NOW())
+                     ))
+                     RETURNING f.file_id, f.current_version
+                 ), current_version AS (
+                     UPDATE file_versions v SET content_type = COALESCE($3, v.content_type)
+                     FROM file WHERE v.file_id = file.file_id AND v.version = file.current_version
+                 )
+                 SELECT file_id FROM file",
+                &[&file_id, &filename, &content_type, &description],
+            )
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Move a file into a folder, or to the top level.
+    ///
+    /// Returns false if the file does not exist or the target already holds a file of that name.
+    pub async fn move_file(&self, file_id: &str, folder_id: Option<&str>) -> Result<bool, Error> {
+        let updated = self.client
+            .execute(
+                "UPDATE user_files f SET folder_id = $2
+                 WHERE f.file_id = $1 AND NOT EXISTS (
+                     SELECT 1 FROM user_files other
+                     WHERE other.user_id = f.user_id AND other.workspace_id IS NULL AND other.folder_id IS NOT DISTINCT FROM $2
+                       AND other.filename = f.filename AND other.deleted_at IS NULL AND other.file_id <> f.file_id
+                       AND (other.expires_at IS NULL OR other.expires_at > NOW())
+                 )",
+                &[&file_id, &folder_id],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Storage used by `user_id` and the quota it counts against.
+    ///
+    /// Users without an override in `user_quotas` get `default_quota`, where `None` means unlimited.
+    pub async fn storage_usage(&self, user_id: i64, default_quota: Option<u64>) -> Result<StorageUsage, Error> {
+        // Expired files are gone, even before the cleanup task deletes them
+        let query = format!(
+            "SELECT
+                 (SELECT COALESCE(SUM(v.size), 0)::BIGINT FROM user_files f JOIN file_versions v ON v.file_id = f.file_id
+                  WHERE f.user_id = $1 AND {}),
+                 (SELECT COUNT(*) FROM user_files f
+                  WHERE f.user_id = $1 AND f.file_id IS NOT NULL AND {} AND {}),
+                 (SELECT COUNT(*) FROM user_files f
+                  WHERE f.user_id = $1 AND f.file_id IS NOT NULL AND f.deleted_at IS NOT NULL AND {}),
+                 q.user_id IS NOT NULL,
+                 q.quota_bytes
+             FROM (SELECT 1) one LEFT JOIN user_quotas q ON q.user_id = $1",
+            NOT_EXPIRED, NOT_TRASHED, NOT_EXPIRED, NOT_EXPIRED
+        );
+        let row = self.client.query_one(&query, &[&user_id]).await?;
+
+        let bytes_used = row.get::<_, i64>(0) as u64;
+        let has_override: bool = row.get(3);
+        let quota_bytes = if has_override {
+            row.get::<_, Option<i64>>(4).map(|quota| quota as u64)
+        } else {
+            default_quota
+        };
+
+        Ok(StorageUsage {
+            bytes_used,
+            file_count: row.get::<_, i64>(1) as u64,
+            trashed_file_count: row.get::<_, i64>(2) as u64,
+            quota_bytes,
+            remaining_bytes: quota_bytes.map(|quota| quota.saturating_sub(bytes_used)),
+        })
+    }
+
+    /// Override the quota of `user_id`; `None` lifts the limit entirely
+    pub async fn set_user_quota(&self, user_id: i64, quota_bytes: Option<u64>) -> Result<(), Error> {
+        let quota_bytes = quota_bytes.map(|quota| quota.min(i64::MAX as u64) as i64);
+        self.client
+            .execute(
+                "INSERT INTO user_quotas (user_id, quota_bytes) VALUES ($1, $2)
+                 ON CONFLICT (user_id) DO UPDATE SET quota_bytes = EXCLUDED.quota_bytes",
+                &[&user_id, &quota_bytes],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the quota override of `user_id` so the default applies again
+    pub async fn clear_user_quota(&self, user_id: i64) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute("DELETE FROM user_quotas WHERE user_id = $1", &[&user_id])
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Create a folder for `user_id`, below `parent_id` or at the top level
+    pub async fn create_folder(&self, user_id: i64, name: &str, parent_id: Option<&str>) -> Result<Folder, Error> {
+        let row = self.client
+            .query_one(
+                "INSERT INTO folders (folder_id, user_id, parent_id, name) VALUES ($1, $2, $3, $4)
+                 RETURNING folder_id, name, parent_id, created_at",
+                &[&Uuid::new_v4().to_string(), &user_id, &parent_id, &name],
+            )
+            .await?;
+        Ok(folder_from_row(&row))
+    }
+
+    /// Look up a folder by id, together with its owner
+    pub async fn get_folder(&self, folder_id: &str) -> Result<Option<FolderRecord>, Error> {
+        let row = self.client
+            .query_opt(
+                "SELECT folder_id, name, parent_id, created_at, user_id FROM folders WHERE folder_id = $1",
+                &[&folder_id],
+            )
+            .await?;
+
+        Ok(row.map(|row| FolderRecord {
+            user_id: row.get(4),
+            folder: folder_from_row(&row),
+        }))
+    }
+
+    /// List the folders of `user_id` directly below `parent_id`, or at the top level, by name
+    pub async fn list_folders(&self, user_id: i64, parent_id: Option<&str>) -> Result<Vec<Folder>, Error> {
+        let rows = self.client
+            .query(
+                "SELECT folder_id, name, parent_id, created_at FROM folders
+                 WHERE user_id = $1 AND parent_id IS NOT DISTINCT FROM $2
+                 ORDER BY name",
+                &[&user_id, &parent_id],
+            )
+            .await?;
+        Ok(rows.iter().map(folder_from_row).collect())
+    }
+
+    /// List the current version of the files of `user_id` in a folder, or at the top level, by name
+    pub async fn list_folder_files(&self, user_id: i64, folder_id: Option<&str>) -> Result<Vec<FileMetadata>, Error> {
+        let query = format!(
+            "SELECT {} FROM {}
+             WHERE f.user_id = $1 AND f.workspace_id IS NULL AND f.folder_id IS NOT DISTINCT FROM $2 AND {} AND {} AND {}
+             ORDER BY f.filename, f.id",
+            FILE_COLUMNS, FILE_VERSIONS, NOT_TRASHED, NOT_EXPIRED, CURRENT_VERSION
+        );
+        let rows = self.client.query(&query, &[&user_id, &folder_id]).await?;
+        Ok(rows.iter().map(file_from_row).collect())
+    }
+
+    /// List the current version of every file in a folder and its subfolders, by path.
+    ///
+    /// Each file comes with the path of its folder relative to `folder_id`, ending in `/`,
+    /// or empty for files directly inside it.
+    pub async fn list_folder_tree_files(&self, folder_id: &str) -> Result<Vec<(String, FileMetadata)>, Error> {
+        let query = format!(
+            "WITH RECURSIVE subtree AS (
+                 SELECT folder_id, ''::TEXT AS path FROM folders WHERE folder_id = $1
+                 UNION ALL
+                 SELECT child.folder_id, subtree.path || child.name || '/'
+                 FROM folders child JOIN subtree ON child.parent_id = subtree.folder_id
+             )
+             SELECT {}, subtree.path FROM {} JOIN subtree ON subtree.folder_id = f.folder_id
+             WHERE {} AND {} AND {} ORDER BY subtree.path, f.filename, f.id",
+            FILE_COLUMNS, FILE_VERSIONS, NOT_TRASHED, NOT_EXPIRED, CURRENT_VERSION
+        );
+        let rows = self.client.query(&query, &[&folder_id]).await?;
+        Ok(rows.iter().map(|row| (row.get("path"), file_from_row(row))).collect())
+    }
+
+    /// Rename a folder; fails with a unique violation if a sibling already has that name
+    pub async fn rename_folder(&self, folder_id: &str, name: &str) -> Result<Option<Folder>, Error> {
+        let row = self.client
+            .query_opt(
+                "UPDATE folders SET name = $2 WHERE folder_id = $1
+                 RETURNING folder_id, name, parent_id, created_at",
+                &[&folder_id, &name],
+            )
+            .await?;
+        Ok(row.as_ref().map(folder_from_row))
+    }
+
+    /// Move a folder below `parent_id`, or to the top level.
+    ///
+    /// Returns `None` if the folder does not exist or the move would put it inside itself.
+    /// Fails with a unique violation if the new parent already has a folder of that name.
+    pub async fn move_folder(&self, folder_id: &str, parent_id: Option<&str>) -> Result<Option<Folder>, Error> {
+        let row = self.client
+            .query_opt(
+                "WITH RECURSIVE subtree AS (
+                     SELECT folder_id FROM folders WHERE folder_id = $1
+                     UNION ALL
+                     SELECT child.folder_id FROM folders child JOIN subtree ON child.parent_id = subtree.folder_id
+                 )
+                 UPDATE folders SET parent_id = $2
+                 WHERE folder_id = $1 AND ($2::VARCHAR IS NULL OR $2 NOT IN (SELECT folder_id FROM subtree))
+                 RETURNING folder_id, name, parent_id, created_at",
+                &[&folder_id, &parent_id],
+            )
+            .await?;
+        Ok(row.as_ref().map(folder_from_row))
+    }
+
+    /// Delete a folder with all its subfolders, moving the files filed in them to the trash of
+    /// `user_id` at the top level, where they are restored to.
+    ///
+    /// Files already in the trash keep their deletion time. Returns the number of files moved to the trash.
+    pub async fn delete_folder(&self, folder_id: &str, user_id: i64) -> Result<u64, Error> {
+        let row = self.client
+            .query_one(
+                "WITH RECURSIVE subtree AS (
+                     SELECT folder_id FROM folders WHERE folder_id = $1
+                     UNION ALL
+                     SELECT child.folder_id FROM folders child JOIN subtree ON child.parent_id = subtree.folder_id
+                 ), trashed AS (
+                     UPDATE user_files SET folder_id = NULL,
+                         deleted_at = COALESCE(deleted_at, NOW()),
+                         deleted_by = CASE WHEN deleted_at IS NULL THEN $2 ELSE deleted_by END
+                     WHERE folder_id IN (SELECT folder_id FROM subtree)
+                     RETURNING deleted_at = NOW() AS newly_trashed
+                 ), deleted_folders AS (
+                     DELETE FROM folders WHERE folder_id IN (SELECT folder_id FROM subtree)
+                 )
+                 SELECT COUNT(*) FILTER (WHERE newly_trashed) FROM trashed",
+                &[&folder_id, &user_id],
+            )
+            .await?;
+        let trashed: i64 = row.get(0);
+        Ok(trashed as u64)
+    }
+
+    /// Forget a shared object whose last reference is gone and queue it for removal.
+    ///
+    /// The reference count is checked again, so an upload that picked the object up
+    /// in the meantime keeps it alive.
+    async fn drop_unreferenced_object(&self, content_hash: &str) -> Result<Vec<String>, Error> {
+        let rows = self.client
+            .query(
+                "WITH dropped AS (
+                     DELETE FROM storage_objects WHERE content_hash = $1 AND ref_count = 0
+                     RETURNING file_key
+                 ), queued AS (
+                     INSERT INTO pending_object_deletions (file_key)
+                     SELECT file_key FROM dropped
+                     ON CONFLICT (file_key) DO NOTHING
+                 )
+                 SELECT file_key FROM dropped",
+                &[&content_hash],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Forget every shared object without references, e.g. left over when a deletion was
+    /// interrupted, and queue them for removal
+    pub async fn drop_unreferenced_objects(&self) -> Result<u64, Error> {
+        self.client
+            .execute(
+                "WITH dropped AS (
+                     DELETE FROM storage_objects WHERE ref_count = 0 RETURNING file_key
+                 )
+                 INSERT INTO pending_object_deletions (file_key)
+                 SELECT file_key FROM dropped
+                 ON CONFLICT (file_key) DO NOTHING",
+                &[],
+            )
+            .await
+    }
+
+    /// Queue an object for removal from storage
+    pub async fn queue_object_deletion(&self, file_key: &str) -> Result<(), Error> {
+        self.client
+            .execute(
+                "INSERT INTO pending_object_deletions (file_key) VALUES ($1) ON CONFLICT (file_key) DO NOTHING",
+                &[&file_key],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Object keys queued for removal from storage, oldest first
+    pub async fn pending_object_deletions(&self, limit: i64) -> Result<Vec<String>, Error> {
+        let rows = self.client
+            .query(
+                "SELECT file_key FROM pending_object_deletions ORDER BY queued_at LIMIT $1",
+                &[&limit],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Forget a queued object once it has been removed from storage
+    pub async fn object_deleted(&self, file_key: &str) -> Result<(), Error> {
+        self.client
+            .execute("DELETE FROM pending_object_deletions WHERE file_key = $1", &[&file_key])
+            .await?;
+        Ok(())
+    }
+
+    /// Remember an upload handed out as a presigned URL
+    pub async fn create_pending_upload(&self, upload: &PendingUpload) -> Result<(), Error> {
+        self.client
+            .execute(
+                "INSERT INTO pending_uploads (file_id, user_id, file_key, filename, content_type, expires_at, folder_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &upload.file_id,
+                    &upload.user_id,
+                    &upload.file_key,
+                    &upload.filename,
+                    &upload.content_type,
+                    &upload.expires_at,
+                    &upload.folder_id,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Look up an upload that has not been completed or expired yet
+    pub async fn get_pending_upload(&self, file_id: &str) -> Result<Option<PendingUpload>, Error> {
+        let row = self.client
+            .query_opt(
+                "SELECT file_id, user_id, file_key, filename, content_type, expires_at, folder_id
+                 FROM pending_uploads WHERE file_id = $1 AND expires_at > NOW()",
+                &[&file_id],
+            )
+            .await?;
+        Ok(row.as_ref().map(pending_upload_from_row))
+    }
+
+    /// Claim a pending upload for completion, so it can only be completed once
+    pub async fn take_pending_upload(&self, file_id: &str) -> Result<Option<PendingUpload>, Error> {
+        let row = self.client
+            .query_opt(
+                "DELETE FROM pending_uploads WHERE file_id = $1 AND expires_at > NOW()
+                 RETURNING file_id, user_id, file_key, filename, content_type, expires_at, folder_id",
+                &[&file_id],
+            )
+            .await?;
+        Ok(row.as_ref().map(pending_upload_from_row))
+    }
+
+    /// Drop uploads that were never completed and queue their objects for removal
+    pub async fn expire_pending_uploads(&self) -> Result<u64, Error> {
+        self.client
+            .execute(
+                "WITH expired AS (
+                     DELETE FROM pending_uploads WHERE expires_at <= NOW() RETURNING file_key
+                 )
+                 INSERT INTO pending_object_deletions (file_key)
+                 SELECT file_key FROM expired
+                 ON CONFLICT (file_key) DO NOTHING",
+                &[],
+            )
+            .await
+    }
+
+    /// Remember a resumable upload created through the tus protocol
+    pub async fn create_tus_upload(&self, upload: &TusUpload) -> Result<(), Error> {
+        self.client
+            .execute(
+                "INSERT INTO tus_uploads (upload_id, user_id, file_key, filename, content_type, folder_id, upload_length, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &upload.upload_id,
+                    &upload.user_id,
+                    &upload.file_key,
+                    &upload.filename,
+                    &upload.content_type,
+                    &upload.folder_id,
+                    &(upload.upload_length as i64),
+                    &upload.expires_at,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Look up a resumable upload, including expired ones that have not been swept yet
+    pub async fn get_tus_upload(&self, upload_id: &str) -> Result<Option<TusUpload>, Error> {
+        let query = format!("SELECT {} FROM tus_uploads WHERE upload_id = $1", TUS_UPLOAD_COLUMNS);
+        let row = self.client.query_opt(&query, &[&upload_id]).await?;
+        Ok(row.as_ref().map(tus_upload_from_row))
+    }
+
+    /// Lock an unexpired upload for appending at `offset` until `locked_until`.
+    ///
+    /// `None` means the upload is at another offset, expired, or already locked by another request.
+    pub async fn lock_tus_upload(
+        &self,
+        upload_id: &str,
+        offset: u64,
+        locked_until: DateTime<Utc>,
+    ) -> Result<Option<TusUpload>, Error> {
+        let query = format!(
+            "UPDATE tus_uploads SET locked_until = $3
+             WHERE upload_id = $1 AND upload_offset = $2 AND expires_at > NOW()
+               AND (locked_until IS NULL OR locked_until <= NOW())
+             RETURNING {}",
+            TUS_UPLOAD_COLUMNS
+        );
+        let row = self.client.query_opt(&query, &[&upload_id, &(offset as i64), &locked_until]).await?;
+        Ok(row.as_ref().map(tus_upload_from_row))
+    }
+
+    /// Release the lock taken by `lock_tus_upload` without recording progress
+    pub async fn unlock_tus_upload(&self, upload_id: &str) -> Result<(), Error> {
+        self.client
+            .execute("UPDATE tus_uploads SET locked_until = NULL WHERE upload_id = $1", &[&upload_id])
+            .await?;
+        Ok(())
+    }
+
+    /// Record the backend multipart upload started for a resumable upload
+    pub async fn set_tus_multipart_upload(&self, upload_id: &str, multipart_upload_id: &str) -> Result<(), Error> {
+        self.client
+            .execute(
+                "UPDATE tus_uploads SET multipart_upload_id = $2 WHERE upload_id = $1",
+                &[&upload_id, &multipart_upload_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Record an uploaded part of `size` bytes; parts start with the previous tail, which is no longer needed
+    pub async fn commit_tus_part(&self, upload_id: &str, part: &CompletedPart, size: u64) -> Result<(), Error> {
+        self.client
+            .execute(
+                "WITH part AS (
+                     INSERT INTO tus_upload_parts (upload_id, part_number, etag) VALUES ($1, $2, $3)
+                     ON CONFLICT (upload_id, part_number) DO UPDATE SET etag = EXCLUDED.etag
+                 )
+                 UPDATE tus_uploads SET upload_offset = upload_offset - tail_size + $4, tail_size = 0
+                 WHERE upload_id = $1",
+                &[&upload_id, &part.part_number, &part.etag, &(size as i64)],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Record a new tail of `tail_size` bytes, extend the expiry and release the lock
+    pub async fn save_tus_tail(&self, upload_id: &str, tail_size: u64, expires_at: DateTime<Utc>) -> Result<(), Error> {
+        self.client
+            .execute(
+                "UPDATE tus_uploads
+                 SET upload_offset = upload_offset - tail_size + $2, tail_size = $2, expires_at = $3, locked_until = NULL
+                 WHERE upload_id = $1",
+                &[&upload_id, &(tail_size as i64), &expires_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Parts uploaded for a resumable upload, in order
+    pub async fn tus_upload_parts(&self, upload_id: &str) -> Result<Vec<CompletedPart>, Error> {
+        let rows = self.client
+            .query(
+                "SELECT part_number, etag FROM tus_upload_parts WHERE upload_id = $1 ORDER BY part_number",
+                &[&upload_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| CompletedPart {
+                part_number: row.get(0),
+                etag: row.get(1),
+            })
+            .collect())
+    }
+
+    /// Remove a resumable upload once it is finished or terminated, so it is only handled once
+    pub async fn take_tus_upload(&self, upload_id: &str) -> Result<Option<TusUpload>, Error> {
+        let query = format!("DELETE FROM tus_uploads WHERE upload_id = $1 RETURNING {}", TUS_UPLOAD_COLUMNS);
+        let row = self.client.query_opt(&query, &[&upload_id]).await?;
+        Ok(row.as_ref().map(tus_upload_from_row))
+    }
+
+    /// Remove expired resumable uploads that are not being appended to; their storage is the caller's to release
+    pub async fn expire_tus_uploads(&self) -> Result<Vec<TusUpload>, Error> {
+        let query = format!(
+            "DELETE FROM tus_uploads
+             WHERE expires_at <= NOW() AND (locked_until IS NULL OR locked_until <= NOW())
+             RETURNING {}",
+            TUS_UPLOAD_COLUMNS
+        );
+        let rows = self.client.query(&query, &[]).await?;
+        Ok(rows.iter().map(tus_upload_from_row).collect())
+    }
+
+    /// Grant `user_id` `permission` on a file, replacing any permission granted before
+    pub async fn grant_access(
+        &self,
+        file_id: &str,
+        user_id: i64,
+        permission: Permission,
+        granted_by: i64,
+    ) -> Result<FileGrant, Error> {
+        let query = format!(
+            "WITH g AS (
+                 INSERT INTO file_grants (file_id, user_id, permission, granted_by)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (file_id, user_id) DO UPDATE SET permission = EXCLUDED.permission, granted_by = EXCLUDED.granted_by
+                 RETURNING *
+             )
+             SELECT {} FROM g JOIN users u ON u.id = g.user_id",
+            FILE_GRANT_COLUMNS
+        );
+        let row = self.client.query_one(&query, &[&file_id, &user_id, &permission.as_str(), &granted_by]).await?;
+        Ok(file_grant_from_row(&row))
+    }
+
+    /// Withdraw the access granted to `user_id` on a file; returns false if there was none
+    pub async fn revoke_access(&self, file_id: &str, user_id: i64) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute("DELETE FROM file_grants WHERE file_id = $1 AND user_id = $2", &[&file_id, &user_id])
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Permission granted to `user_id` on a file, `None` if none was; owners have no grant on their own files
+    pub async fn file_permission(&self, file_id: &str, user_id: i64) -> Result<Option<Permission>, Error> {
+        let row = self.client
+            .query_opt(
+                "SELECT permission FROM file_grants WHERE file_id = $1 AND user_id = $2",
+                &[&file_id, &user_id],
+            )
+            .await?;
+        Ok(row.map(|row| Permission::from_column(row.get(0))))
+    }
+
+    /// Users granted access to a file, in the order access was granted
+    pub async fn list_file_grants(&self, file_id: &str) -> Result<Vec<FileGrant>, Error> {
+        let query = format!(
+            "SELECT {} FROM file_grants g JOIN users u ON u.id = g.user_id
+             WHERE g.file_id = $1 ORDER BY g.created_at, g.user_id",
+            FILE_GRANT_COLUMNS
+        );
+        let rows = self.client.query(&query, &[&file_id]).await?;
+        Ok(rows.iter().map(file_grant_from_row).collect())
+    }
+
+    /// Current versions of the files other users granted `user_id` access to, by filename
+    pub async fn list_shared_files(&self, user_id: i64) -> Result<Vec<SharedFile>, Error> {
+        let query = format!(
+            "SELECT {}, g.permission, owner.email
+             FROM {} JOIN file_grants g ON g.file_id = f.file_id JOIN users owner ON owner.id = f.user_id
+             WHERE g.user_id = $1 AND {} AND {} AND {}
+             ORDER BY f.filename, f.id",
+            FILE_COLUMNS, FILE_VERSIONS, NOT_TRASHED, NOT_EXPIRED, CURRENT_VERSION
+        );
+        let rows = self.client.query(&query, &[&user_id]).await?;
+        Ok(rows
+            .iter()
+            .map(|row| SharedFile {
+                file: file_from_row(row),
+                permission: Permission::from_column(row.get("permission")),
+                owner_email: row.get("email"),
+            })
+            .collect())
+    }
+
+    /// Remember a newly created share link
+    pub async fn create_share_link(&self, link: &ShareLink) -> Result<(), Error> {
+        self.client
+            .execute(
+                "INSERT INTO share_links (token, file_id, user_id, password_hash, expires_at, max_downloads, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &link.token,
+                    &link.file_id,
+                    &link.user_id,
+                    &link.password_hash,
+                    &link.expires_at,
+                    &link.max_downloads,
+                    &link.created_at,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Look up a share link by token, whether or not it can still be used
+    pub async fn get_share_link(&self, token: &str) -> Result<Option<ShareLink>, Error> {
+        let query = format!("SELECT {} FROM share_links WHERE token = $1", SHARE_LINK_COLUMNS);
+        let row = self.client.query_opt(&query, &[&token]).await?;
+        Ok(row.as_ref().map(share_link_from_row))
+    }
+
+    /// Share links of a file, including revoked and expired ones, newest first
+    pub async fn list_share_links(&self, file_id: &str) -> Result<Vec<ShareLink>, Error> {
+        let query = format!(
+            "SELECT {} FROM share_links WHERE file_id = $1 ORDER BY created_at DESC, token",
+            SHARE_LINK_COLUMNS
+        );
+        let rows = self.client.query(&query, &[&file_id]).await?;
+        Ok(rows.iter().map(share_link_from_row).collect())
+    }
+
+    /// Revoke a share link of a file; revoking it again keeps the original time.
+    ///
+    /// Returns the link as revoked, `None` if the file has no such link.
+    pub async fn revoke_share_link(&self, file_id: &str, token: &str) -> Result<Option<ShareLink>, Error> {
+        let query = format!(
+            "UPDATE share_links SET revoked_at = COALESCE(revoked_at, NOW())
+             WHERE token = $1 AND file_id = $2
+             RETURNING {}",
+            SHARE_LINK_COLUMNS
+        );
+        let row = self.client.query_opt(&query, &[&token, &file_id]).await?;
+        Ok(row.as_ref().map(share_link_from_row))
+    }
+
+    /// Count a download through a share link, unless the link was revoked, expired or used up.
+    ///
+    /// Returns false if the download must be refused; the check and the count are one
+    /// statement, so concurrent downloads cannot exceed the limit.
+    pub async fn count_share_download(&self, token: &str) -> Result<bool, Error> {
+        let updated = self.client
+            .execute(
+                "UPDATE share_links SET download_count = download_count + 1
+                 WHERE token = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+                   AND (max_downloads IS NULL OR download_count < max_downloads)",
+                &[&token],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    /// Give back a download counted by `count_share_download` that could not be served
+    pub async fn uncount_share_download(&self, token: &str) -> Result<(), Error> {
+        self.client
+            .execute(
+                "UPDATE share_links SET download_count = download_count - 1 WHERE token = $1 AND download_count > 0",
+                &[&token],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Create a workspace with `user_id` as its owner
+    pub async fn create_workspace(&self, user_id: i64, name: &str) -> Result<Workspace, Error> {
+        let query = format!(
+            "WITH w AS (
+                 INSERT INTO workspaces (workspace_id, name, created_by) VALUES ($1, $2, $3)
+                 RETURNING workspace_id, name, created_at
+             ), m AS (
+                 INSERT INTO workspace_members (workspace_id, user_id, role) VALUES ($1, $3, 'owner')
+                 RETURNING role
+             )
+             SELECT {} FROM w, m",
+            WORKSPACE_COLUMNS
+        );
+        let row = self.client.query_one(&query, &[&Uuid::new_v4().to_string(), &name, &user_id]).await?;
+        Ok(workspace_from_row(&row))
+    }
+
+    /// Look up a workspace as seen by `user_id`; `None` if it does not exist or they are not a member
+    pub async fn get_workspace(&self, workspace_id: &str, user_id: i64) -> Result<Option<Workspace>, Error> {
+        let query = format!(
+            "SELECT {} FROM workspaces w JOIN workspace_members m ON m.workspace_id = w.workspace_id
+             WHERE w.workspace_id = $1 AND m.user_id = $2",
+            WORKSPACE_COLUMNS
+        );
+        let row = self.client.query_opt(&query, &[&workspace_id, &user_id]).await?;
+        Ok(row.as_ref().map(workspace_from_row))
+    }
+
+    /// Workspaces `user_id` is a member of, by name
+    pub async fn list_workspaces(&self, user_id: i64) -> Result<Vec<Workspace>, Error> {
+        let query = format!(
+            "SELECT {} FROM workspaces w JOIN workspace_members m ON m.workspace_id = w.workspace_id
+             WHERE m.user_id = $1 ORDER BY w.name, w.id",
+            WORKSPACE_COLUMNS
+        );
+        let rows = self.client.query(&query, &[&user_id]).await?;
+        Ok(rows.iter().map(workspace_from_row).collect())
+    }
+
+    /// Role of `user_id` in a workspace, `None` if they are not a member
+    pub async fn workspace_role(&self, workspace_id: &str, user_id: i64) -> Result<Option<WorkspaceRole>, Error> {
+        let row = self.client
+            .query_opt(
+                "SELECT role FROM workspace_members WHERE workspace_id = $1 AND user_id = $2",
+                &[&workspace_id, &user_id],
+            )
+            .await?;
+        Ok(row.map(|row| WorkspaceRole::from_column(row.get(0))))
+    }
+
+    /// Delete a workspace with its members and invitations; returns false if it still has files
+    pub async fn delete_workspace(&self, workspace_id: &str) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute(
+                "DELETE FROM workspaces
+                 WHERE workspace_id = $1 AND NOT EXISTS (SELECT 1 FROM user_files WHERE workspace_id = $1)",
+                &[&workspace_id],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Members of a workspace, in the order they joined
+    pub async fn list_workspace_members(&self, workspace_id: &str) -> Result<Vec<WorkspaceMember>, Error> {
+        let query = format!(
+            "SELECT {} FROM workspace_members m JOIN users u ON u.id = m.user_id
+             WHERE m.workspace_id = $1 ORDER BY m.joined_at, m.user_id",
+            WORKSPACE_MEMBER_COLUMNS
+        );
+        let rows = self.client.query(&query, &[&workspace_id]).await?;
+        Ok(rows.iter().map(workspace_member_from_row).collect())
+    }
+
+    /// Change the role of a member.
+    ///
+    /// Returns `None` if they are not a member, or if they are the last owner and would no longer be one.
+    pub async fn set_member_role(
+        &self,
+        workspace_id: &str,
+        user_id: i64,
+        role: WorkspaceRole,
+    ) -> Result<Option<WorkspaceMember>, Error> {
+        let query = format!(
+            "WITH m AS (
+                 UPDATE workspace_members m SET role = $3
+                 WHERE m.workspace_id = $1 AND m.user_id = $2
+                   AND (m.role <> 'owner' OR $3 = 'owner' OR EXISTS (
+                       SELECT 1 FROM workspace_members other
+                       WHERE other.workspace_id = $1 AND other.role = 'owner' AND other.user_id <> $2
+                   ))
+                 RETURNING *
+             )
+             SELECT {} FROM m JOIN users u ON u.id = m.user_id",
+            WORKSPACE_MEMBER_COLUMNS
+        );
+        let row = self.client.query_opt(&query, &[&workspace_id, &user_id, &role.as_str()]).await?;
+        Ok(row.as_ref().map(workspace_member_from_row))
+    }
+
+    /// Remove a member from a workspace; returns false if they are not a member or are its last owner
+    pub async fn remove_member(&self, workspace_id: &str, user_id: i64) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute(
+                "DELETE FROM workspace_members m
+                 WHERE m.workspace_id = $1 AND m.user_id = $2
+                   AND (m.role <> 'owner' OR EXISTS (
+                       SELECT 1 FROM workspace_members other
+                       WHERE other.workspace_id = $1 AND other.role = 'owner' AND other.user_id <> $2
+                   ))",
+                &[&workspace_id, &user_id],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Invite whoever signs in with `email` to a workspace, replacing any invitation sent to them before
+    pub async fn create_invitation(
+        &self,
+        workspace_id: &str,
+        email: &str,
+        role: WorkspaceRole,
+        invited_by: i64,
+    ) -> Result<WorkspaceInvitation, Error> {
+        let query = format!(
+            "WITH i AS (
+                 INSERT INTO workspace_invitations (invitation_id, workspace_id, email, role, invited_by)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (workspace_id, LOWER(email)) DO UPDATE
+                 SET email = EXCLUDED.email, role = EXCLUDED.role, invited_by = EXCLUDED.invited_by, created_at = NOW()
+                 RETURNING *
+             )
+             SELECT {} FROM i JOIN workspaces w ON w.workspace_id = i.workspace_id",
+            WORKSPACE_INVITATION_COLUMNS
+        );
+        let row = self.client
+            .query_one(
+                &query,
+                &[&Uuid::new_v4().to_string(), &workspace_id, &email, &role.as_str(), &invited_by],
+            )
+            .await?;
+        Ok(workspace_invitation_from_row(&row))
+    }
+
+    /// Invitations to a workspace that have not been answered yet, oldest first
+    pub async fn list_workspace_invitations(&self, workspace_id: &str) -> Result<Vec<WorkspaceInvitation>, Error> {
+        let query = format!(
+            "SELECT {} FROM workspace_invitations i JOIN workspaces w ON w.workspace_id = i.workspace_id
+             WHERE i.workspace_id = $1 ORDER BY i.created_at, i.invitation_id",
+            WORKSPACE_INVITATION_COLUMNS
+        );
+        let rows = self.client.query(&query, &[&workspace_id]).await?;
+        Ok(rows.iter().map(workspace_invitation_from_row).collect())
+    }
+
+    /// Invitations addressed to `email`, compared case-insensitively, oldest first
+    pub async fn list_invitations_for(&self, email: &str) -> Result<Vec<WorkspaceInvitation>, Error> {
+        let query = format!(
+            "SELECT {} FROM workspace_invitations i JOIN workspaces w ON w.workspace_id = i.workspace_id
+             WHERE LOWER(i.email) = LOWER($1) ORDER BY i.created_at, i.invitation_id",
+            WORKSPACE_INVITATION_COLUMNS
+        );
+        let rows = self.client.query(&query, &[&email]).await?;
+        Ok(rows.iter().map(workspace_invitation_from_row).collect())
+    }
+
+    /// Withdraw an invitation to a workspace; returns false if there is no such invitation
+    pub async fn revoke_invitation(&self, workspace_id: &str, invitation_id: &str) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute(
+                "DELETE FROM workspace_invitations WHERE invitation_id = $1 AND workspace_id = $2",
+                &[&invitation_id, &workspace_id],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Accept an invitation addressed to `email` and make `user_id` a member with the invited role.
+    ///
+    /// Members keep the role they have. Returns the id of the workspace joined, `None` if there is
+    /// no such invitation for `email`.
+    pub async fn accept_invitation(&self, invitation_id: &str, email: &str, user_id: i64) -> Result<Option<String>, Error> {
+        let row = self.client
+            .query_opt(
+                "WITH i AS (
+                     DELETE FROM workspace_invitations
+                     WHERE invitation_id = $1 AND LOWER(email) = LOWER($2)
+                     RETURNING workspace_id, role
+                 ), m AS (
+                     INSERT INTO workspace_members (workspace_id, user_id, role)
+                     SELECT workspace_id, $3, role FROM i
+                     ON CONFLICT (workspace_id, user_id) DO NOTHING
+                 )
+                 SELECT workspace_id FROM i",
+                &[&invitation_id, &email, &user_id],
+            )
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Decline an invitation addressed to `email`; returns false if there is no such invitation
+    pub async fn decline_invitation(&self, invitation_id: &str, email: &str) -> Result<bool, Error> {
+        let deleted = self.client
+            .execute(
+                "DELETE FROM workspace_invitations WHERE invitation_id = $1 AND LOWER(email) = LOWER($2)",
+                &[&invitation_id, &email],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Fill in metadata for `user_files` rows that only have a `file_key`.
+    ///
+    /// Sizes, content types and timestamps come from the stored objects. The original
+    /// filename is unknown for these rows, so the object key is used instead.
+    pub async fn backfill(&self, storage: &dyn ObjectStorage) -> Result<BackfillReport, Box<dyn std::error::Error>> {
+        let rows = self.client
+            .query("SELECT id, file_key FROM user_files WHERE file_id IS NULL ORDER BY id", &[])
+            .await?;
+
+        let mut report = BackfillReport::default();
+        for row in rows {
+            let row_id: i64 = row.get(0);
+            let file_key: String = row.get(1);
+
+            let object = match storage.head_object(&file_key).await {
+                Ok(object) => object,
+                Err(StorageError::NotFound(_)) => {
+                    report.missing.push(file_key);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            // Keys were generated as `<file id><extension>`, so reuse that id where possible
+            let stem = file_key.split('.').next().unwrap_or_default();
+            let file_id = match Uuid::parse_str(stem) {
+                Ok(id) if self.get_file(&id.to_string()).await?.is_none() => id.to_string(),
+                _ => Uuid::new_v4().to_string(),
+            };
+
+            self.client
+                .execute(
+                    "WITH file AS (
+                         UPDATE user_files
+                         SET file_id = $2, filename = $3, current_version = 1, latest_version = 1, file_key = NULL
+                         WHERE id = $1 AND file_id IS NULL
+                         RETURNING file_id
+                     )
+                     INSERT INTO file_versions (file_id, version, file_key, size, content_type, upload_time)
+                     SELECT file_id, 1, $3, $4, $5, COALESCE($6, NOW()) FROM file",
+                    &[
+                        &row_id,
+                        &file_id,
+                        &file_key,
+                        &(object.size as i64),
+                        &object.content_type,
+                        &object.last_modified,
+                    ],
+                )
+                .await?;
+            report.updated += 1;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn round_trip(cursor: &FileCursor) -> FileCursor {
+        FileCursor::decode(&cursor.encode()).unwrap()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = round_trip(&FileCursor {
+            sort: FileSort::Name,
+            descending: true,
+            key: CursorKey::Name("Ünïcode, \"quoted\" & long ".repeat(20)),
+            row_id: i64::MAX,
+        });
+        assert_eq!((cursor.sort, cursor.descending, cursor.row_id), (FileSort::Name, true, i64::MAX));
+        assert!(matches!(cursor.key, CursorKey::Name(name) if name.starts_with("Ünïcode")));
+
+        let cursor = round_trip(&FileCursor { sort: FileSort::Size, descending: false, key: CursorKey::Size(0), row_id: 1 });
+        assert!(matches!(cursor.key, CursorKey::Size(0)));
+
+        let time = Utc.with_ymd_and_hms(2024, 2, 29, 23, 59, 59).unwrap() + chrono::Duration::microseconds(123_456);
+        let cursor = round_trip(&FileCursor { sort: FileSort::Date, descending: false, key: CursorKey::Date(time), row_id: 7 });
+        assert!(matches!(cursor.key, CursorKey::Date(date) if date == time));
+    }
+
+    #[test]
+    fn cursors_are_url_safe() {
+        let cursor = FileCursor { sort: FileSort::Name, descending: false, key: CursorKey::Name("???>>>".to_string()), row_id: 3 };
+        let encoded = cursor.encode();
+        assert!(encoded.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "{}", encoded);
+    }
+
+    #[test]
+    fn rejects_tampered_cursors() {
+        let encoded = FileCursor { sort: FileSort::Size, descending: false, key: CursorKey::Size(10), row_id: 3 }.encode();
+        assert!(FileCursor::decode(&encoded[..encoded.len() - 2]).is_none());
+        assert!(FileCursor::decode(&format!("{}=", encoded)).is_none());
+        assert!(FileCursor::decode(&encoded.replace('e', "+")).is_none());
+
+        let decode = |json: &str| FileCursor::decode(&URL_SAFE_NO_PAD.encode(json));
+        assert!(decode(r#"{"sort":"size","descending":false,"key":{"Size":10},"row_id":3}"#).is_some());
+        assert!(decode(r#"{"sort":"size","descending":false,"key":{"Name":"10"},"row_id":3}"#).is_none());
+        assert!(decode(r#"{"sort":"size","descending":false,"key":{"Size":10},"row_id":"3"}"#).is_none());
+        assert!(decode(r#"{"sort":"size","descending":"no","key":{"Size":10},"row_id":3}"#).is_none());
+        assert!(decode(r#"[]"#).is_none());
+    }
+
+    #[test]
+    fn escapes_like_patterns() {
+        assert_eq!(escape_like("report"), "report");
+        assert_eq!(escape_like("100%_done\\"), "100\\%\\_done\\\\");
+    }
+}
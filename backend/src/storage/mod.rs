@@ -1,24 +1,36 @@
 pub mod cloudflare_s3;
+pub mod compression;
+pub mod encryption;
+pub mod local_fs;
+pub mod memory;
+pub mod metadata_store;
 pub mod redis_token_store;
+pub mod transfer;
 
-use aws_sdk_s3 as s3;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::Stream;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use uuid::Uuid;
-use aws_smithy_types::byte_stream::ByteStream;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use chrono::{DateTime, Utc};
 
-#[derive(Deserialize)]
-struct CloudflareConfig {
-    account_id: String,
-    access_key_id: String,
-    access_key_secret: String,
-}
+use crate::conf::{validate_config, Config, StorageBackend};
 
-#[derive(Deserialize)]
-struct Config {
-    cloudflare: CloudflareConfig,
-}
+pub use cloudflare_s3::CloudflareStorage;
+pub use compression::{Compression, ContentEncoding};
+pub use encryption::EncryptedStorage;
+pub use local_fs::LocalFsStorage;
+pub use memory::MemoryStorage;
+pub use metadata_store::{
+    FileCursor, FileFilter, FileGrant, FileOwner, FilePage, FileRecord, FileSort, Folder, MetadataStore, PendingUpload,
+    Permission, ShareLink, SharedFile, StorageUsage, TrashedFile, TusUpload, Workspace, WorkspaceInvitation,
+    WorkspaceMember, WorkspaceRole,
+};
+pub use transfer::{upload_file, UploadStreamError};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FileMetadata {
@@ -28,141 +40,226 @@ pub struct FileMetadata {
     pub content_type: Option<String>,
     pub upload_time: DateTime<Utc>,
     pub s3_key: String,
+    /// Hex-encoded SHA-256 of the content, unknown for files uploaded through presigned URLs
+    pub content_hash: Option<String>,
+    /// Version of the file this content belongs to, starting at 1
+    pub version: i32,
+    /// Folder the file is filed in, `None` at the top level
+    pub folder_id: Option<String>,
+    /// Workspace the file belongs to, `None` if it belongs to the user who uploaded it
+    pub workspace_id: Option<String>,
+    /// Free-text description set by the owner
+    pub description: Option<String>,
+    /// Labels attached by the owner, sorted
+    pub tags: Vec<String>,
+    /// Custom key/value attributes set by the owner
+    pub attributes: BTreeMap<String, String>,
+    /// Encoding the object is stored with, `None` if it holds the content as uploaded
+    pub content_encoding: Option<String>,
+    /// Time after which the file is gone and deleted by the cleanup task, `None` if it is kept
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl FileMetadata {
+    /// Whether the file has passed its expiry time
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
 }
 
-pub struct CloudflareStorage {
-    client: s3::Client,
-    bucket_name: String,
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound(String),
+    BucketNotFound(String),
+    CredentialsInvalid,
+    NetworkError(String),
+    ConfigurationError(String),
+    Io(String),
+    Unsupported(String),
+    Encryption(String),
 }
 
-impl CloudflareStorage {
-    /// Create a new CloudflareStorage instance
-    pub async fn new(bucket_name: String) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = Self::create_s3_client().await?;
-        Ok(Self {
-            client,
-            bucket_name,
-        })
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound(key) => write!(f, "Object not found: {}", key),
+            StorageError::BucketNotFound(bucket) => write!(f, "Bucket not found: {}", bucket),
+            StorageError::CredentialsInvalid => write!(f, "Invalid storage credentials"),
+            StorageError::NetworkError(msg) => write!(f, "Storage network error: {}", msg),
+            StorageError::ConfigurationError(msg) => write!(f, "Storage configuration error: {}", msg),
+            StorageError::Io(msg) => write!(f, "Storage I/O error: {}", msg),
+            StorageError::Unsupported(msg) => write!(f, "Not supported by this storage backend: {}", msg),
+            StorageError::Encryption(msg) => write!(f, "Storage encryption error: {}", msg),
+        }
     }
+}
+
+impl std::error::Error for StorageError {}
 
-    /// Load configuration from TOML file
-    fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-        let config_content = fs::read_to_string("src/conf/init.toml")?;
-        let config: Config = toml::from_str(&config_content)?;
-        Ok(config)
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e.to_string())
     }
+}
+
+/// Object attributes as reported by the backend, without the body
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// Stream of object bytes handed out by the storage backends
+pub type ObjectStream = Pin<Box<dyn Stream<Item = Result<Bytes, StorageError>> + Send>>;
 
-    /// Create S3 client with Cloudflare R2 configuration
-    async fn create_s3_client() -> Result<s3::Client, Box<dyn std::error::Error>> {
-        let app_config = Self::load_config()?;
-        
-        let account_id = &app_config.cloudflare.account_id;
-        let access_key_id = &app_config.cloudflare.access_key_id;
-        let access_key_secret = &app_config.cloudflare.access_key_secret;
-
-        // Configure the client
-        let aws_config = aws_config::from_env()
-            .endpoint_url(format!("https://{}.r2.cloudflarestorage.com", account_id))
-            .credentials_provider(s3::config::Credentials::new(
-                access_key_id.clone(),
-                access_key_secret.clone(),
-                None, // session token is not used with R2
-                None,
-                "R2",
-            ))
-            .region("auto")
-            .load()
-            .await;
-
-        Ok(s3::Client::new(&aws_config))
+/// Inclusive range of bytes within an object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes covered by the range
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
     }
+}
+
+/// Body of an object being read from storage
+pub struct ObjectBody {
+    pub body: ObjectStream,
+    /// Number of bytes `body` will yield
+    pub content_length: u64,
+}
+
+/// A request a client can send straight to the storage backend, without going through the server
+#[derive(Debug, Clone, Serialize)]
+pub struct PresignedRequest {
+    pub method: String,
+    pub url: String,
+    /// Headers the client must send along with the request
+    pub headers: BTreeMap<String, String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A part that has been uploaded as part of a multipart upload
+#[derive(Debug, Clone)]
+pub struct CompletedPart {
+    pub part_number: i32,
+    pub etag: String,
+}
 
-    /// Upload a file to Cloudflare R2
-    pub async fn upload_file(
+/// Common interface over the object stores the server can write files to
+#[async_trait]
+pub trait ObjectStorage: Send + Sync {
+    /// Store `content` under `key`, replacing any existing object
+    async fn put_object(
         &self,
-        filename: &str,
+        key: &str,
         content: Vec<u8>,
-        content_type: Option<String>,
-    ) -> Result<FileMetadata, Box<dyn std::error::Error>> {
-        let file_id = Uuid::new_v4().to_string();
-        let file_extension = std::path::Path::new(filename)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| format!(".{}", ext))
-            .unwrap_or_default();
-        
-        let s3_key = format!("{}{}", file_id, file_extension);
-        let content_size = content.len() as u64;
-        
-        let mut put_object = self.client
-            .put_object()
-            .bucket(&self.bucket_name)
-            .key(&s3_key)
-            .body(ByteStream::from(content));
-
-        if let Some(ct) = &content_type {
-            put_object = put_object.content_type(ct);
-        }
+        content_type: Option<&str>,
+    ) -> Result<(), StorageError>;
 
-        put_object.send().await?;
+    /// Stream the object stored under `key`, or only `range` of it when given
+    async fn get_object(&self, key: &str, range: Option<ByteRange>) -> Result<ObjectBody, StorageError>;
 
-        let metadata = FileMetadata {
-            id: file_id,
-            filename: filename.to_string(),
-            size: content_size,
-            content_type,
-            upload_time: chrono::Utc::now(),
-            s3_key,
-        };
+    /// Remove the object stored under `key`
+    async fn delete_object(&self, key: &str) -> Result<(), StorageError>;
 
-        Ok(metadata)
-    }
+    /// List all object keys
+    #[allow(dead_code)]
+    async fn list_objects(&self) -> Result<Vec<String>, StorageError>;
+
+    /// Fetch the attributes of the object stored under `key`
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, StorageError>;
+
+    /// Start a multipart upload for `key` and return its upload id
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+    ) -> Result<String, StorageError>;
+
+    /// Upload one part of a multipart upload. Part numbers start at 1, and `last` is set on the
+    /// part that ends the object.
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        content: Vec<u8>,
+        last: bool,
+    ) -> Result<CompletedPart, StorageError>;
+
+    /// Assemble the uploaded parts into the final object
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), StorageError>;
 
-    /// Download a file from Cloudflare R2
-    pub async fn download_file(
+    /// Discard a multipart upload together with any parts uploaded so far
+    async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Result<(), StorageError>;
+
+    /// Presign a request uploading the object `key` directly to the backend
+    async fn presign_put(
         &self,
-        s3_key: &str,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let response = self.client
-            .get_object()
-            .bucket(&self.bucket_name)
-            .key(s3_key)
-            .send()
-            .await?;
-
-        let data = response.body.collect().await?;
-        Ok(data.into_bytes().to_vec())
+        _key: &str,
+        _content_type: Option<&str>,
+        _expires_in: Duration,
+    ) -> Result<PresignedRequest, StorageError> {
+        Err(StorageError::Unsupported("presigned uploads".to_string()))
     }
 
-    /// Delete a file from Cloudflare R2
-    pub async fn delete_file(
+    /// Presign a request downloading the object `key` directly from the backend as `filename`
+    async fn presign_get(
         &self,
-        s3_key: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.client
-            .delete_object()
-            .bucket(&self.bucket_name)
-            .key(s3_key)
-            .send()
-            .await?;
-
-        Ok(())
+        _key: &str,
+        _filename: &str,
+        _expires_in: Duration,
+    ) -> Result<PresignedRequest, StorageError> {
+        Err(StorageError::Unsupported("presigned downloads".to_string()))
     }
+}
+
+/// Build the object key for a file: its id followed by the original extension
+pub fn object_key(file_id: &str, filename: &str) -> String {
+    let file_extension = std::path::Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| format!(".{}", ext))
+        .unwrap_or_default();
 
-    /// List all files in the bucket
-    pub async fn list_files(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let response = self.client
-            .list_objects_v2()
-            .bucket(&self.bucket_name)
-            .send()
-            .await?;
-
-        let keys = response
-            .contents()
-            .iter()
-            .filter_map(|obj| obj.key().map(|k| k.to_string()))
-            .collect();
-
-        Ok(keys)
+    format!("{}{}", file_id, file_extension)
+}
+
+/// Create the storage backend selected in the configuration, encrypting objects if enabled
+pub async fn create_storage(config: &Config) -> Result<Arc<dyn ObjectStorage>, Box<dyn std::error::Error>> {
+    let storage = create_backend(config).await?;
+    if !config.encryption.enabled {
+        return Ok(storage);
     }
-}
\ No newline at end of file
+    Ok(Arc::new(
+        EncryptedStorage::new(storage, &config.encryption, &config.postgres.postgres_url).await?,
+    ))
+}
+
+async fn create_backend(config: &Config) -> Result<Arc<dyn ObjectStorage>, Box<dyn std::error::Error>> {
+    match config.storage.backend {
+        StorageBackend::R2 => {
+            let cloudflare = config
+                .cloudflare
+                .as_ref()
+                .ok_or("The r2 storage backend requires a [cloudflare] section")?;
+            validate_config(cloudflare)?;
+            Ok(Arc::new(CloudflareStorage::new(cloudflare).await?))
+        }
+        StorageBackend::Local => {
+            Ok(Arc::new(LocalFsStorage::new(&config.storage.local_path).await?))
+        }
+        StorageBackend::Memory => Ok(Arc::new(MemoryStorage::new())),
+    }
+}
@@ -86,6 +86,7 @@ impl RedisTokenStore {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn is_token_valid(&self, token: &str) -> Result<bool, RedisError> {
         let info = self.get_token_info(token).await?;
         Ok(info.is_some())
@@ -95,9 +96,9 @@ impl RedisTokenStore {
         let client = self.client.lock().await.clone();
         let mut conn = client.get_async_connection().await?;
         
-        let pattern = format!("auth:token:*");
+        let pattern = "auth:token:*";
         let keys: Vec<String> = redis::cmd("KEYS")
-            .arg(&pattern)
+            .arg(pattern)
             .query_async::<_, Vec<String>>(&mut conn)
             .await?;
         
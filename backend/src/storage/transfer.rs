@@ -0,0 +1,195 @@
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::future::{self, Either};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use uuid::Uuid;
+
+use super::compression::{self, Compression};
+use super::{object_key, CompletedPart, FileMetadata, ObjectStorage, StorageError};
+
+/// Size of the parts sent to the backend while streaming an upload.
+///
+/// This bounds the memory held per upload; S3 requires at least 5 MiB for every part but the last.
+pub const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Error raised while streaming a request body into object storage
+#[derive(Debug)]
+pub enum UploadStreamError<E> {
+    /// Reading from the source stream failed
+    Source(E),
+    /// Writing to the storage backend failed
+    Storage(StorageError),
+    /// The body grew beyond the allowed size, given in bytes
+    TooLarge(u64),
+}
+
+impl<E: fmt::Display> fmt::Display for UploadStreamError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadStreamError::Source(e) => write!(f, "Failed to read upload body: {}", e),
+            UploadStreamError::Storage(e) => write!(f, "{}", e),
+            UploadStreamError::TooLarge(limit) => write!(f, "Upload exceeds the limit of {} bytes", limit),
+        }
+    }
+}
+
+impl<E> From<StorageError> for UploadStreamError<E> {
+    fn from(e: StorageError) -> Self {
+        UploadStreamError::Storage(e)
+    }
+}
+
+/// Stream `body` into the object `key` and return the number of bytes written.
+///
+/// Bodies smaller than one part are stored with a single put. Larger bodies go through a
+/// multipart upload holding at most one part in memory, which is aborted if anything fails.
+pub async fn upload_stream<S, E>(
+    storage: &dyn ObjectStorage,
+    key: &str,
+    content_type: Option<&str>,
+    mut body: S,
+) -> Result<u64, UploadStreamError<E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut buffer = Vec::with_capacity(MULTIPART_PART_SIZE);
+
+    // Fill the first part before deciding whether a multipart upload is needed at all
+    while buffer.len() < MULTIPART_PART_SIZE {
+        match body.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk.map_err(UploadStreamError::Source)?),
+            None => {
+                let size = buffer.len() as u64;
+                storage.put_object(key, buffer, content_type).await?;
+                return Ok(size);
+            }
+        }
+    }
+
+    let upload_id = storage.create_multipart_upload(key, content_type).await?;
+
+    match upload_parts(storage, key, &upload_id, buffer, &mut body).await {
+        Ok((parts, size)) => {
+            if let Err(e) = storage.complete_multipart_upload(key, &upload_id, parts).await {
+                abort_upload(storage, key, &upload_id).await;
+                return Err(e.into());
+            }
+            Ok(size)
+        }
+        Err(e) => {
+            abort_upload(storage, key, &upload_id).await;
+            Err(e)
+        }
+    }
+}
+
+async fn upload_parts<S, E>(
+    storage: &dyn ObjectStorage,
+    key: &str,
+    upload_id: &str,
+    mut buffer: Vec<u8>,
+    body: &mut S,
+) -> Result<(Vec<CompletedPart>, u64), UploadStreamError<E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut parts = Vec::new();
+    let mut size = 0u64;
+
+    loop {
+        let chunk = body.next().await.transpose().map_err(UploadStreamError::Source)?;
+        let finished = chunk.is_none();
+        if let Some(chunk) = chunk {
+            buffer.extend_from_slice(&chunk);
+        }
+
+        // Every part but the last is exactly one part size. A full part is only sent once more
+        // content follows it or the body has ended, so the last part is known to be the last.
+        while buffer.len() > MULTIPART_PART_SIZE || (finished && !buffer.is_empty()) {
+            let rest = buffer.split_off(buffer.len().min(MULTIPART_PART_SIZE));
+            let content = std::mem::replace(&mut buffer, rest);
+            size += content.len() as u64;
+            let part_number = parts.len() as i32 + 1;
+            let last = finished && buffer.is_empty();
+            parts.push(storage.upload_part(key, upload_id, part_number, content, last).await?);
+        }
+
+        if finished {
+            return Ok((parts, size));
+        }
+    }
+}
+
+async fn abort_upload(storage: &dyn ObjectStorage, key: &str, upload_id: &str) {
+    if let Err(e) = storage.abort_multipart_upload(key, upload_id).await {
+        eprintln!("Failed to abort multipart upload {} for {}: {}", upload_id, key, e);
+    }
+}
+
+/// Stream a file into storage under a freshly generated key and describe it.
+///
+/// The SHA-256 of the content is computed on the way through, so identical uploads can be
+/// detected without reading the object back. With a `max_size`, the upload is aborted as soon
+/// as the body grows beyond it. With a `compression`, the content is compressed before it is
+/// stored; the hash, the size limit and the returned size all apply to the content as uploaded.
+pub async fn upload_file<S, E>(
+    storage: &dyn ObjectStorage,
+    filename: &str,
+    content_type: Option<String>,
+    body: S,
+    max_size: Option<u64>,
+    compression: Option<Compression>,
+) -> Result<FileMetadata, UploadStreamError<E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let file_id = Uuid::new_v4().to_string();
+    let s3_key = object_key(&file_id, filename);
+
+    let mut hasher = Sha256::new();
+    let mut received = 0u64;
+    let body = body
+        .map_err(UploadStreamError::Source)
+        .and_then(|chunk| {
+            received += chunk.len() as u64;
+            future::ready(match max_size {
+                Some(limit) if received > limit => Err(UploadStreamError::TooLarge(limit)),
+                _ => Ok(chunk),
+            })
+        })
+        .inspect_ok(|chunk| hasher.update(chunk));
+    let body = match compression {
+        Some(compression) => Either::Left(Box::pin(compression::compress(body, compression))),
+        None => Either::Right(body),
+    };
+
+    upload_stream(storage, &s3_key, content_type.as_deref(), body)
+        .await
+        .map_err(|e| match e {
+            UploadStreamError::Source(e) => e,
+            UploadStreamError::Storage(e) => UploadStreamError::Storage(e),
+            UploadStreamError::TooLarge(limit) => UploadStreamError::TooLarge(limit),
+        })?;
+
+    Ok(FileMetadata {
+        id: file_id,
+        filename: filename.to_string(),
+        size: received,
+        content_type,
+        upload_time: Utc::now(),
+        s3_key,
+        content_hash: Some(hex::encode(hasher.finalize())),
+        // The metadata store decides which version of which file this becomes
+        version: 1,
+        folder_id: None,
+        workspace_id: None,
+        description: None,
+        tags: Vec::new(),
+        attributes: BTreeMap::new(),
+        content_encoding: compression.map(|compression| compression.encoding.as_str().to_string()),
+        expires_at: None,
+    })
+}
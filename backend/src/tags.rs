@@ -0,0 +1,178 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::auth::Claims;
+use crate::storage::Permission;
+use crate::{check_file_access, error_response, AppState, UploadResponse};
+
+/// Longest accepted tag or attribute key, in characters
+pub const MAX_TAG_LENGTH: usize = 255;
+
+/// Longest accepted attribute value, in characters
+pub const MAX_ATTRIBUTE_VALUE_LENGTH: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct AddTagsRequest {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAttributesRequest {
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// Trim a tag and check that it can be used, returning why not otherwise
+fn validate_tag(tag: &str) -> std::result::Result<String, &'static str> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err("Tags must not be empty");
+    }
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        return Err("Tag is too long");
+    }
+    if tag.chars().any(char::is_control) {
+        return Err("Tag contains invalid characters");
+    }
+    Ok(tag.to_string())
+}
+
+/// Attribute keys end up in `meta.<key>` query parameters, so they are kept to a safe alphabet
+fn validate_attribute(key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    if key.is_empty() || key.len() > MAX_TAG_LENGTH {
+        return Err("Attribute keys must be between 1 and 255 characters long");
+    }
+    if !key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err("Attribute keys may only contain letters, digits, '_', '-' and '.'");
+    }
+    if value.chars().count() > MAX_ATTRIBUTE_VALUE_LENGTH {
+        return Err("Attribute value is too long");
+    }
+    Ok(())
+}
+
+async fn updated_file_response(data: &AppState, file_id: &str, message: &str) -> HttpResponse {
+    match data.file_metadata.get_file(file_id).await {
+        Ok(Some(record)) => HttpResponse::Ok().json(UploadResponse {
+            success: true,
+            message: message.to_string(),
+            file: Some(record.metadata),
+        }),
+        Ok(None) => error_response(HttpResponse::NotFound(), "File not found"),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            error_response(HttpResponse::InternalServerError(), "Failed to retrieve updated file")
+        }
+    }
+}
+
+pub async fn add_tags(
+    claims: Claims,
+    path: web::Path<String>,
+    tags_req: web::Json<AddTagsRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if tags_req.tags.is_empty() {
+        return Ok(error_response(HttpResponse::BadRequest(), "No tags given"));
+    }
+    let tags = match tags_req.tags.iter().map(|tag| validate_tag(tag)).collect::<std::result::Result<Vec<_>, _>>() {
+        Ok(tags) => tags,
+        Err(message) => return Ok(error_response(HttpResponse::BadRequest(), message)),
+    };
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Write).await {
+        return Ok(response);
+    }
+
+    if let Err(e) = data.file_metadata.add_tags(&file_id, &tags).await {
+        eprintln!("Database error: {}", e);
+        return Ok(error_response(HttpResponse::InternalServerError(), "Failed to add tags"));
+    }
+
+    Ok(updated_file_response(&data, &file_id, "Tags added successfully").await)
+}
+
+pub async fn remove_tag(
+    claims: Claims,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (file_id, tag) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Write).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.remove_tag(&file_id, &tag).await {
+        Ok(true) => Ok(updated_file_response(&data, &file_id, "Tag removed successfully").await),
+        Ok(false) => Ok(error_response(HttpResponse::NotFound(), "The file does not have this tag")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to remove tag"))
+        }
+    }
+}
+
+pub async fn set_attributes(
+    claims: Claims,
+    path: web::Path<String>,
+    attributes_req: web::Json<SetAttributesRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if attributes_req.attributes.is_empty() {
+        return Ok(error_response(HttpResponse::BadRequest(), "No attributes given"));
+    }
+    for (key, value) in &attributes_req.attributes {
+        if let Err(message) = validate_attribute(key, value) {
+            return Ok(error_response(HttpResponse::BadRequest(), message));
+        }
+    }
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Write).await {
+        return Ok(response);
+    }
+
+    if let Err(e) = data.file_metadata.set_attributes(&file_id, &attributes_req.attributes).await {
+        eprintln!("Database error: {}", e);
+        return Ok(error_response(HttpResponse::InternalServerError(), "Failed to set attributes"));
+    }
+
+    Ok(updated_file_response(&data, &file_id, "Attributes set successfully").await)
+}
+
+pub async fn remove_attribute(
+    claims: Claims,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (file_id, key) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Write).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.remove_attribute(&file_id, &key).await {
+        Ok(true) => Ok(updated_file_response(&data, &file_id, "Attribute removed successfully").await),
+        Ok(false) => Ok(error_response(HttpResponse::NotFound(), "The file does not have this attribute")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to remove attribute"))
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Claims;
+use crate::storage::{FileOwner, Permission, TrashedFile, WorkspaceRole};
+use crate::{authorize_file_access, cleanup, error_response, file_expired_response, workspaces, AppState, FileInfo, UploadResponse};
+
+#[derive(Debug, Deserialize)]
+pub struct TrashQuery {
+    /// Workspace whose trash is meant, the user's own trash if absent
+    pub workspace_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TrashResponse {
+    pub success: bool,
+    pub message: String,
+    pub files: Vec<TrashedFile>,
+}
+
+/// Whose trash `query` is about, answering with an error response unless `user_id` may manage it.
+///
+/// Everyone manages their own trash; a workspace's trash is managed by its admins.
+async fn trash_owner(data: &AppState, query: TrashQuery, user_id: i64) -> std::result::Result<FileOwner, HttpResponse> {
+    match query.workspace_id {
+        Some(workspace_id) => {
+            workspaces::check_workspace_role(data, &workspace_id, user_id, WorkspaceRole::Admin).await?;
+            Ok(FileOwner::Workspace(workspace_id))
+        }
+        None => Ok(FileOwner::User(user_id)),
+    }
+}
+
+/// Check that `user_id` may restore or delete the trashed file `file_id`, which takes the access
+/// needed to delete it
+async fn check_trashed_file_access(data: &AppState, file_id: &str, user_id: i64) -> std::result::Result<FileInfo, HttpResponse> {
+    match data.file_metadata.get_trashed_file(file_id).await {
+        Ok(Some(record)) => {
+            let file = authorize_file_access(data, record, user_id, Permission::Manage).await?;
+            if file.is_expired() {
+                return Err(file_expired_response());
+            }
+            Ok(file)
+        }
+        Ok(None) => Err(error_response(HttpResponse::NotFound(), "File not found in trash")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(
+                HttpResponse::InternalServerError(),
+                "Database error while checking file ownership",
+            ))
+        }
+    }
+}
+
+// List the files in the trash
+pub async fn list_trash(
+    claims: Claims,
+    query: web::Query<TrashQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let owner = match trash_owner(&data, query.into_inner(), user_id).await {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+
+    match data.file_metadata.list_trash(&owner, data.trash_retention).await {
+        Ok(files) => Ok(HttpResponse::Ok().json(TrashResponse {
+            success: true,
+            message: "Trash retrieved successfully".to_string(),
+            files,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve trash"))
+        }
+    }
+}
+
+// Take a file out of the trash, back to where it was deleted from
+pub async fn restore_file(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let file_metadata = match check_trashed_file_access(&data, &file_id, user_id).await {
+        Ok(file_metadata) => file_metadata,
+        Err(response) => return Ok(response),
+    };
+
+    // The file is in the trash, so no update means its name has been taken since it was deleted
+    match data.file_metadata.restore_file(&file_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(UploadResponse {
+            success: true,
+            message: "File restored successfully".to_string(),
+            file: Some(file_metadata),
+        })),
+        Ok(false) => Ok(error_response(
+            HttpResponse::Conflict(),
+            "Another file with this name has been created in its place; rename it first",
+        )),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to restore file"))
+        }
+    }
+}
+
+// Delete a file in the trash for good
+pub async fn delete_trashed_file(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let file_metadata = match check_trashed_file_access(&data, &file_id, user_id).await {
+        Ok(file_metadata) => file_metadata,
+        Err(response) => return Ok(response),
+    };
+
+    // Remove the database row first; objects it no longer references are queued for deletion in the same statement
+    let file_keys = match data.file_metadata.delete_trashed_file(&file_id).await {
+        Ok(Some(file_keys)) => file_keys,
+        Ok(None) => return Ok(error_response(HttpResponse::NotFound(), "File not found in trash")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(error_response(
+                HttpResponse::InternalServerError(),
+                "Failed to delete file metadata; the file was not deleted",
+            ));
+        }
+    };
+
+    // Content shared with other files stays in storage until its last reference is gone
+    if !cleanup::remove_objects(&data.file_metadata, data.storage.as_ref(), &file_keys).await {
+        return Ok(HttpResponse::Accepted().json(UploadResponse {
+            success: true,
+            message: "File deleted, but removing it from storage failed; removal will be retried".to_string(),
+            file: Some(file_metadata),
+        }));
+    }
+
+    Ok(HttpResponse::Ok().json(UploadResponse {
+        success: true,
+        message: "File deleted successfully".to_string(),
+        file: Some(file_metadata),
+    }))
+}
+
+// Delete every file in the trash for good
+pub async fn empty_trash(
+    claims: Claims,
+    query: web::Query<TrashQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let owner = match trash_owner(&data, query.into_inner(), user_id).await {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+
+    let file_keys = match data.file_metadata.empty_trash(&owner).await {
+        Ok(file_keys) => file_keys,
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(error_response(
+                HttpResponse::InternalServerError(),
+                "Failed to empty trash; no files were deleted",
+            ));
+        }
+    };
+
+    if !cleanup::remove_objects(&data.file_metadata, data.storage.as_ref(), &file_keys).await {
+        return Ok(HttpResponse::Accepted().json(UploadResponse {
+            success: true,
+            message: "Trash emptied, but removing some files from storage failed; removal will be retried".to_string(),
+            file: None,
+        }));
+    }
+
+    Ok(HttpResponse::Ok().json(UploadResponse {
+        success: true,
+        message: "Trash emptied successfully".to_string(),
+        file: None,
+    }))
+}
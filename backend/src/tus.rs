@@ -0,0 +1,731 @@
+use actix_web::http::header::{self, HeaderName, HeaderValue, HttpDate};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt as _;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use crate::auth::Claims;
+use crate::storage::transfer::MULTIPART_PART_SIZE;
+use crate::storage::{object_key, ByteRange, FileMetadata, StorageError, TusUpload};
+use crate::{cleanup, error_response, folders, policy, quota, versions, AppState};
+
+/// Version of the tus protocol implemented here
+const TUS_VERSION: &str = "1.0.0";
+
+/// Protocol extensions supported, as announced to clients
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+/// Content type of PATCH request bodies
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+/// How long a PATCH request may hold an upload before another request can take over
+const PATCH_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+/// Id of the file an upload was recorded as, sent once it is finished
+const FILE_ID: HeaderName = HeaderName::from_static("file-id");
+
+/// Add the `Tus-Resumable` header every tus response carries
+fn tus_response(mut response: HttpResponse) -> HttpResponse {
+    response.headers_mut().insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn tus_error(builder: HttpResponseBuilder, message: &str) -> HttpResponse {
+    tus_response(error_response(builder, message))
+}
+
+/// Answer to a request speaking another protocol version, if it does
+fn version_mismatch(req: &HttpRequest) -> Option<HttpResponse> {
+    if req.headers().get(&TUS_RESUMABLE).and_then(|value| value.to_str().ok()) == Some(TUS_VERSION) {
+        return None;
+    }
+    let mut response = tus_error(HttpResponse::PreconditionFailed(), "Unsupported tus protocol version");
+    response.headers_mut().insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+    Some(response)
+}
+
+fn numeric_header(req: &HttpRequest, name: &HeaderName) -> Option<u64> {
+    req.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    HttpDate::from(SystemTime::from(time)).to_string()
+}
+
+/// Object holding the bytes of an upload that do not fill a part yet
+fn tail_key(upload: &TusUpload) -> String {
+    format!("{}.tail", upload.file_key)
+}
+
+/// Parse `Upload-Metadata`: comma-separated unique keys, each optionally followed by a base64 value
+fn parse_metadata(value: &str) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next()?;
+        let value = match parts.next() {
+            Some(encoded) => String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?,
+            None => String::new(),
+        };
+        if metadata.insert(key.to_string(), value).is_some() {
+            return None;
+        }
+    }
+    Some(metadata)
+}
+
+/// Answer to a PATCH request sent for another offset than the upload is at, if it is
+fn offset_mismatch(upload: &TusUpload, offset: u64) -> Option<HttpResponse> {
+    (offset != upload.upload_offset)
+        .then(|| tus_error(HttpResponse::Conflict(), "Upload-Offset does not match the offset of the upload"))
+}
+
+/// Whether `buffered` bytes received after the last part would take the upload beyond its length
+fn exceeds_length(upload: &TusUpload, buffered: usize) -> bool {
+    upload.committed_size() + buffered as u64 > upload.upload_length
+}
+
+/// Whether `buffered` bytes received after the last part start with a part to send right away.
+///
+/// The part ending the upload is held back for `finish`, which sends it as the last one.
+fn has_full_part(upload: &TusUpload, buffered: usize) -> bool {
+    buffered >= MULTIPART_PART_SIZE && upload.committed_size() + (MULTIPART_PART_SIZE as u64) < upload.upload_length
+}
+
+/// Number of the part after the ones sent so far; every part but the last is exactly one part size
+fn next_part_number(upload: &TusUpload) -> i32 {
+    (upload.committed_size() / MULTIPART_PART_SIZE as u64) as i32 + 1
+}
+
+/// Look up an upload of `user_id` that can still be resumed, answering with an error response if not
+async fn find_upload(data: &AppState, upload_id: &str, user_id: i64) -> Result<TusUpload, HttpResponse> {
+    match data.file_metadata.get_tus_upload(upload_id).await {
+        Ok(Some(upload)) if upload.user_id != user_id => {
+            Err(tus_error(HttpResponse::Forbidden(), "Access denied: You didn't start this upload"))
+        }
+        Ok(Some(upload)) if upload.expires_at <= Utc::now() => Err(tus_error(HttpResponse::Gone(), "Upload expired")),
+        Ok(Some(upload)) => Ok(upload),
+        Ok(None) => Err(tus_error(HttpResponse::NotFound(), "Upload not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(tus_error(HttpResponse::InternalServerError(), "Database error while looking up the upload"))
+        }
+    }
+}
+
+/// Release the storage held by an unfinished upload
+pub async fn discard_upload(data: &AppState, upload: &TusUpload) {
+    if let Some(multipart_upload_id) = &upload.multipart_upload_id {
+        if let Err(e) = data.storage.abort_multipart_upload(&upload.file_key, multipart_upload_id).await {
+            eprintln!("Failed to abort multipart upload {} for {}: {}", multipart_upload_id, upload.file_key, e);
+        }
+    }
+    cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &tail_key(upload)).await;
+}
+
+// Announce the supported protocol version and extensions
+pub async fn options(data: web::Data<AppState>) -> HttpResponse {
+    let mut response = HttpResponse::NoContent();
+    response
+        .insert_header((TUS_VERSION_HEADER, TUS_VERSION))
+        .insert_header((TUS_EXTENSION, TUS_EXTENSIONS));
+    if let Some(max_size) = data.upload_policy.max_file_size() {
+        response.insert_header((TUS_MAX_SIZE, max_size));
+    }
+    tus_response(response.finish())
+}
+
+// Create an upload; `Upload-Metadata` carries `filename` and optionally `filetype` and `folder_id`
+pub async fn create_upload(
+    claims: Claims,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Some(response) = version_mismatch(&req) {
+        return Ok(response);
+    }
+
+    let upload_length = match numeric_header(&req, &UPLOAD_LENGTH) {
+        Some(upload_length) => upload_length,
+        None => return Ok(tus_error(HttpResponse::BadRequest(), "Missing or invalid Upload-Length")),
+    };
+    let metadata = match req.headers().get(&UPLOAD_METADATA).map(|value| value.to_str().ok().and_then(parse_metadata)) {
+        Some(Some(metadata)) => metadata,
+        Some(None) => return Ok(tus_error(HttpResponse::BadRequest(), "Invalid Upload-Metadata")),
+        None => HashMap::new(),
+    };
+
+    let filename = match metadata.get("filename").map(|filename| folders::validate_name(filename)) {
+        Some(Ok(filename)) => filename,
+        Some(Err(message)) => return Ok(tus_error(HttpResponse::BadRequest(), message)),
+        None => return Ok(tus_error(HttpResponse::BadRequest(), "Upload-Metadata must include a filename")),
+    };
+    let content_type = metadata.get("filetype").filter(|filetype| !filetype.is_empty()).cloned();
+    let folder_id = metadata.get("folder_id").filter(|folder_id| !folder_id.is_empty()).cloned();
+
+    // The content is only seen once the upload is finished; refuse what can be refused already
+    let checked = data.upload_policy
+        .check_filename(&filename)
+        .and_then(|()| data.upload_policy.check_size(upload_length))
+        .and_then(|()| data.upload_policy.resolve_content_type(content_type.as_deref(), &[]));
+    if let Err(violation) = checked {
+        return Ok(tus_response(violation.response()));
+    }
+
+    if let Some(folder_id) = &folder_id {
+        if let Err(response) = folders::check_folder_ownership(&data, folder_id, user_id).await {
+            return Ok(tus_response(response));
+        }
+    }
+
+    match quota::remaining_quota(&data, user_id).await {
+        Ok(Some(remaining)) if upload_length > remaining => return Ok(tus_response(quota::quota_exceeded_response())),
+        Ok(_) => {}
+        Err(response) => return Ok(tus_response(response)),
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+    let upload = TusUpload {
+        file_key: object_key(&upload_id, &filename),
+        upload_id,
+        user_id,
+        filename,
+        content_type,
+        folder_id,
+        upload_length,
+        upload_offset: 0,
+        tail_size: 0,
+        multipart_upload_id: None,
+        expires_at: Utc::now() + chrono::Duration::from_std(data.resumable_upload_expiry).unwrap_or_default(),
+    };
+
+    if let Err(e) = data.file_metadata.create_tus_upload(&upload).await {
+        eprintln!("Database error: {}", e);
+        return Ok(tus_error(HttpResponse::InternalServerError(), "Failed to record upload"));
+    }
+
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), upload.upload_id);
+    let expires = http_date(upload.expires_at);
+
+    // An empty upload is finished as soon as it exists
+    let mut response = if upload.upload_length == 0 {
+        match finish(&data, upload, Vec::new()).await {
+            Ok(file_id) => {
+                let mut response = HttpResponse::Created();
+                response.insert_header((FILE_ID, file_id));
+                response
+            }
+            Err(response) => return Ok(tus_response(response)),
+        }
+    } else {
+        HttpResponse::Created()
+    };
+
+    Ok(tus_response(
+        response
+            .insert_header((header::LOCATION, location))
+            .insert_header((UPLOAD_EXPIRES, expires))
+            .finish(),
+    ))
+}
+
+// Report how many bytes of an upload have been received
+pub async fn upload_offset(
+    claims: Claims,
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let upload_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Some(response) = version_mismatch(&req) {
+        return Ok(response);
+    }
+
+    let upload = match find_upload(&data, &upload_id, user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(tus_response(
+        HttpResponse::Ok()
+            .insert_header((UPLOAD_OFFSET, upload.upload_offset))
+            .insert_header((UPLOAD_LENGTH, upload.upload_length))
+            .insert_header((UPLOAD_EXPIRES, http_date(upload.expires_at)))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .finish(),
+    ))
+}
+
+/// Why appending a request body to an upload stopped early
+enum AppendError {
+    /// The client went away or sent a malformed body
+    Source(actix_web::error::PayloadError),
+    /// The body goes beyond `Upload-Length`
+    TooLong,
+    Storage(StorageError),
+    Database(tokio_postgres::Error),
+}
+
+/// Read the tail left by earlier requests; only its recorded size is trusted
+async fn load_tail(data: &AppState, upload: &TusUpload) -> Result<Vec<u8>, StorageError> {
+    let mut tail = Vec::with_capacity(MULTIPART_PART_SIZE);
+    if upload.tail_size == 0 {
+        return Ok(tail);
+    }
+
+    let range = ByteRange {
+        start: 0,
+        end: upload.tail_size - 1,
+    };
+    let mut object = data.storage.get_object(&tail_key(upload), Some(range)).await?;
+    while let Some(chunk) = object.body.next().await {
+        tail.extend_from_slice(&chunk?);
+    }
+    if tail.len() as u64 != upload.tail_size {
+        return Err(StorageError::Io(format!("Tail of upload {} is incomplete", upload.upload_id)));
+    }
+    Ok(tail)
+}
+
+/// Upload the first full part held in `buffer` and record it, so it survives the request
+async fn upload_part(data: &AppState, upload: &mut TusUpload, buffer: &mut Vec<u8>) -> Result<(), AppendError> {
+    let multipart_upload_id = match &upload.multipart_upload_id {
+        Some(multipart_upload_id) => multipart_upload_id.clone(),
+        None => {
+            let multipart_upload_id = data.storage
+                .create_multipart_upload(&upload.file_key, upload.content_type.as_deref())
+                .await
+                .map_err(AppendError::Storage)?;
+            data.file_metadata
+                .set_tus_multipart_upload(&upload.upload_id, &multipart_upload_id)
+                .await
+                .map_err(AppendError::Database)?;
+            upload.multipart_upload_id = Some(multipart_upload_id.clone());
+            multipart_upload_id
+        }
+    };
+
+    let part_number = next_part_number(upload);
+    let part = data.storage
+        .upload_part(&upload.file_key, &multipart_upload_id, part_number, buffer[..MULTIPART_PART_SIZE].to_vec(), false)
+        .await
+        .map_err(AppendError::Storage)?;
+    data.file_metadata
+        .commit_tus_part(&upload.upload_id, &part, MULTIPART_PART_SIZE as u64)
+        .await
+        .map_err(AppendError::Database)?;
+
+    buffer.drain(..MULTIPART_PART_SIZE);
+    upload.upload_offset = upload.committed_size() + MULTIPART_PART_SIZE as u64;
+    upload.tail_size = 0;
+    Ok(())
+}
+
+/// Append a request body to `buffer`, which starts out holding the upload's tail, sending full parts on the way
+async fn receive(
+    data: &AppState,
+    upload: &mut TusUpload,
+    buffer: &mut Vec<u8>,
+    body: &mut web::Payload,
+) -> Result<(), AppendError> {
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(AppendError::Source)?;
+        if exceeds_length(upload, buffer.len() + chunk.len()) {
+            return Err(AppendError::TooLong);
+        }
+        buffer.extend_from_slice(&chunk);
+
+        while has_full_part(upload, buffer.len()) {
+            upload_part(data, upload, buffer).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Assemble the complete content of an upload and record it as a file, returning the file's id.
+///
+/// `buffer` holds the bytes received after the last part. On failure the upload stays at its last
+/// recorded offset, so the client can send the remaining bytes again.
+async fn finish(data: &AppState, upload: TusUpload, buffer: Vec<u8>) -> Result<String, HttpResponse> {
+    let assembled = match &upload.multipart_upload_id {
+        Some(multipart_upload_id) => {
+            let mut parts = match data.file_metadata.tus_upload_parts(&upload.upload_id).await {
+                Ok(parts) => parts,
+                Err(e) => {
+                    eprintln!("Database error: {}", e);
+                    return Err(unlock(data, &upload, "Failed to finish the upload").await);
+                }
+            };
+            let mut result = Ok(());
+            if !buffer.is_empty() {
+                let part_number = parts.len() as i32 + 1;
+                match data.storage.upload_part(&upload.file_key, multipart_upload_id, part_number, buffer, true).await {
+                    Ok(part) => parts.push(part),
+                    Err(e) => result = Err(e),
+                }
+            }
+            match result {
+                Ok(()) => data.storage.complete_multipart_upload(&upload.file_key, multipart_upload_id, parts).await,
+                Err(e) => Err(e),
+            }
+        }
+        None => data.storage.put_object(&upload.file_key, buffer, upload.content_type.as_deref()).await,
+    };
+    if let Err(e) = assembled {
+        eprintln!("Upload error: {}", e);
+        return Err(unlock(data, &upload, "Failed to upload file to storage").await);
+    }
+    cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &tail_key(&upload)).await;
+
+    // Claim the upload so that finishing it twice cannot record the object twice
+    let upload = match data.file_metadata.take_tus_upload(&upload.upload_id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => {
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &upload.file_key).await;
+            return Err(tus_error(HttpResponse::NotFound(), "Upload not found"));
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Err(tus_error(HttpResponse::InternalServerError(), "Database error while finishing the upload"));
+        }
+    };
+
+    // Quotas may have changed since the upload was created, and the content is only known now
+    let checked = match quota::remaining_quota(data, upload.user_id).await {
+        Ok(Some(remaining)) if upload.upload_length > remaining => Err(quota::quota_exceeded_response()),
+        Ok(_) => policy::check_stored_object(data, &upload.file_key, upload.upload_length, upload.content_type.as_deref()).await,
+        Err(response) => Err(response),
+    };
+    let content_type = match checked {
+        Ok(content_type) => content_type,
+        Err(response) => {
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &upload.file_key).await;
+            return Err(tus_response(response));
+        }
+    };
+
+    let file_metadata = FileMetadata {
+        id: upload.upload_id,
+        filename: upload.filename,
+        size: upload.upload_length,
+        content_type,
+        upload_time: Utc::now(),
+        s3_key: upload.file_key,
+        // The content arrives over several requests, so it is not hashed and cannot be deduplicated
+        content_hash: None,
+        version: 1,
+        folder_id: upload.folder_id,
+        workspace_id: None,
+        description: None,
+        tags: Vec::new(),
+        attributes: BTreeMap::new(),
+        content_encoding: None,
+        expires_at: None,
+    };
+
+    match data.file_metadata.insert_file(upload.user_id, &file_metadata).await {
+        Ok(stored) => {
+            if stored.version > 1 {
+                if let Err(e) = versions::prune(data, &stored.id).await {
+                    eprintln!("Failed to prune versions of {}: {}", stored.id, e);
+                }
+            }
+            Ok(stored.id)
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            cleanup::discard_object(&data.file_metadata, data.storage.as_ref(), &file_metadata.s3_key).await;
+            Err(tus_error(HttpResponse::InternalServerError(), "Failed to save file metadata"))
+        }
+    }
+}
+
+/// Release the lock of an upload after a failure, answering with a server error
+async fn unlock(data: &AppState, upload: &TusUpload, message: &str) -> HttpResponse {
+    if let Err(e) = data.file_metadata.unlock_tus_upload(&upload.upload_id).await {
+        eprintln!("Failed to unlock upload {}: {}", upload.upload_id, e);
+    }
+    tus_error(HttpResponse::InternalServerError(), message)
+}
+
+// Append the request body to an upload at `Upload-Offset`
+pub async fn append(
+    claims: Claims,
+    req: HttpRequest,
+    path: web::Path<String>,
+    mut body: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let upload_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Some(response) = version_mismatch(&req) {
+        return Ok(response);
+    }
+    if req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()) != Some(OFFSET_OCTET_STREAM) {
+        return Ok(tus_error(
+            HttpResponse::UnsupportedMediaType(),
+            "PATCH requests must have the content type application/offset+octet-stream",
+        ));
+    }
+    let offset = match numeric_header(&req, &UPLOAD_OFFSET) {
+        Some(offset) => offset,
+        None => return Ok(tus_error(HttpResponse::BadRequest(), "Missing or invalid Upload-Offset")),
+    };
+
+    let upload = match find_upload(&data, &upload_id, user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    if let Some(response) = offset_mismatch(&upload, offset) {
+        return Ok(response);
+    }
+
+    // Only one request at a time may append, so parts and tail stay consistent with the offset
+    let locked_until = Utc::now() + chrono::Duration::from_std(PATCH_LOCK_TIMEOUT).unwrap_or_default();
+    let mut upload = match data.file_metadata.lock_tus_upload(&upload_id, offset, locked_until).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(tus_error(HttpResponse::Conflict(), "The upload is being appended to by another request")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(tus_error(HttpResponse::InternalServerError(), "Database error while looking up the upload"));
+        }
+    };
+
+    let mut buffer = match load_tail(&data, &upload).await {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("Storage error: {}", e);
+            return Ok(unlock(&data, &upload, "Failed to read the upload from storage").await);
+        }
+    };
+
+    let received = receive(&data, &mut upload, &mut buffer, &mut body).await;
+
+    let offset = upload.committed_size() + buffer.len() as u64;
+    if received.is_ok() && offset == upload.upload_length {
+        return Ok(match finish(&data, upload, buffer).await {
+            Ok(file_id) => tus_response(
+                HttpResponse::NoContent()
+                    .insert_header((UPLOAD_OFFSET, offset))
+                    .insert_header((FILE_ID, file_id))
+                    .finish(),
+            ),
+            Err(response) => response,
+        });
+    }
+
+    // Keep whatever arrived, even if the request failed halfway, so the client can resume from there
+    if !buffer.is_empty() {
+        if let Err(e) = data.storage.put_object(&tail_key(&upload), buffer.clone(), None).await {
+            eprintln!("Storage error: {}", e);
+            return Ok(unlock(&data, &upload, "Failed to store the upload").await);
+        }
+    }
+    let expires_at = Utc::now() + chrono::Duration::from_std(data.resumable_upload_expiry).unwrap_or_default();
+    if let Err(e) = data.file_metadata.save_tus_tail(&upload_id, buffer.len() as u64, expires_at).await {
+        eprintln!("Database error: {}", e);
+        return Ok(unlock(&data, &upload, "Failed to record upload progress").await);
+    }
+
+    match received {
+        Ok(()) => Ok(tus_response(
+            HttpResponse::NoContent()
+                .insert_header((UPLOAD_OFFSET, offset))
+                .insert_header((UPLOAD_EXPIRES, http_date(expires_at)))
+                .finish(),
+        )),
+        Err(AppendError::Source(e)) => {
+            eprintln!("Upload aborted by client: {}", e);
+            Err(e.into())
+        }
+        Err(AppendError::TooLong) => Ok(tus_error(
+            HttpResponse::PayloadTooLarge(),
+            "The request body goes beyond the Upload-Length of the upload",
+        )),
+        Err(AppendError::Storage(e)) => {
+            eprintln!("Upload error: {}", e);
+            Ok(tus_error(HttpResponse::InternalServerError(), "Failed to upload file to storage"))
+        }
+        Err(AppendError::Database(e)) => {
+            eprintln!("Database error: {}", e);
+            Ok(tus_error(HttpResponse::InternalServerError(), "Failed to record upload progress"))
+        }
+    }
+}
+
+// Abandon an upload and release everything stored for it so far
+pub async fn terminate(
+    claims: Claims,
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let upload_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Some(response) = version_mismatch(&req) {
+        return Ok(response);
+    }
+    if let Err(response) = find_upload(&data, &upload_id, user_id).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.take_tus_upload(&upload_id).await {
+        Ok(Some(upload)) => {
+            discard_upload(&data, &upload).await;
+            Ok(tus_response(HttpResponse::NoContent().finish()))
+        }
+        Ok(None) => Ok(tus_error(HttpResponse::NotFound(), "Upload not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(tus_error(HttpResponse::InternalServerError(), "Failed to terminate upload"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+
+    const PART: u64 = MULTIPART_PART_SIZE as u64;
+
+    fn upload(upload_length: u64, upload_offset: u64, tail_size: u64) -> TusUpload {
+        TusUpload {
+            upload_id: "upload".to_string(),
+            user_id: 1,
+            file_key: "upload.bin".to_string(),
+            filename: "upload.bin".to_string(),
+            content_type: None,
+            folder_id: None,
+            upload_length,
+            upload_offset,
+            tail_size,
+            multipart_upload_id: None,
+            expires_at: Utc::now(),
+        }
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    /// Sizes of the parts an upload of `length` bytes is stored in when its body arrives in
+    /// requests of `requests` bytes each, the part sent by `finish` last
+    fn part_sizes(length: u64, requests: u64) -> Vec<u64> {
+        let mut upload = upload(length, 0, 0);
+        let mut sizes = Vec::new();
+        while upload.upload_offset < length {
+            let received = requests.min(length - upload.upload_offset);
+            assert!(!exceeds_length(&upload, (upload.tail_size + received) as usize));
+            let mut buffered = (upload.tail_size + received) as usize;
+            while has_full_part(&upload, buffered) {
+                assert_eq!(next_part_number(&upload), sizes.len() as i32 + 1);
+                sizes.push(PART);
+                buffered -= MULTIPART_PART_SIZE;
+                upload.upload_offset = upload.committed_size() + PART;
+                upload.tail_size = 0;
+            }
+            upload.upload_offset = upload.committed_size() + buffered as u64;
+            upload.tail_size = buffered as u64;
+        }
+        if upload.tail_size > 0 {
+            assert_eq!(next_part_number(&upload), sizes.len() as i32 + 1);
+            sizes.push(upload.tail_size);
+        }
+        sizes
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let parsed = parse_metadata("filename cmVwb3J0LnBkZg==,filetype YXBwbGljYXRpb24vcGRm").unwrap();
+        assert_eq!(parsed, metadata(&[("filename", "report.pdf"), ("filetype", "application/pdf")]));
+        assert_eq!(parse_metadata(" filename  cmVwb3J0LnBkZg== , , ").unwrap(), metadata(&[("filename", "report.pdf")]));
+        assert_eq!(parse_metadata("").unwrap(), HashMap::new());
+    }
+
+    #[test]
+    fn parses_keys_without_values() {
+        let parsed = parse_metadata("is_confidential,filename YQ==").unwrap();
+        assert_eq!(parsed, metadata(&[("is_confidential", ""), ("filename", "a")]));
+        assert_eq!(parse_metadata("folder_id ").unwrap(), metadata(&[("folder_id", "")]));
+    }
+
+    #[test]
+    fn rejects_malformed_metadata() {
+        // Not base64, unpadded, or not UTF-8 once decoded
+        assert!(parse_metadata("filename report.pdf").is_none());
+        assert!(parse_metadata("filename cmVwb3J0LnBkZg").is_none());
+        assert!(parse_metadata("filename //79").is_none());
+        assert!(parse_metadata("filename YQ== extra").is_none());
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        assert!(parse_metadata("filename YQ==,filename Yg==").is_none());
+        assert!(parse_metadata("flag,flag").is_none());
+    }
+
+    #[test]
+    fn detects_offset_mismatches() {
+        let upload = upload(3 * PART, PART + 10, 10);
+        assert!(offset_mismatch(&upload, PART + 10).is_none());
+        for offset in [0, PART, PART + 9, PART + 11, 3 * PART] {
+            let response = offset_mismatch(&upload, offset).unwrap();
+            assert_eq!(response.status(), StatusCode::CONFLICT, "offset {}", offset);
+            assert_eq!(response.headers().get(&TUS_RESUMABLE).unwrap(), TUS_VERSION);
+        }
+    }
+
+    #[test]
+    fn detects_bodies_beyond_the_length() {
+        let upload = upload(PART + 100, PART + 10, 10);
+        assert!(!exceeds_length(&upload, 100));
+        assert!(exceeds_length(&upload, 101));
+    }
+
+    #[test]
+    fn holds_back_the_last_part() {
+        // An upload of exactly one part is sent whole by `finish`
+        assert!(!has_full_part(&upload(PART, 0, 0), MULTIPART_PART_SIZE));
+        assert!(has_full_part(&upload(PART + 1, 0, 0), MULTIPART_PART_SIZE));
+        assert!(!has_full_part(&upload(2 * PART, PART, 0), MULTIPART_PART_SIZE));
+        assert!(!has_full_part(&upload(3 * PART, 0, 0), MULTIPART_PART_SIZE - 1));
+    }
+
+    #[test]
+    fn splits_uploads_into_parts() {
+        assert_eq!(part_sizes(10, 3), [10]);
+        assert_eq!(part_sizes(PART, PART), [PART]);
+        assert_eq!(part_sizes(PART, 1000), [PART]);
+        assert_eq!(part_sizes(PART + 1, PART + 1), [PART, 1]);
+        assert_eq!(part_sizes(2 * PART, 3 * PART / 4), [PART, PART]);
+        assert_eq!(part_sizes(2 * PART + 7, PART / 3), [PART, PART, 7]);
+        assert_eq!(part_sizes(3 * PART, 3 * PART), [PART, PART, PART]);
+    }
+}
@@ -0,0 +1,159 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+
+use crate::auth::Claims;
+use crate::storage::Permission;
+use crate::{check_file_access, cleanup, download, error_response, AppState, FileInfo, UploadResponse};
+
+#[derive(Serialize)]
+pub struct FileVersionsResponse {
+    pub success: bool,
+    pub message: String,
+    /// The current version of the file
+    pub file: Option<FileInfo>,
+    /// All kept versions, newest first
+    pub versions: Vec<FileInfo>,
+}
+
+/// Apply the configured version retention to a file and remove the objects of pruned versions.
+///
+/// Objects that cannot be removed now stay queued and are retried by the cleanup task.
+pub async fn prune(data: &AppState, file_id: &str) -> Result<(), tokio_postgres::Error> {
+    let file_keys = data.file_metadata.prune_versions(file_id, data.version_retention).await?;
+    for file_key in &file_keys {
+        if let Err(e) = cleanup::remove_object(&data.file_metadata, data.storage.as_ref(), file_key).await {
+            eprintln!("Storage cleanup error for {}: {}", file_key, e);
+        }
+    }
+    Ok(())
+}
+
+async fn versions_response(data: &AppState, file: FileInfo, message: &str) -> HttpResponse {
+    match data.file_metadata.list_file_versions(&file.id).await {
+        Ok(versions) => HttpResponse::Ok().json(FileVersionsResponse {
+            success: true,
+            message: message.to_string(),
+            file: Some(file),
+            versions,
+        }),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            error_response(HttpResponse::InternalServerError(), "Failed to retrieve file versions")
+        }
+    }
+}
+
+// List the kept versions of a file
+pub async fn list_versions(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let file = match check_file_access(&data, &file_id, user_id, Permission::Read).await {
+        Ok(file) => file,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(versions_response(&data, file, "File versions retrieved successfully").await)
+}
+
+// Download a specific version of a file
+pub async fn download_version(
+    claims: Claims,
+    req: HttpRequest,
+    path: web::Path<(String, i32)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (file_id, version) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Read).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.get_file_version(&file_id, version).await {
+        Ok(Some(record)) => {
+            match download::serve_file(&req, data.storage.as_ref(), &record.metadata).await {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    eprintln!("Download error: {}", e);
+                    Ok(error_response(HttpResponse::InternalServerError(), "Failed to download file from storage"))
+                }
+            }
+        }
+        Ok(None) => Ok(error_response(HttpResponse::NotFound(), "File version not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Database error while checking file ownership"))
+        }
+    }
+}
+
+// Make an older version the current version of a file again
+pub async fn restore_version(
+    claims: Claims,
+    path: web::Path<(String, i32)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (file_id, version) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_file_access(&data, &file_id, user_id, Permission::Write).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.restore_version(&file_id, version).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(error_response(HttpResponse::NotFound(), "File version not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(error_response(HttpResponse::InternalServerError(), "Failed to restore file version"));
+        }
+    }
+
+    match data.file_metadata.get_file(&file_id).await {
+        Ok(Some(record)) => Ok(HttpResponse::Ok().json(UploadResponse {
+            success: true,
+            message: format!("Version {} restored as the current version", version),
+            file: Some(record.metadata),
+        })),
+        Ok(None) => Ok(error_response(HttpResponse::NotFound(), "File not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve restored file"))
+        }
+    }
+}
+
+// Delete versions beyond the configured retention count
+pub async fn prune_versions(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let file_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let file = match check_file_access(&data, &file_id, user_id, Permission::Manage).await {
+        Ok(file) => file,
+        Err(response) => return Ok(response),
+    };
+
+    if let Err(e) = prune(&data, &file_id).await {
+        eprintln!("Database error: {}", e);
+        return Ok(error_response(HttpResponse::InternalServerError(), "Failed to prune file versions"));
+    }
+
+    Ok(versions_response(&data, file, "Old file versions pruned").await)
+}
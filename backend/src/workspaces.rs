@@ -0,0 +1,533 @@
+use actix_web::{web, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{AuthService, Claims};
+use crate::folders::MAX_NAME_LENGTH;
+use crate::storage::{Workspace, WorkspaceInvitation, WorkspaceMember, WorkspaceRole};
+use crate::{error_response, AppState};
+
+/// Longest accepted email address of an invitee
+const MAX_EMAIL_LENGTH: usize = 255;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWorkspaceRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteRequest {
+    /// Email address the invitee signs in with; they do not need an account yet
+    pub email: String,
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMemberRequest {
+    pub role: WorkspaceRole,
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceResponse {
+    pub success: bool,
+    pub message: String,
+    pub workspace: Option<Workspace>,
+}
+
+#[derive(Serialize)]
+pub struct WorkspacesResponse {
+    pub success: bool,
+    pub message: String,
+    pub workspaces: Vec<Workspace>,
+}
+
+#[derive(Serialize)]
+pub struct MemberResponse {
+    pub success: bool,
+    pub message: String,
+    pub member: Option<WorkspaceMember>,
+}
+
+#[derive(Serialize)]
+pub struct MembersResponse {
+    pub success: bool,
+    pub message: String,
+    pub members: Vec<WorkspaceMember>,
+}
+
+#[derive(Serialize)]
+pub struct InvitationResponse {
+    pub success: bool,
+    pub message: String,
+    pub invitation: Option<WorkspaceInvitation>,
+}
+
+#[derive(Serialize)]
+pub struct InvitationsResponse {
+    pub success: bool,
+    pub message: String,
+    pub invitations: Vec<WorkspaceInvitation>,
+}
+
+fn workspace_error(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(WorkspaceResponse {
+        success: false,
+        message: message.to_string(),
+        workspace: None,
+    })
+}
+
+fn member_error(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(MemberResponse {
+        success: false,
+        message: message.to_string(),
+        member: None,
+    })
+}
+
+fn invitation_error(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(InvitationResponse {
+        success: false,
+        message: message.to_string(),
+        invitation: None,
+    })
+}
+
+/// Whether a member with role `manager` may give out or take away `role`.
+///
+/// Owners may change anyone; admins only the members and viewers below them.
+fn can_manage(manager: WorkspaceRole, role: WorkspaceRole) -> bool {
+    manager == WorkspaceRole::Owner || manager > role
+}
+
+/// Check that `user_id` has at least the role `required` in the workspace `workspace_id`,
+/// answering with an error response if not.
+///
+/// Workspaces the user is not a member of are reported as not found, so their ids reveal nothing.
+pub async fn check_workspace_role(
+    data: &AppState,
+    workspace_id: &str,
+    user_id: i64,
+    required: WorkspaceRole,
+) -> Result<WorkspaceRole, HttpResponse> {
+    match data.file_metadata.workspace_role(workspace_id, user_id).await {
+        Ok(Some(role)) if role >= required => Ok(role),
+        Ok(Some(_)) => Err(error_response(
+            HttpResponse::Forbidden(),
+            &format!("Access denied: You need the {} role in this workspace", required.as_str()),
+        )),
+        Ok(None) => Err(error_response(HttpResponse::NotFound(), "Workspace not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Err(error_response(
+                HttpResponse::InternalServerError(),
+                "Database error while checking workspace membership",
+            ))
+        }
+    }
+}
+
+// Create a workspace owned by the current user
+pub async fn create_workspace(
+    claims: Claims,
+    create_req: web::Json<CreateWorkspaceRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let name = create_req.name.trim();
+    if name.is_empty() {
+        return Ok(workspace_error(HttpResponse::BadRequest(), "Name must not be empty"));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Ok(workspace_error(HttpResponse::BadRequest(), "Name is too long"));
+    }
+    if name.chars().any(char::is_control) {
+        return Ok(workspace_error(HttpResponse::BadRequest(), "Name contains invalid characters"));
+    }
+
+    match data.file_metadata.create_workspace(user_id, name).await {
+        Ok(workspace) => Ok(HttpResponse::Created().json(WorkspaceResponse {
+            success: true,
+            message: "Workspace created successfully".to_string(),
+            workspace: Some(workspace),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(workspace_error(HttpResponse::InternalServerError(), "Failed to create workspace"))
+        }
+    }
+}
+
+// List the workspaces the current user is a member of
+pub async fn list_workspaces(
+    claims: Claims,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    match data.file_metadata.list_workspaces(user_id).await {
+        Ok(workspaces) => Ok(HttpResponse::Ok().json(WorkspacesResponse {
+            success: true,
+            message: "Workspaces retrieved successfully".to_string(),
+            workspaces,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve workspaces"))
+        }
+    }
+}
+
+// Delete a workspace once its files are gone; only owners may
+pub async fn delete_workspace(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let workspace_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Owner).await {
+        return Ok(response);
+    }
+
+    // The workspace exists, so nothing deleted means files remain in it, possibly in its trash
+    match data.file_metadata.delete_workspace(&workspace_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(WorkspaceResponse {
+            success: true,
+            message: "Workspace deleted successfully".to_string(),
+            workspace: None,
+        })),
+        Ok(false) => Ok(workspace_error(
+            HttpResponse::Conflict(),
+            "The workspace still has files; delete them and empty its trash first",
+        )),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(workspace_error(HttpResponse::InternalServerError(), "Failed to delete workspace"))
+        }
+    }
+}
+
+// List the members of a workspace
+pub async fn list_members(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let workspace_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Viewer).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.list_workspace_members(&workspace_id).await {
+        Ok(members) => Ok(HttpResponse::Ok().json(MembersResponse {
+            success: true,
+            message: "Members retrieved successfully".to_string(),
+            members,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve members"))
+        }
+    }
+}
+
+// Change the role of a member of a workspace
+pub async fn update_member(
+    claims: Claims,
+    path: web::Path<(String, i64)>,
+    update_req: web::Json<UpdateMemberRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (workspace_id, member_id) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let role = match check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Admin).await {
+        Ok(role) => role,
+        Err(response) => return Ok(response),
+    };
+    let current = match data.file_metadata.workspace_role(&workspace_id, member_id).await {
+        Ok(Some(current)) => current,
+        Ok(None) => return Ok(member_error(HttpResponse::NotFound(), "This user is not a member of the workspace")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(member_error(HttpResponse::InternalServerError(), "Database error while checking membership"));
+        }
+    };
+    if !can_manage(role, current) || !can_manage(role, update_req.role) {
+        return Ok(member_error(
+            HttpResponse::Forbidden(),
+            "Access denied: Only owners may appoint or change admins and owners",
+        ));
+    }
+
+    // The member exists, so no update means they are the last owner
+    match data.file_metadata.set_member_role(&workspace_id, member_id, update_req.role).await {
+        Ok(Some(member)) => Ok(HttpResponse::Ok().json(MemberResponse {
+            success: true,
+            message: format!("{} is now {}", member.email, member.role.as_str()),
+            member: Some(member),
+        })),
+        Ok(None) => Ok(member_error(HttpResponse::Conflict(), "A workspace must keep at least one owner")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(member_error(HttpResponse::InternalServerError(), "Failed to change role"))
+        }
+    }
+}
+
+// Remove a member from a workspace; members may also leave on their own
+pub async fn remove_member(
+    claims: Claims,
+    path: web::Path<(String, i64)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (workspace_id, member_id) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let required = if member_id == user_id { WorkspaceRole::Viewer } else { WorkspaceRole::Admin };
+    let role = match check_workspace_role(&data, &workspace_id, user_id, required).await {
+        Ok(role) => role,
+        Err(response) => return Ok(response),
+    };
+    if member_id != user_id {
+        match data.file_metadata.workspace_role(&workspace_id, member_id).await {
+            Ok(Some(current)) if !can_manage(role, current) => {
+                return Ok(member_error(
+                    HttpResponse::Forbidden(),
+                    "Access denied: Only owners may remove admins and owners",
+                ));
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(member_error(HttpResponse::NotFound(), "This user is not a member of the workspace")),
+            Err(e) => {
+                eprintln!("Database error: {}", e);
+                return Ok(member_error(HttpResponse::InternalServerError(), "Database error while checking membership"));
+            }
+        }
+    }
+
+    // The member exists, so nothing removed means they are the last owner
+    match data.file_metadata.remove_member(&workspace_id, member_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(MemberResponse {
+            success: true,
+            message: "Member removed successfully".to_string(),
+            member: None,
+        })),
+        Ok(false) => Ok(member_error(HttpResponse::Conflict(), "A workspace must keep at least one owner")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(member_error(HttpResponse::InternalServerError(), "Failed to remove member"))
+        }
+    }
+}
+
+// Invite someone to a workspace by email
+pub async fn invite(
+    claims: Claims,
+    path: web::Path<String>,
+    invite_req: web::Json<InviteRequest>,
+    data: web::Data<AppState>,
+    auth_service: web::Data<AuthService>,
+) -> Result<HttpResponse> {
+    let workspace_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let role = match check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Admin).await {
+        Ok(role) => role,
+        Err(response) => return Ok(response),
+    };
+    if invite_req.role == WorkspaceRole::Owner {
+        return Ok(invitation_error(
+            HttpResponse::BadRequest(),
+            "Invitations cannot make owners; promote a member instead",
+        ));
+    }
+    if !can_manage(role, invite_req.role) {
+        return Ok(invitation_error(HttpResponse::Forbidden(), "Access denied: Only owners may invite admins"));
+    }
+
+    let email = invite_req.email.trim();
+    if email.len() > MAX_EMAIL_LENGTH || email.split('@').filter(|part| !part.is_empty()).count() != 2 {
+        return Ok(invitation_error(HttpResponse::BadRequest(), "Invalid email address"));
+    }
+
+    // Users already in the workspace change role through their membership instead
+    match auth_service.get_user_by_email(email).await {
+        Ok(Some(user)) => match data.file_metadata.workspace_role(&workspace_id, user.id).await {
+            Ok(Some(_)) => {
+                return Ok(invitation_error(HttpResponse::Conflict(), "This user is already a member of the workspace"));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Database error: {}", e);
+                return Ok(invitation_error(HttpResponse::InternalServerError(), "Database error while checking membership"));
+            }
+        },
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(invitation_error(HttpResponse::InternalServerError(), "Failed to look up user"));
+        }
+    }
+
+    match data.file_metadata.create_invitation(&workspace_id, email, invite_req.role, user_id).await {
+        Ok(invitation) => Ok(HttpResponse::Created().json(InvitationResponse {
+            success: true,
+            message: format!("Invited {} as {}", invitation.email, invitation.role.as_str()),
+            invitation: Some(invitation),
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(invitation_error(HttpResponse::InternalServerError(), "Failed to create invitation"))
+        }
+    }
+}
+
+// List the invitations to a workspace that have not been answered yet
+pub async fn list_invitations(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let workspace_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Admin).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.list_workspace_invitations(&workspace_id).await {
+        Ok(invitations) => Ok(HttpResponse::Ok().json(InvitationsResponse {
+            success: true,
+            message: "Invitations retrieved successfully".to_string(),
+            invitations,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve invitations"))
+        }
+    }
+}
+
+// Withdraw an invitation to a workspace
+pub async fn revoke_invitation(
+    claims: Claims,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (workspace_id, invitation_id) = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    if let Err(response) = check_workspace_role(&data, &workspace_id, user_id, WorkspaceRole::Admin).await {
+        return Ok(response);
+    }
+
+    match data.file_metadata.revoke_invitation(&workspace_id, &invitation_id).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(InvitationResponse {
+            success: true,
+            message: "Invitation revoked successfully".to_string(),
+            invitation: None,
+        })),
+        Ok(false) => Ok(invitation_error(HttpResponse::NotFound(), "Invitation not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(invitation_error(HttpResponse::InternalServerError(), "Failed to revoke invitation"))
+        }
+    }
+}
+
+// List the invitations addressed to the current user's email
+pub async fn my_invitations(
+    claims: Claims,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    match data.file_metadata.list_invitations_for(&claims.email).await {
+        Ok(invitations) => Ok(HttpResponse::Ok().json(InvitationsResponse {
+            success: true,
+            message: "Invitations retrieved successfully".to_string(),
+            invitations,
+        })),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(error_response(HttpResponse::InternalServerError(), "Failed to retrieve invitations"))
+        }
+    }
+}
+
+// Accept an invitation addressed to the current user and join its workspace
+pub async fn accept_invitation(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let invitation_id = path.into_inner();
+    let user_id: i64 = claims.sub.parse().map_err(|_| {
+        actix_web::error::ErrorBadRequest("Invalid user ID")
+    })?;
+
+    let workspace_id = match data.file_metadata.accept_invitation(&invitation_id, &claims.email, user_id).await {
+        Ok(Some(workspace_id)) => workspace_id,
+        Ok(None) => return Ok(workspace_error(HttpResponse::NotFound(), "Invitation not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return Ok(workspace_error(HttpResponse::InternalServerError(), "Failed to accept invitation"));
+        }
+    };
+
+    match data.file_metadata.get_workspace(&workspace_id, user_id).await {
+        Ok(Some(workspace)) => Ok(HttpResponse::Ok().json(WorkspaceResponse {
+            success: true,
+            message: format!("Joined {} as {}", workspace.name, workspace.role.as_str()),
+            workspace: Some(workspace),
+        })),
+        Ok(None) => Ok(workspace_error(HttpResponse::NotFound(), "Workspace not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(workspace_error(HttpResponse::InternalServerError(), "Database error while retrieving workspace"))
+        }
+    }
+}
+
+// Decline an invitation addressed to the current user
+pub async fn decline_invitation(
+    claims: Claims,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let invitation_id = path.into_inner();
+
+    match data.file_metadata.decline_invitation(&invitation_id, &claims.email).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(InvitationResponse {
+            success: true,
+            message: "Invitation declined".to_string(),
+            invitation: None,
+        })),
+        Ok(false) => Ok(invitation_error(HttpResponse::NotFound(), "Invitation not found")),
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            Ok(invitation_error(HttpResponse::InternalServerError(), "Failed to decline invitation"))
+        }
+    }
+}